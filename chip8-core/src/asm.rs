@@ -0,0 +1,874 @@
+// An assembler for the same syntax the disassembler prints. A source file is
+// a list of lines like these:
+//
+//   SPEED = 3                ; constants, any expression
+//   start:                   ; labels
+//       LD V0, SPEED * 2
+//       LD I, sprite
+//       DRW V0, V1, sprite_end - sprite
+//       JP start
+//   sprite:
+//       db 0x3C, 0x42, "text" ; bytes and strings
+//       dw 0x1234             ; big-endian words
+//   sprite_end:
+//   include "more.asm"       ; relative to the including file
+//
+// Mnemonics and register names are case-insensitive, labels and constants
+// aren't. Numbers can be decimal, 0x hex or 0b binary, and `$` is the
+// address of the current line. The program is assembled to run from 0x200.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+const ORIGIN: usize = 0x200;
+const MAX_SIZE: usize = 0x10000 - ORIGIN;
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub file: String,
+    pub line: usize,   // from 1
+    pub column: usize, // from 1, 0 if the error is about the whole line
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.column > 0 {
+            write!(f, "{}:{}:{}: {}", self.file, self.line, self.column, self.message)
+        } else {
+            write!(f, "{}:{}: {}", self.file, self.line, self.message)
+        }
+    }
+}
+
+impl Error for AsmError {}
+
+// Which source line each instruction was assembled from, for debuggers.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceMap {
+    lines: Vec<SourceLine>, // by address
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLine {
+    pub address: u16,
+    pub file: String,
+    pub line: usize, // from 1
+}
+
+impl SourceMap {
+    // Later code at the same address (from Octo's :org) replaces earlier.
+    pub(crate) fn new(mut lines: Vec<SourceLine>) -> SourceMap {
+        lines.reverse();
+        lines.sort_by_key(|line| line.address);
+        lines.dedup_by_key(|line| line.address);
+        SourceMap { lines }
+    }
+
+    pub fn lines(&self) -> &[SourceLine] {
+        &self.lines
+    }
+
+    // The instruction that starts at address.
+    pub fn line_at(&self, address: u16) -> Option<&SourceLine> {
+        let i = self.lines.binary_search_by_key(&address, |line| line.address).ok()?;
+        Some(&self.lines[i])
+    }
+
+    // Where a breakpoint on this line goes: its first instruction, or the
+    // first one on a line after it.
+    pub fn address_of(&self, file: &str, line: usize) -> Option<&SourceLine> {
+        self.lines.iter()
+            .filter(|source| source.file == file && source.line >= line)
+            .min_by_key(|source| (source.line, source.address))
+    }
+}
+
+// Assembles source text. Includes are looked up relative to the current directory.
+pub fn assemble(source: &str) -> Result<Vec<u8>, AsmError> {
+    let mut asm = Assembler::new();
+    asm.parse_source(source, Rc::from("<source>"), Path::new(""), 0)?;
+    asm.finish()
+}
+
+pub fn assemble_file(path: &Path) -> Result<Vec<u8>, AsmError> {
+    assemble_file_with_map(path).map(|(rom, _)| rom)
+}
+
+// Included files appear in the map under their path joined onto the
+// including file's directory.
+pub fn assemble_file_with_map(path: &Path) -> Result<(Vec<u8>, SourceMap), AsmError> {
+    let mut asm = Assembler::new();
+    asm.parse_file(path, None, 0)?;
+    let map = asm.source_map();
+    Ok((asm.finish()?, map))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Tok {
+    Ident(String),
+    Number(i64),
+    Str(Vec<u8>),
+    Punct(char), // , : = ( ) [ ] + - * / % & | ^ ~ $
+    ShiftLeft,
+    ShiftRight,
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    tok: Tok,
+    column: usize,
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Number(i64),
+    Symbol(String, usize),
+    Here, // $
+    Negate(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(Tok, Box<Expr>, Box<Expr>, usize),
+}
+
+#[derive(Debug, Clone)]
+enum Operand {
+    V(u8),
+    I,
+    IndirectI, // [I]
+    DT,
+    ST,
+    K,
+    F,
+    HF,
+    B,
+    R,
+    Long(Expr),
+    Expr(Expr),
+}
+
+#[derive(Debug, Clone)]
+enum Item {
+    Instruction { mnemonic: String, operands: Vec<(Operand, usize)>, column: usize },
+    Bytes(Vec<(Data, usize)>),
+    Words(Vec<(Expr, usize)>),
+}
+
+#[derive(Debug, Clone)]
+enum Data {
+    Expr(Expr),
+    Str(Vec<u8>),
+}
+
+#[derive(Debug, Clone)]
+struct Location {
+    file: Rc<str>,
+    line: usize,
+}
+
+impl Location {
+    fn error(&self, column: usize, message: impl Into<String>) -> AsmError {
+        AsmError {
+            file: self.file.to_string(),
+            line: self.line,
+            column,
+            message: message.into(),
+        }
+    }
+}
+
+struct Statement {
+    location: Location,
+    address: usize,
+    item: Item,
+}
+
+enum Symbol {
+    Label(usize),
+    // Evaluated when used, so constants can refer to labels further down.
+    Constant(Expr, Location, usize),
+}
+
+struct Assembler {
+    statements: Vec<Statement>,
+    symbols: HashMap<String, Symbol>,
+    address: usize,
+}
+
+impl Assembler {
+    fn new() -> Assembler {
+        Assembler {
+            statements: Vec::new(),
+            symbols: HashMap::new(),
+            address: ORIGIN,
+        }
+    }
+
+    fn parse_file(&mut self, path: &Path, from: Option<(&Location, usize)>, depth: usize) -> Result<(), AsmError> {
+        let name: Rc<str> = Rc::from(path.display().to_string());
+        let source = match fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(err) => {
+                let message = format!("couldn't read {}: {}", path.display(), err);
+                return Err(match from {
+                    Some((location, column)) => location.error(column, message),
+                    None => AsmError { file: name.to_string(), line: 0, column: 0, message },
+                });
+            },
+        };
+        let dir = path.parent().unwrap_or(Path::new(""));
+        self.parse_source(&source, name, dir, depth)
+    }
+
+    // First pass: tokenize and parse every line, giving each one its address.
+    fn parse_source(&mut self, source: &str, file: Rc<str>, dir: &Path, depth: usize) -> Result<(), AsmError> {
+        for (i, text) in source.lines().enumerate() {
+            let location = Location { file: file.clone(), line: i + 1 };
+            let tokens = tokenize(text).map_err(|(column, message)| location.error(column, message))?;
+            let mut tokens = &tokens[..];
+
+            // Any number of labels can start a line.
+            while let [Token { tok: Tok::Ident(name), column }, Token { tok: Tok::Punct(':'), .. }, rest @ ..] = tokens {
+                self.define(name, Symbol::Label(self.address), &location, *column)?;
+                tokens = rest;
+            }
+
+            let (first, column) = match tokens.first() {
+                Some(Token { tok: Tok::Ident(first), column }) => (first.clone(), *column),
+                Some(token) => return Err(location.error(token.column, "expected an instruction, label or directive")),
+                None => continue,
+            };
+            let keyword = first.to_ascii_lowercase();
+
+            match tokens.get(1).map(|token| &token.tok) {
+                Some(Tok::Punct('=')) => {
+                    let expr = parse_full_expr(&tokens[2..], tokens[1].column)
+                        .map_err(|(column, message)| location.error(column, message))?;
+                    self.define(&first, Symbol::Constant(expr, location.clone(), self.address), &location, column)?;
+                    continue;
+                },
+                Some(Tok::Ident(equ)) if equ.eq_ignore_ascii_case("equ") => {
+                    let expr = parse_full_expr(&tokens[2..], tokens[1].column)
+                        .map_err(|(column, message)| location.error(column, message))?;
+                    self.define(&first, Symbol::Constant(expr, location.clone(), self.address), &location, column)?;
+                    continue;
+                },
+                _ => {},
+            }
+
+            let args = split_operands(&tokens[1..]).map_err(|(column, message)| location.error(column, message))?;
+            let (item, size) = match keyword.as_str() {
+                "include" => {
+                    let path = match &tokens[1..] {
+                        [Token { tok: Tok::Str(path), .. }] => String::from_utf8_lossy(path).into_owned(),
+                        _ => return Err(location.error(column, "include needs a file name in quotes")),
+                    };
+                    if depth >= MAX_INCLUDE_DEPTH {
+                        return Err(location.error(column, "includes are nested too deeply"));
+                    }
+                    let path: PathBuf = dir.join(path);
+                    self.parse_file(&path, Some((&location, column)), depth + 1)?;
+                    continue;
+                },
+                "db" => {
+                    let mut data = Vec::new();
+                    let mut size = 0;
+                    for arg in args {
+                        let (data_item, len) = match arg {
+                            [Token { tok: Tok::Str(bytes), .. }] => (Data::Str(bytes.clone()), bytes.len()),
+                            _ => (Data::Expr(parse_operand_expr(arg, &location)?), 1),
+                        };
+                        data.push((data_item, arg[0].column));
+                        size += len;
+                    }
+                    (Item::Bytes(data), size)
+                },
+                "dw" => {
+                    let mut words = Vec::new();
+                    for arg in args {
+                        words.push((parse_operand_expr(arg, &location)?, arg[0].column));
+                    }
+                    let size = words.len() * 2;
+                    (Item::Words(words), size)
+                },
+                _ => {
+                    let mut operands = Vec::new();
+                    for arg in args {
+                        let operand = parse_operand(arg).map_err(|(column, message)| location.error(column, message))?;
+                        operands.push((operand, arg[0].column));
+                    }
+                    // LD I, LONG NNNN is the only 4 byte instruction.
+                    let size = match operands.get(1) {
+                        Some((Operand::Long(_), _)) => 4,
+                        _ => 2,
+                    };
+                    (Item::Instruction { mnemonic: keyword, operands, column }, size)
+                },
+            };
+
+            self.statements.push(Statement { location: location.clone(), address: self.address, item });
+            self.address += size;
+            if self.address - ORIGIN > MAX_SIZE {
+                return Err(location.error(0, "program doesn't fit in memory"));
+            }
+        }
+        Ok(())
+    }
+
+    fn define(&mut self, name: &str, symbol: Symbol, location: &Location, column: usize) -> Result<(), AsmError> {
+        if self.symbols.contains_key(name) {
+            return Err(location.error(column, format!("{} is already defined", name)));
+        }
+        if is_reserved(name) {
+            return Err(location.error(column, format!("{} is a register name and can't be redefined", name)));
+        }
+        self.symbols.insert(name.to_string(), symbol);
+        Ok(())
+    }
+
+    fn source_map(&self) -> SourceMap {
+        let lines = self.statements.iter()
+            .filter(|statement| matches!(statement.item, Item::Instruction { .. }))
+            .map(|statement| SourceLine {
+                address: statement.address as u16,
+                file: statement.location.file.to_string(),
+                line: statement.location.line,
+            })
+            .collect();
+        SourceMap::new(lines)
+    }
+
+    // Second pass: every address is known now, so evaluate and encode.
+    fn finish(self) -> Result<Vec<u8>, AsmError> {
+        let mut out = Vec::with_capacity(self.address - ORIGIN);
+        for statement in self.statements.iter() {
+            let eval = Eval { symbols: &self.symbols, here: statement.address, depth: 0 };
+            let location = &statement.location;
+            let result = match &statement.item {
+                Item::Instruction { mnemonic, operands, column } =>
+                    encode(mnemonic, operands, *column, &eval),
+                Item::Bytes(data) => data.iter().try_fold(Vec::new(), |mut bytes, (data, column)| {
+                    match data {
+                        Data::Str(s) => bytes.extend_from_slice(s),
+                        Data::Expr(expr) => {
+                            let value = eval.eval(expr)?;
+                            bytes.push(fit(value, -0x80, 0xFF, "byte", *column)? as u8);
+                        },
+                    }
+                    Ok(bytes)
+                }),
+                Item::Words(words) => words.iter().try_fold(Vec::new(), |mut bytes, (expr, column)| {
+                    let value = eval.eval(expr)?;
+                    let value = fit(value, -0x8000, 0xFFFF, "word", *column)?;
+                    bytes.extend_from_slice(&(value as u16).to_be_bytes());
+                    Ok(bytes)
+                }),
+            };
+            match result {
+                Ok(bytes) => out.extend_from_slice(&bytes),
+                Err(EncodeError::At(column, message)) => return Err(location.error(column, message)),
+                Err(EncodeError::Asm(err)) => return Err(err),
+            }
+        }
+        Ok(out)
+    }
+}
+
+enum EncodeError {
+    At(usize, String),
+    Asm(AsmError),
+}
+
+impl From<AsmError> for EncodeError {
+    fn from(err: AsmError) -> EncodeError {
+        EncodeError::Asm(err)
+    }
+}
+
+impl From<(usize, String)> for EncodeError {
+    fn from((column, message): (usize, String)) -> EncodeError {
+        EncodeError::At(column, message)
+    }
+}
+
+struct Eval<'a> {
+    symbols: &'a HashMap<String, Symbol>,
+    here: usize,
+    depth: usize,
+}
+
+impl<'a> Eval<'a> {
+    // Errors in a constant's own definition are reported where it was defined.
+    fn eval(&self, expr: &Expr) -> Result<i64, EncodeError> {
+        Ok(match expr {
+            Expr::Number(n) => *n,
+            Expr::Here => self.here as i64,
+            Expr::Negate(expr) => self.eval(expr)?.wrapping_neg(),
+            Expr::Not(expr) => !self.eval(expr)?,
+            Expr::Symbol(name, column) => match self.symbols.get(name) {
+                Some(Symbol::Label(address)) => *address as i64,
+                Some(Symbol::Constant(expr, location, here)) => {
+                    if self.depth > 64 {
+                        return Err(EncodeError::At(*column, format!("{} is defined in terms of itself", name)));
+                    }
+                    let inner = Eval { symbols: self.symbols, here: *here, depth: self.depth + 1 };
+                    match inner.eval(expr) {
+                        Ok(value) => value,
+                        Err(EncodeError::At(column, message)) => return Err(location.error(column, message).into()),
+                        Err(err) => return Err(err),
+                    }
+                },
+                None => return Err(EncodeError::At(*column, format!("{} isn't defined", name))),
+            },
+            Expr::Binary(op, left, right, column) => {
+                let (a, b) = (self.eval(left)?, self.eval(right)?);
+                match op {
+                    Tok::Punct('+') => a.wrapping_add(b),
+                    Tok::Punct('-') => a.wrapping_sub(b),
+                    Tok::Punct('*') => a.wrapping_mul(b),
+                    Tok::Punct('/') | Tok::Punct('%') if b == 0 =>
+                        return Err(EncodeError::At(*column, "division by zero".to_string())),
+                    Tok::Punct('/') => a.wrapping_div(b),
+                    Tok::Punct('%') => a.wrapping_rem(b),
+                    Tok::Punct('&') => a & b,
+                    Tok::Punct('|') => a | b,
+                    Tok::Punct('^') => a ^ b,
+                    Tok::ShiftLeft => a.wrapping_shl(b as u32),
+                    Tok::ShiftRight => a.wrapping_shr(b as u32),
+                    _ => unreachable!("not a binary operator"),
+                }
+            },
+        })
+    }
+}
+
+// Checks a value fits, allowing negative numbers as two's complement.
+fn fit(value: i64, min: i64, max: i64, what: &str, column: usize) -> Result<i64, (usize, String)> {
+    if value < min || value > max {
+        return Err((column, format!("{} doesn't fit in a {}", value, what)));
+    }
+    Ok(if value < 0 { value & max } else { value })
+}
+
+fn encode(mnemonic: &str, operands: &[(Operand, usize)], column: usize, eval: &Eval) -> Result<Vec<u8>, EncodeError> {
+    use Operand::*;
+
+    let value = |expr: &self::Expr, min: i64, max: i64, what: &str, column: usize| -> Result<u16, EncodeError> {
+        let value = eval.eval(expr)?;
+        Ok(fit(value, min, max, what, column)? as u16)
+    };
+    let addr = |expr: &self::Expr, column: usize| value(expr, 0, 0xFFF, "12-bit address", column);
+    let byte = |expr: &self::Expr, column: usize| value(expr, -0x80, 0xFF, "byte", column);
+    let nibble = |expr: &self::Expr, column: usize| value(expr, 0, 0xF, "nibble", column);
+    let x = |x: u8| (x as u16) << 8;
+    let y = |y: u8| (y as u16) << 4;
+
+    let ops: Vec<&Operand> = operands.iter().map(|(operand, _)| operand).collect();
+    let col = |i: usize| operands.get(i).map_or(column, |(_, column)| *column);
+
+    let opcode: u16 = match (mnemonic, &ops[..]) {
+        ("cls", []) => 0x00E0,
+        ("ret", []) => 0x00EE,
+        ("scd", [Expr(n)]) => 0x00C0 | nibble(n, col(0))?,
+        ("scu", [Expr(n)]) => 0x00D0 | nibble(n, col(0))?,
+        ("scr", []) => 0x00FB,
+        ("scl", []) => 0x00FC,
+        ("exit", []) => 0x00FD,
+        ("low", []) => 0x00FE,
+        ("high", []) => 0x00FF,
+        ("jp", [Expr(a)]) => 0x1000 | addr(a, col(0))?,
+        ("jp", [V(0), Expr(a)]) => 0xB000 | addr(a, col(1))?,
+        ("call", [Expr(a)]) => 0x2000 | addr(a, col(0))?,
+        ("se", [V(vx), Expr(n)]) => 0x3000 | x(*vx) | byte(n, col(1))?,
+        ("sne", [V(vx), Expr(n)]) => 0x4000 | x(*vx) | byte(n, col(1))?,
+        ("se", [V(vx), V(vy)]) => 0x5000 | x(*vx) | y(*vy),
+        ("save", [V(vx), V(vy)]) => 0x5002 | x(*vx) | y(*vy),
+        ("load", [V(vx), V(vy)]) => 0x5003 | x(*vx) | y(*vy),
+        ("ld", [V(vx), Expr(n)]) => 0x6000 | x(*vx) | byte(n, col(1))?,
+        ("add", [V(vx), Expr(n)]) => 0x7000 | x(*vx) | byte(n, col(1))?,
+        ("ld", [V(vx), V(vy)]) => 0x8000 | x(*vx) | y(*vy),
+        ("or", [V(vx), V(vy)]) => 0x8001 | x(*vx) | y(*vy),
+        ("and", [V(vx), V(vy)]) => 0x8002 | x(*vx) | y(*vy),
+        ("xor", [V(vx), V(vy)]) => 0x8003 | x(*vx) | y(*vy),
+        ("add", [V(vx), V(vy)]) => 0x8004 | x(*vx) | y(*vy),
+        ("sub", [V(vx), V(vy)]) => 0x8005 | x(*vx) | y(*vy),
+        ("shr", [V(vx)]) => 0x8006 | x(*vx) | y(*vx),
+        ("shr", [V(vx), V(vy)]) => 0x8006 | x(*vx) | y(*vy),
+        ("subn", [V(vx), V(vy)]) => 0x8007 | x(*vx) | y(*vy),
+        ("shl", [V(vx)]) => 0x800E | x(*vx) | y(*vx),
+        ("shl", [V(vx), V(vy)]) => 0x800E | x(*vx) | y(*vy),
+        ("sne", [V(vx), V(vy)]) => 0x9000 | x(*vx) | y(*vy),
+        ("ld", [I, Expr(a)]) => 0xA000 | addr(a, col(1))?,
+        ("ld", [I, Long(a)]) => {
+            let a = value(a, 0, 0xFFFF, "16-bit address", col(1))?;
+            return Ok(vec![0xF0, 0x00, (a >> 8) as u8, a as u8]);
+        },
+        ("rnd", [V(vx), Expr(n)]) => 0xC000 | x(*vx) | byte(n, col(1))?,
+        ("drw", [V(vx), V(vy), Expr(n)]) => 0xD000 | x(*vx) | y(*vy) | nibble(n, col(2))?,
+        ("skp", [V(vx)]) => 0xE09E | x(*vx),
+        ("sknp", [V(vx)]) => 0xE0A1 | x(*vx),
+        ("plane", [Expr(n)]) => 0xF001 | nibble(n, col(0))? << 8,
+        ("audio", []) => 0xF002,
+        ("ld", [V(vx), DT]) => 0xF007 | x(*vx),
+        ("ld", [V(vx), K]) => 0xF00A | x(*vx),
+        ("ld", [DT, V(vx)]) => 0xF015 | x(*vx),
+        ("ld", [ST, V(vx)]) => 0xF018 | x(*vx),
+        ("add", [I, V(vx)]) => 0xF01E | x(*vx),
+        ("ld", [F, V(vx)]) => 0xF029 | x(*vx),
+        ("ld", [HF, V(vx)]) => 0xF030 | x(*vx),
+        ("ld", [B, V(vx)]) => 0xF033 | x(*vx),
+        ("pitch", [V(vx)]) => 0xF03A | x(*vx),
+        ("ld", [IndirectI, V(vx)]) => 0xF055 | x(*vx),
+        ("ld", [V(vx), IndirectI]) => 0xF065 | x(*vx),
+        ("ld", [R, V(vx)]) => 0xF075 | x(*vx),
+        ("ld", [V(vx), R]) => 0xF085 | x(*vx),
+        _ if !is_mnemonic(mnemonic) =>
+            return Err(EncodeError::At(column, format!("unknown instruction {}", mnemonic.to_ascii_uppercase()))),
+        _ => return Err(EncodeError::At(column,
+            format!("{} can't take these operands", mnemonic.to_ascii_uppercase()))),
+    };
+    Ok(opcode.to_be_bytes().to_vec())
+}
+
+fn is_mnemonic(name: &str) -> bool {
+    matches!(name, "cls" | "ret" | "scd" | "scu" | "scr" | "scl" | "exit" | "low" | "high"
+        | "jp" | "call" | "se" | "sne" | "save" | "load" | "ld" | "add" | "or" | "and"
+        | "xor" | "sub" | "shr" | "subn" | "shl" | "rnd" | "drw" | "skp" | "sknp"
+        | "plane" | "audio" | "pitch")
+}
+
+fn register(name: &str) -> Option<Operand> {
+    let lower = name.to_ascii_lowercase();
+    Some(match lower.as_str() {
+        "i" => Operand::I,
+        "dt" => Operand::DT,
+        "st" => Operand::ST,
+        "k" => Operand::K,
+        "f" => Operand::F,
+        "hf" => Operand::HF,
+        "b" => Operand::B,
+        "r" => Operand::R,
+        _ => {
+            let digit = lower.strip_prefix('v')?;
+            if digit.len() != 1 {
+                return None;
+            }
+            Operand::V(u8::from_str_radix(digit, 16).ok()?)
+        },
+    })
+}
+
+fn is_reserved(name: &str) -> bool {
+    register(name).is_some() || name.eq_ignore_ascii_case("long")
+}
+
+// Splits the tokens after the mnemonic at the commas.
+fn split_operands(tokens: &[Token]) -> Result<Vec<&[Token]>, (usize, String)> {
+    if tokens.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut operands = Vec::new();
+    let mut start = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        if token.tok == Tok::Punct(',') {
+            if i == start {
+                return Err((token.column, "missing operand before ','".to_string()));
+            }
+            operands.push(&tokens[start..i]);
+            start = i + 1;
+        }
+    }
+    if start == tokens.len() {
+        return Err((tokens[tokens.len() - 1].column, "missing operand after ','".to_string()));
+    }
+    operands.push(&tokens[start..]);
+    Ok(operands)
+}
+
+fn parse_operand(tokens: &[Token]) -> Result<Operand, (usize, String)> {
+    match tokens {
+        [Token { tok: Tok::Ident(name), .. }] => {
+            if let Some(register) = register(name) {
+                return Ok(register);
+            }
+        },
+        [Token { tok: Tok::Punct('['), .. }, Token { tok: Tok::Ident(name), .. }, Token { tok: Tok::Punct(']'), .. }]
+            if name.eq_ignore_ascii_case("i") => return Ok(Operand::IndirectI),
+        [Token { tok: Tok::Ident(name), column }, rest @ ..] if name.eq_ignore_ascii_case("long") =>
+            return Ok(Operand::Long(parse_full_expr(rest, *column)?)),
+        _ => {},
+    }
+    Ok(Operand::Expr(parse_full_expr(tokens, tokens[0].column)?))
+}
+
+fn parse_operand_expr(tokens: &[Token], location: &Location) -> Result<Expr, AsmError> {
+    parse_full_expr(tokens, tokens[0].column).map_err(|(column, message)| location.error(column, message))
+}
+
+// `column` is used for the error when there's no expression at all.
+fn parse_full_expr(tokens: &[Token], column: usize) -> Result<Expr, (usize, String)> {
+    if tokens.is_empty() {
+        return Err((column, "expected an expression".to_string()));
+    }
+    let mut parser = ExprParser { tokens, pos: 0 };
+    let expr = parser.binary(0)?;
+    if let Some(token) = tokens.get(parser.pos) {
+        return Err((token.column, "unexpected text after expression".to_string()));
+    }
+    Ok(expr)
+}
+
+struct ExprParser<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+}
+
+// Lowest first, like C.
+fn precedence(tok: &Tok) -> Option<usize> {
+    Some(match tok {
+        Tok::Punct('|') => 0,
+        Tok::Punct('^') => 1,
+        Tok::Punct('&') => 2,
+        Tok::ShiftLeft | Tok::ShiftRight => 3,
+        Tok::Punct('+') | Tok::Punct('-') => 4,
+        Tok::Punct('*') | Tok::Punct('/') | Tok::Punct('%') => 5,
+        _ => return None,
+    })
+}
+
+impl<'a> ExprParser<'a> {
+    fn binary(&mut self, min: usize) -> Result<Expr, (usize, String)> {
+        let mut left = self.unary()?;
+        while let Some(token) = self.tokens.get(self.pos) {
+            let prec = match precedence(&token.tok) {
+                Some(prec) if prec >= min => prec,
+                _ => break,
+            };
+            self.pos += 1;
+            let right = self.binary(prec + 1)?;
+            left = Expr::Binary(token.tok.clone(), Box::new(left), Box::new(right), token.column);
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expr, (usize, String)> {
+        let token = match self.tokens.get(self.pos) {
+            Some(token) => token,
+            None => {
+                let column = self.tokens.last().map_or(0, |token| token.column);
+                return Err((column, "expression ends too soon".to_string()));
+            },
+        };
+        self.pos += 1;
+        match &token.tok {
+            Tok::Number(n) => Ok(Expr::Number(*n)),
+            Tok::Ident(name) => Ok(Expr::Symbol(name.clone(), token.column)),
+            Tok::Punct('$') => Ok(Expr::Here),
+            Tok::Punct('-') => Ok(Expr::Negate(Box::new(self.unary()?))),
+            Tok::Punct('~') => Ok(Expr::Not(Box::new(self.unary()?))),
+            Tok::Punct('(') => {
+                let expr = self.binary(0)?;
+                match self.tokens.get(self.pos) {
+                    Some(Token { tok: Tok::Punct(')'), .. }) => {
+                        self.pos += 1;
+                        Ok(expr)
+                    },
+                    _ => Err((token.column, "unclosed '('".to_string())),
+                }
+            },
+            _ => Err((token.column, "expected a number, name or '('".to_string())),
+        }
+    }
+}
+
+fn tokenize(line: &str) -> Result<Vec<Token>, (usize, String)> {
+    let chars: Vec<char> = line.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let column = i + 1;
+        if c == ';' {
+            break;
+        } else if c.is_whitespace() {
+            i += 1;
+        } else if c.is_ascii_digit() {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            let text: String = chars[start..i].iter().filter(|&&c| c != '_').collect();
+            let lower = text.to_ascii_lowercase();
+            let parsed = if let Some(hex) = lower.strip_prefix("0x") {
+                i64::from_str_radix(hex, 16)
+            } else if let Some(bin) = lower.strip_prefix("0b") {
+                i64::from_str_radix(bin, 2)
+            } else {
+                lower.parse()
+            };
+            match parsed {
+                Ok(n) => tokens.push(Token { tok: Tok::Number(n), column }),
+                Err(_) => return Err((column, format!("{} isn't a number", text))),
+            }
+        } else if c.is_alphabetic() || c == '_' || c == '.' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '.') {
+                i += 1;
+            }
+            tokens.push(Token { tok: Tok::Ident(chars[start..i].iter().collect()), column });
+        } else if c == '"' {
+            i += 1;
+            let mut s = String::new();
+            loop {
+                match chars.get(i) {
+                    Some('"') => break,
+                    Some('\\') => {
+                        match chars.get(i + 1) {
+                            Some('n') => s.push('\n'),
+                            Some('0') => s.push('\0'),
+                            Some(&c) => s.push(c),
+                            None => return Err((column, "unterminated string".to_string())),
+                        }
+                        i += 2;
+                    },
+                    Some(&c) => {
+                        s.push(c);
+                        i += 1;
+                    },
+                    None => return Err((column, "unterminated string".to_string())),
+                }
+            }
+            i += 1;
+            tokens.push(Token { tok: Tok::Str(s.into_bytes()), column });
+        } else if (c == '<' || c == '>') && chars.get(i + 1) == Some(&c) {
+            let tok = if c == '<' { Tok::ShiftLeft } else { Tok::ShiftRight };
+            tokens.push(Token { tok, column });
+            i += 2;
+        } else if ",:=()[]+-*/%&|^~$".contains(c) {
+            tokens.push(Token { tok: Tok::Punct(c), column });
+            i += 1;
+        } else {
+            return Err((column, format!("unexpected character '{}'", c)));
+        }
+    }
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm;
+    use std::env;
+
+    fn error(source: &str) -> AsmError {
+        assemble(source).expect_err("should not assemble")
+    }
+
+    #[test]
+    fn labels_forward_and_back() {
+        let rom = assemble("start:\n    JP end\n    JP start\nend: CALL start\n").unwrap();
+        assert_eq!(rom, [0x12, 0x04, 0x12, 0x00, 0x22, 0x00]);
+    }
+
+    #[test]
+    fn constants_and_expressions() {
+        let source = "
+            SPEED = HALF * 2 + 1   ; uses a constant defined further down
+            HALF = 3
+            LD V0, SPEED
+            LD V1, (1 << 4) | 0b11
+            LD V2, -1
+            LD I, data
+            ADD V3, data_end - data
+            JP $
+        data:
+            db 1, 2, \"ab\"
+            dw 0x1234
+        data_end:
+        ";
+        let rom = assemble(source).unwrap();
+        assert_eq!(rom, [
+            0x60, 0x07, 0x61, 0x13, 0x62, 0xFF, 0xA2, 0x0C, 0x73, 0x06, 0x12, 0x0A,
+            0x01, 0x02, b'a', b'b', 0x12, 0x34,
+        ]);
+    }
+
+    #[test]
+    fn case_of_mnemonics_and_symbols() {
+        assert_eq!(assemble("ld v0, 1\ncls").unwrap(), [0x60, 0x01, 0x00, 0xE0]);
+        let err = error("Loop:\nJP loop");
+        assert_eq!((err.line, err.column), (2, 4));
+    }
+
+    #[test]
+    fn errors_point_at_the_problem() {
+        let err = error("CLS\n    LD V0, 0x100\n");
+        assert_eq!((err.file.as_str(), err.line, err.column), ("<source>", 2, 12));
+
+        let err = error("CLS\n  JP nowhere\n");
+        assert_eq!((err.line, err.column), (2, 6));
+        assert!(err.message.contains("nowhere"), "{}", err.message);
+
+        let err = error("a:\na:\n");
+        assert_eq!(err.line, 2);
+
+        let err = error("  FROB V0\n");
+        assert_eq!((err.line, err.column), (1, 3));
+
+        // In the definitions, not where it's used.
+        let err = error("ONE = TWO\nTWO = ONE\nLD V0, ONE\n");
+        assert_eq!(err.line, 1);
+        assert!(err.message.contains("itself"), "{}", err.message);
+    }
+
+    #[test]
+    fn includes_are_relative_to_the_file() {
+        let dir = env::temp_dir().join(format!("chip8-asm-test-{}", std::process::id()));
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        fs::write(dir.join("main.asm"), "include \"lib/sprites.asm\"\n    JP digit\n").unwrap();
+        fs::write(dir.join("lib/sprites.asm"), "include \"digit.asm\"\n").unwrap();
+        fs::write(dir.join("lib/digit.asm"), "digit:\n    db 0xF0\n    bad\n").unwrap();
+
+        let err = assemble_file(&dir.join("main.asm")).unwrap_err();
+        assert_eq!((err.file, err.line), (dir.join("lib").join("digit.asm").display().to_string(), 3));
+
+        fs::write(dir.join("lib/digit.asm"), "digit:\n    db 0xF0\n").unwrap();
+        let (rom, map) = assemble_file_with_map(&dir.join("main.asm")).unwrap();
+        assert_eq!(rom, [0xF0, 0x12, 0x00]);
+        assert_eq!(map.line_at(0x201).map(|line| line.line), Some(2));
+
+        fs::write(dir.join("main.asm"), "include \"main.asm\"\n").unwrap();
+        assert!(assemble_file(&dir.join("main.asm")).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // What the disassembler prints has to assemble back to the same bytes.
+    fn round_trip(rom: &[u8]) {
+        let source: Vec<String> = disasm::disassemble(rom).iter().map(|line| line.to_string()).collect();
+        let source = source.join("\n");
+        assert_eq!(assemble(&source).unwrap_or_else(|err| panic!("{}\n{}", err, source)), rom, "\n{}", source);
+    }
+
+    #[test]
+    fn disassembly_round_trips() {
+        round_trip(&[
+            0x00, 0xE0, 0x00, 0xC3, 0x00, 0xD4, 0x00, 0xFB, 0x00, 0xFC, 0x00, 0xFE, 0x00, 0xFF,
+            0x61, 0x23, 0x71, 0xFF, 0x81, 0x20, 0x81, 0x21, 0x81, 0x22, 0x81, 0x23, 0x81, 0x24,
+            0x81, 0x25, 0x81, 0x26, 0x81, 0x27, 0x81, 0x2E, 0x31, 0x05, 0x41, 0x05, 0x51, 0x20,
+            0x91, 0x20, 0x52, 0x32, 0x53, 0x43, 0xA3, 0x00, 0xC1, 0x0F, 0xD1, 0x25, 0xD1, 0x20,
+            0xE1, 0x9E, 0xE1, 0xA1, 0xF1, 0x07, 0xF1, 0x0A, 0xF1, 0x15, 0xF1, 0x18, 0xF1, 0x1E,
+            0xF1, 0x29, 0xF1, 0x30, 0xF1, 0x33, 0xF1, 0x55, 0xF1, 0x65, 0xF1, 0x75, 0xF1, 0x85,
+            0xF2, 0x01, 0xF0, 0x02, 0xF1, 0x3A, 0xF0, 0x00, 0x12, 0x34, 0x22, 0x80, 0xB2, 0x00,
+            0x00, 0xEE, 0x00, 0xFD, 0xAA, 0x55, 0x01,
+        ]);
+
+        let roms = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("roms");
+        for name in ["arithmetic", "memory", "display", "hires", "keypad"] {
+            round_trip(&assemble_file(&roms.join(format!("{}.asm", name))).unwrap());
+        }
+    }
+}
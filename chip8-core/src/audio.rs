@@ -0,0 +1,182 @@
+// The beeper as samples, for writing to a WAV file. It plays the XO-CHIP
+// audio pattern at its playback rate while the sound timer runs, which for
+// plain CHIP-8 programs is the default square wave.
+//
+// The samples follow the Env's own clock rather than the wall's: each
+// instruction is a fraction of a 60hz frame, so however fast or slow the
+// program is run, a second of it is a second of sound. Whatever the sound
+// was doing when the samples were last made is taken to have lasted until
+// now, so making them after every step is exact to the instruction and
+// after every frame is exact to the frame.
+
+use std::io::{self, Seek, SeekFrom, Write};
+
+use crate::host::Host;
+use crate::vm::Env;
+
+const VOLUME: f64 = 0.25 * i16::MAX as f64;
+
+pub struct Beeper {
+    sample_rate: u32,
+    made: u64, // samples so far
+    position: f64, // in the pattern, which is 128 samples long
+    beeping: bool, // what the sound was doing last time
+    pattern: [u8; 16],
+    rate: f64,
+}
+
+impl Beeper {
+    pub fn new(sample_rate: u32) -> Beeper {
+        Beeper { sample_rate, made: 0, position: 0.0, beeping: false, pattern: [0; 16], rate: 0.0 }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // Adds the samples from last time up to the Env's current point in
+    // time. After a rewind or a save state is loaded that point jumps, and
+    // the samples pick up from there.
+    pub fn render<H: Host>(&mut self, env: &Env<H>, out: &mut Vec<i16>) {
+        let cycles_per_frame = env.cycles_per_frame() as u64;
+        let cycles = env.frame_count() * cycles_per_frame + env.frame_cycle() as u64;
+        let due = (cycles as u128 * self.sample_rate as u128 / (60 * cycles_per_frame) as u128) as u64;
+        // More than a second ahead is a jump too.
+        if due < self.made || due - self.made > self.sample_rate as u64 {
+            self.made = due;
+        }
+
+        let step = self.rate / self.sample_rate as f64;
+        for _ in self.made..due {
+            let sample = if self.beeping {
+                let i = self.position as usize;
+                self.position = (self.position + step) % 128.0;
+                if self.pattern[i / 8] >> (7 - i % 8) & 1 == 1 { VOLUME } else { -VOLUME }
+            } else {
+                0.0
+            };
+            out.push(sample as i16);
+        }
+        self.made = due;
+
+        self.beeping = env.is_beeping();
+        self.pattern = *env.audio_pattern();
+        self.rate = env.playback_rate() as f64;
+    }
+}
+
+// 16-bit mono PCM. The sizes in the header are filled in by finish.
+pub struct WavWriter<W: Write + Seek> {
+    out: W,
+    bytes: u64, // of samples written
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut out: W, sample_rate: u32) -> io::Result<WavWriter<W>> {
+        out.write_all(b"RIFF\0\0\0\0WAVEfmt ")?;
+        out.write_all(&16u32.to_le_bytes())?;
+        out.write_all(&1u16.to_le_bytes())?; // PCM
+        out.write_all(&1u16.to_le_bytes())?; // channels
+        out.write_all(&sample_rate.to_le_bytes())?;
+        out.write_all(&(sample_rate * 2).to_le_bytes())?; // bytes per second
+        out.write_all(&2u16.to_le_bytes())?; // bytes per sample
+        out.write_all(&16u16.to_le_bytes())?; // bits per sample
+        out.write_all(b"data\0\0\0\0")?;
+        Ok(WavWriter { out, bytes: 0 })
+    }
+
+    pub fn write(&mut self, samples: &[i16]) -> io::Result<()> {
+        let bytes: Vec<u8> = samples.iter().flat_map(|sample| sample.to_le_bytes()).collect();
+        self.out.write_all(&bytes)?;
+        self.bytes += bytes.len() as u64;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        // Past 4GB the sizes are wrong, but most players read to the end anyway.
+        let bytes = self.bytes.min(u32::MAX as u64 - 36) as u32;
+        self.out.seek(SeekFrom::Start(4))?;
+        self.out.write_all(&(36 + bytes).to_le_bytes())?;
+        self.out.seek(SeekFrom::Start(40))?;
+        self.out.write_all(&bytes.to_le_bytes())?;
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    use crate::keypad::Keypad;
+    use crate::quirks::Quirks;
+
+    // Beeps for 30 frames, then loops quietly.
+    fn beeping() -> Env<Keypad> {
+        let mut env = Env::new(Keypad::new(), Quirks::default());
+        // LD V0, 30; LD ST, V0; JP 0x204
+        env.load_into_memory(&[0x60, 0x1E, 0xF0, 0x18, 0x12, 0x04]).unwrap();
+        env
+    }
+
+    #[test]
+    fn a_frame_is_a_60th_of_a_second() {
+        for (sample_rate, frames) in [(44100, 61), (48000, 7), (22050, 121)] {
+            let mut env = beeping();
+            let mut beeper = Beeper::new(sample_rate);
+            let mut samples = Vec::new();
+            beeper.render(&env, &mut samples);
+            for _ in 0..frames {
+                env.run_frame().unwrap();
+                beeper.render(&env, &mut samples);
+            }
+            assert_eq!(samples.len() as u64, frames * sample_rate as u64 / 60);
+        }
+    }
+
+    #[test]
+    fn every_step_comes_to_the_same() {
+        let (mut by_frame, mut by_step) = (Vec::new(), Vec::new());
+        let (mut env, mut beeper) = (beeping(), Beeper::new(44100));
+        for _ in 0..45 {
+            env.run_frame().unwrap();
+            beeper.render(&env, &mut by_frame);
+        }
+        let (mut env, mut beeper) = (beeping(), Beeper::new(44100));
+        while env.frame_count() < 45 {
+            env.step().unwrap();
+            beeper.render(&env, &mut by_step);
+        }
+        assert_eq!(by_step.len(), by_frame.len());
+        // The square wave, for as long as the sound timer ran.
+        for samples in [by_frame, by_step] {
+            let loud = samples.iter().filter(|&&sample| sample != 0).count();
+            assert!((29 * 735..=30 * 735).contains(&loud), "{} samples", loud);
+            assert!(samples.iter().all(|&sample| [0, VOLUME as i16, -VOLUME as i16].contains(&sample)));
+            assert_eq!(samples.last(), Some(&0));
+        }
+    }
+
+    #[test]
+    fn finish_fills_in_the_sizes() {
+        let mut wav = WavWriter::new(Cursor::new(Vec::new()), 44100).unwrap();
+        wav.write(&[1, -1, 2]).unwrap();
+        wav.write(&[i16::MAX; 997]).unwrap();
+        let bytes = wav.finish().unwrap().into_inner();
+        let u32_at = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+        let u16_at = |i: usize| u16::from_le_bytes(bytes[i..i + 2].try_into().unwrap());
+
+        assert_eq!(bytes.len(), 44 + 2000);
+        assert_eq!(bytes[..4], *b"RIFF");
+        assert_eq!(u32_at(4), 36 + 2000);
+        assert_eq!(bytes[8..16], *b"WAVEfmt ");
+        assert_eq!((u32_at(16), u16_at(20), u16_at(22)), (16, 1, 1));
+        assert_eq!((u32_at(24), u32_at(28)), (44100, 88200));
+        assert_eq!((u16_at(32), u16_at(34)), (2, 16));
+        assert_eq!(bytes[36..40], *b"data");
+        assert_eq!(u32_at(40), 2000);
+        assert_eq!(bytes[44..50], [1, 0, 0xFF, 0xFF, 2, 0]);
+    }
+}
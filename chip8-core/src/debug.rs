@@ -0,0 +1,381 @@
+use std::cell::Cell;
+use std::collections::BTreeSet;
+use std::fmt;
+
+use crate::error::Chip8Error;
+use crate::host::Host;
+use crate::vm::Env;
+
+mod condition;
+
+pub use condition::{Condition, ConditionError};
+
+// Why the debugger stopped the program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    Breakpoint(u16),
+    RunTo(u16),
+    Paused, // asked to with `pause`
+    // The instruction at pc touched a watched address.
+    Watchpoint { pc: u16, address: u16, access: Access },
+    // The condition at this index of `watches` became true.
+    Condition(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+// `len` bytes of memory from `address`, watched for reads, writes or both.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub address: u16,
+    pub len: u16,
+    pub read: bool,
+    pub write: bool,
+}
+
+impl Watchpoint {
+    #[inline]
+    fn matches(&self, address: usize, access: Access) -> bool {
+        let start = self.address as usize;
+        (start..start + self.len as usize).contains(&address) && match access {
+            Access::Read => self.read,
+            Access::Write => self.write,
+        }
+    }
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match (self.read, self.write) {
+            (true, false) => "read",
+            (false, true) => "write",
+            _ => "access",
+        };
+        write!(f, "{} 0x{:03X}", kind, self.address)?;
+        if self.len > 1 {
+            write!(f, "-0x{:03X}", self.address as usize + self.len as usize - 1)?;
+        }
+        Ok(())
+    }
+}
+
+// Stops the program after the instruction that makes it happen.
+#[derive(Debug, Clone)]
+pub enum Watch {
+    Memory(Watchpoint),
+    Condition(Condition), // only when it goes from false to true
+}
+
+impl fmt::Display for Watch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Watch::Memory(watchpoint) => write!(f, "{}", watchpoint),
+            Watch::Condition(condition) => write!(f, "when {}", condition),
+        }
+    }
+}
+
+// What the Env checks its loads and stores against. The interpreter only
+// has a shared borrow on its loads, hence the Cell.
+#[derive(Debug)]
+pub(crate) struct MemoryWatch {
+    watchpoints: Vec<Watchpoint>,
+    hit: Cell<Option<(u16, Access)>>,
+}
+
+impl MemoryWatch {
+    pub(crate) fn new(watchpoints: &[Watchpoint]) -> MemoryWatch {
+        MemoryWatch { watchpoints: watchpoints.to_vec(), hit: Cell::new(None) }
+    }
+
+    #[inline]
+    pub(crate) fn check(&self, address: usize, access: Access) {
+        if self.hit.get().is_none() && self.watchpoints.iter().any(|wp| wp.matches(address, access)) {
+            self.hit.set(Some((address as u16, access)));
+        }
+    }
+
+    pub(crate) fn take_hit(&mut self) -> Option<(u16, Access)> {
+        self.hit.take()
+    }
+}
+
+// Runs an Env with breakpoints and watches. Frontends call `run_frame` in
+// place of `Env::run_frame`, which does nothing while paused, and `step` to
+// go one instruction at a time. Breakpoints stop the program before the
+// instruction at that address runs, watches right after the one that
+// triggers them.
+#[derive(Debug, Default)]
+pub struct Debugger {
+    breakpoints: BTreeSet<u16>,
+    watches: Vec<Watch>,
+    // Whether each condition in watches held after the last step. None
+    // until it's first checked.
+    conditions_held: Vec<Option<bool>>,
+    watches_changed: bool, // the Env needs the new memory watchpoints
+    run_to: Option<u16>, // a one-off breakpoint
+    paused: bool,
+    stop: Option<Stop>,
+    resuming: bool, // don't stop again on the breakpoint we're sitting on
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger::default()
+    }
+
+    // Returns false if there already was one.
+    pub fn add_breakpoint(&mut self, address: u16) -> bool {
+        self.breakpoints.insert(address)
+    }
+
+    // Returns false if there wasn't one.
+    pub fn remove_breakpoint(&mut self, address: u16) -> bool {
+        self.breakpoints.remove(&address)
+    }
+
+    pub fn has_breakpoint(&self, address: u16) -> bool {
+        self.breakpoints.contains(&address)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = u16> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    // Returns the new watch's index.
+    pub fn add_watch(&mut self, watch: Watch) -> usize {
+        self.watches_changed |= matches!(watch, Watch::Memory(_));
+        self.watches.push(watch);
+        self.conditions_held.push(None);
+        self.watches.len() - 1
+    }
+
+    pub fn remove_watch(&mut self, index: usize) -> Option<Watch> {
+        if index >= self.watches.len() {
+            return None;
+        }
+        self.conditions_held.remove(index);
+        let watch = self.watches.remove(index);
+        self.watches_changed |= matches!(watch, Watch::Memory(_));
+        Some(watch)
+    }
+
+    pub fn watches(&self) -> &[Watch] {
+        &self.watches
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn pause(&mut self) {
+        if !self.paused {
+            self.paused = true;
+            self.stop = Some(Stop::Paused);
+        }
+    }
+
+    // Continue, which also cancels a pending run-to.
+    pub fn resume(&mut self) {
+        self.run_to = None;
+        self.paused = false;
+        self.resuming = true;
+    }
+
+    // Continue until the instruction at `address` is about to run.
+    pub fn run_to(&mut self, address: u16) {
+        self.resume();
+        self.run_to = Some(address);
+    }
+
+    // Why the program stopped since this was last called, if it did.
+    pub fn take_stop(&mut self) -> Option<Stop> {
+        self.stop.take()
+    }
+
+    // Runs one instruction, paused or not, ignoring breakpoints. Watches
+    // still report through take_stop.
+    pub fn step<H: Host>(&mut self, env: &mut Env<H>) -> Result<(), Chip8Error> {
+        self.prepare(env);
+        self.resuming = false;
+        let pc = env.program_counter();
+        if let Err(err) = env.step() {
+            env.take_watch_hit();
+            return Err(err);
+        }
+        if let Some(stop) = self.check_watches(env, pc) {
+            self.stop = Some(stop);
+        }
+        Ok(())
+    }
+
+    // Brings the Env's watchpoints up to date, and notes which conditions
+    // already hold so they only stop us when they become true.
+    fn prepare<H: Host>(&mut self, env: &mut Env<H>) {
+        if self.watches_changed {
+            let watchpoints: Vec<Watchpoint> = self.watches.iter()
+                .filter_map(|watch| match watch {
+                    Watch::Memory(watchpoint) => Some(*watchpoint),
+                    Watch::Condition(_) => None,
+                })
+                .collect();
+            env.set_watchpoints(&watchpoints);
+            self.watches_changed = false;
+        }
+        for (watch, held) in self.watches.iter().zip(self.conditions_held.iter_mut()) {
+            if let (Watch::Condition(condition), None) = (watch, &held) {
+                *held = Some(condition.is_true(env));
+            }
+        }
+    }
+
+    // After the instruction at pc has run.
+    fn check_watches<H: Host>(&mut self, env: &mut Env<H>, pc: u16) -> Option<Stop> {
+        if self.watches.is_empty() {
+            return None;
+        }
+        let mut stop = env.take_watch_hit()
+            .map(|(address, access)| Stop::Watchpoint { pc, address, access });
+        for (i, (watch, held)) in self.watches.iter().zip(self.conditions_held.iter_mut()).enumerate() {
+            if let Watch::Condition(condition) = watch {
+                let now = condition.is_true(env);
+                if now && *held == Some(false) && stop.is_none() {
+                    stop = Some(Stop::Condition(i));
+                }
+                *held = Some(now);
+            }
+        }
+        stop
+    }
+
+    // Steps to the end of the frame unless paused or a breakpoint is hit on
+    // the way. An error pauses too, leaving the PC at the faulting instruction.
+    pub fn run_frame<H: Host>(&mut self, env: &mut Env<H>) -> Result<(), Chip8Error> {
+        if self.paused {
+            return Ok(());
+        }
+        self.prepare(env);
+        let frame = env.frame_count();
+        while env.frame_count() == frame && !env.has_exited() {
+            let pc = env.program_counter();
+            if !self.resuming {
+                let stop = if self.run_to == Some(pc) {
+                    self.run_to = None;
+                    Some(Stop::RunTo(pc))
+                } else if self.breakpoints.contains(&pc) {
+                    Some(Stop::Breakpoint(pc))
+                } else {
+                    None
+                };
+                if stop.is_some() {
+                    self.paused = true;
+                    self.stop = stop;
+                    return Ok(());
+                }
+            }
+            self.resuming = false;
+            if let Err(err) = env.step() {
+                // Forget what the faulting instruction touched, or it would
+                // stop the next one.
+                env.take_watch_hit();
+                self.paused = true;
+                return Err(err);
+            }
+            if let Some(stop) = self.check_watches(env, pc) {
+                self.paused = true;
+                self.stop = Some(stop);
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keypad::Keypad;
+    use crate::quirks::Quirks;
+
+    fn env_with(rom: &[u8]) -> Env<Keypad> {
+        let mut env = Env::new(Keypad::new(), Quirks::default());
+        env.load_into_memory(rom).unwrap();
+        env
+    }
+
+    fn watchpoint(address: u16, len: u16, read: bool, write: bool) -> Watch {
+        Watch::Memory(Watchpoint { address, len, read, write })
+    }
+
+    #[test]
+    fn watchpoints_match_their_range_and_access() {
+        let read = Watchpoint { address: 0x300, len: 2, read: true, write: false };
+        assert!(read.matches(0x300, Access::Read));
+        assert!(read.matches(0x301, Access::Read));
+        assert!(!read.matches(0x302, Access::Read));
+        assert!(!read.matches(0x2FF, Access::Read));
+        assert!(!read.matches(0x300, Access::Write));
+
+        let write = Watchpoint { read: false, write: true, ..read };
+        assert!(write.matches(0x301, Access::Write));
+        assert!(!write.matches(0x301, Access::Read));
+
+        let access = Watchpoint { read: true, write: true, ..read };
+        assert!(access.matches(0x300, Access::Read));
+        assert!(access.matches(0x300, Access::Write));
+    }
+
+    #[test]
+    fn memory_watches_stop_after_the_instruction() {
+        // LD I, 0x300; LD V0, 5; LD [I], V0; LD I, 0x300; LD V0, [I]
+        let mut env = env_with(&[0xA3, 0x00, 0x60, 0x05, 0xF0, 0x55, 0xA3, 0x00, 0xF0, 0x65]);
+        let mut debugger = Debugger::new();
+        debugger.add_watch(watchpoint(0x300, 1, false, true));
+        debugger.add_watch(watchpoint(0x300, 1, true, false));
+
+        debugger.step(&mut env).unwrap();
+        debugger.step(&mut env).unwrap();
+        assert_eq!(debugger.take_stop(), None);
+        debugger.step(&mut env).unwrap();
+        assert_eq!(debugger.take_stop(), Some(Stop::Watchpoint { pc: 0x204, address: 0x300, access: Access::Write }));
+        debugger.step(&mut env).unwrap();
+        assert_eq!(debugger.take_stop(), None);
+        debugger.step(&mut env).unwrap();
+        assert_eq!(debugger.take_stop(), Some(Stop::Watchpoint { pc: 0x208, address: 0x300, access: Access::Read }));
+    }
+
+    #[test]
+    fn conditions_stop_only_when_they_become_true() {
+        // ADD V0, 1, forever
+        let mut env = env_with(&[0x70, 0x01, 0x12, 0x00]);
+        let mut debugger = Debugger::new();
+        debugger.add_watch(Watch::Condition(Condition::parse("V0 < 100").unwrap()));
+        debugger.add_watch(Watch::Condition(Condition::parse("V0 == 2").unwrap()));
+
+        let mut stops = Vec::new();
+        for _ in 0..8 {
+            debugger.step(&mut env).unwrap();
+            stops.extend(debugger.take_stop().map(|stop| (env.registers()[0], stop)));
+        }
+        assert_eq!(stops, [(2, Stop::Condition(1))]);
+    }
+
+    #[test]
+    fn a_fault_forgets_what_it_touched() {
+        // DRW V0, V0, 5 with I two bytes from the end of memory.
+        let mut env = env_with(&[0xD0, 0x05]);
+        env.set_index_register(0xFFFE);
+        let mut debugger = Debugger::new();
+        debugger.add_watch(watchpoint(0xFFFE, 1, true, false));
+
+        assert!(debugger.run_frame(&mut env).is_err());
+        assert_eq!(debugger.take_stop(), None);
+        env.memory_mut()[0x200..0x202].copy_from_slice(&[0x60, 0x00]); // LD V0, 0
+        debugger.step(&mut env).unwrap();
+        assert_eq!(debugger.take_stop(), None);
+    }
+}
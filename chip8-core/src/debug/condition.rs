@@ -0,0 +1,282 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::host::Host;
+use crate::vm::Env;
+
+// An expression over the machine's state, like `V3 == 0x10 && I > 0x300`.
+// It can use V0-VF, I, PC, SP, DT, ST, `[addr]` for a byte of memory,
+// numbers (0x for hex), C's comparison, logic and arithmetic operators and
+// parentheses. Anything but 0 counts as true.
+#[derive(Debug, Clone)]
+pub struct Condition {
+    text: String,
+    expr: Expr,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConditionError {
+    pub column: usize, // from 1
+    pub message: String,
+}
+
+impl fmt::Display for ConditionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "column {}: {}", self.column, self.message)
+    }
+}
+
+impl Error for ConditionError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Value {
+    V(u8),
+    I,
+    PC,
+    SP,
+    DT,
+    ST,
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Number(i64),
+    Value(Value),
+    Memory(Box<Expr>),
+    Unary(&'static str, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+}
+
+// Lowest first. Each level is a list of operators, longest first so `<=`
+// isn't read as `<`.
+const LEVELS: [&[&str]; 7] = [
+    &["||"],
+    &["&&"],
+    &["==", "!="],
+    &["<=", ">=", "<", ">"],
+    &["|", "^"],
+    &["&"],
+    &["+", "-"],
+];
+
+impl Condition {
+    pub fn parse(text: &str) -> Result<Condition, ConditionError> {
+        let mut parser = Parser { text, pos: 0 };
+        let expr = parser.binary(0)?;
+        parser.skip_space();
+        if parser.pos < text.len() {
+            return Err(parser.error("unexpected text after the expression"));
+        }
+        Ok(Condition { text: text.trim().to_string(), expr })
+    }
+
+    pub fn is_true<H: Host>(&self, env: &Env<H>) -> bool {
+        eval(&self.expr, env) != 0
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+fn eval<H: Host>(expr: &Expr, env: &Env<H>) -> i64 {
+    match expr {
+        Expr::Number(n) => *n,
+        Expr::Value(value) => match *value {
+            Value::V(x) => env.registers()[x as usize] as i64,
+            Value::I => env.index_register() as i64,
+            Value::PC => env.program_counter() as i64,
+            Value::SP => env.stack().len() as i64,
+            Value::DT => env.delay_timer() as i64,
+            Value::ST => env.sound_timer() as i64,
+        },
+        Expr::Memory(address) => {
+            let address = eval(address, env);
+            usize::try_from(address).ok()
+                .and_then(|address| env.memory().get(address))
+                .map_or(0, |&byte| byte as i64)
+        },
+        Expr::Unary(op, a) => {
+            let a = eval(a, env);
+            match *op {
+                "!" => (a == 0) as i64,
+                "-" => a.wrapping_neg(),
+                _ => !a,
+            }
+        },
+        Expr::Binary(op, a, b) => {
+            let (a, b) = (eval(a, env), eval(b, env));
+            match *op {
+                "||" => (a != 0 || b != 0) as i64,
+                "&&" => (a != 0 && b != 0) as i64,
+                "==" => (a == b) as i64,
+                "!=" => (a != b) as i64,
+                "<=" => (a <= b) as i64,
+                ">=" => (a >= b) as i64,
+                "<" => (a < b) as i64,
+                ">" => (a > b) as i64,
+                "|" => a | b,
+                "^" => a ^ b,
+                "&" => a & b,
+                "+" => a.wrapping_add(b),
+                _ => a.wrapping_sub(b),
+            }
+        },
+    }
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize, // in bytes
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, message: &str) -> ConditionError {
+        ConditionError {
+            column: self.text[..self.pos].chars().count() + 1,
+            message: message.to_string(),
+        }
+    }
+
+    fn skip_space(&mut self) {
+        let rest = &self.text[self.pos..];
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    // Consumes the first of the operators that's next, if any. `|` and `&`
+    // don't match the start of `||` and `&&`.
+    fn operator(&mut self, ops: &[&'static str]) -> Option<&'static str> {
+        self.skip_space();
+        let rest = &self.text[self.pos..];
+        let op = ops.iter().copied().find(|op| {
+            rest.starts_with(op) && !(op.len() == 1 && "|&".contains(*op) && rest[1..].starts_with(op))
+        })?;
+        self.pos += op.len();
+        Some(op)
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Expr, ConditionError> {
+        if level == LEVELS.len() {
+            return self.unary();
+        }
+        let mut left = self.binary(level + 1)?;
+        while let Some(op) = self.operator(LEVELS[level]) {
+            let right = self.binary(level + 1)?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expr, ConditionError> {
+        if let Some(op) = self.operator(&["!", "-", "~"]) {
+            return Ok(Expr::Unary(op, Box::new(self.unary()?)));
+        }
+        if self.operator(&["("]).is_some() {
+            let expr = self.binary(0)?;
+            return match self.operator(&[")"]) {
+                Some(_) => Ok(expr),
+                None => Err(self.error("expected )")),
+            };
+        }
+        if self.operator(&["["]).is_some() {
+            let expr = self.binary(0)?;
+            return match self.operator(&["]"]) {
+                Some(_) => Ok(Expr::Memory(Box::new(expr))),
+                None => Err(self.error("expected ]")),
+            };
+        }
+
+        let rest = &self.text[self.pos..];
+        let len = rest.find(|c: char| !c.is_ascii_alphanumeric()).unwrap_or(rest.len());
+        if len == 0 {
+            return Err(self.error("expected a number, register or ("));
+        }
+        let word = &rest[..len];
+        let lower = word.to_ascii_lowercase();
+        let expr = if let Some(hex) = lower.strip_prefix("0x") {
+            i64::from_str_radix(hex, 16).ok().map(Expr::Number)
+        } else if lower.starts_with(|c: char| c.is_ascii_digit()) {
+            lower.parse().ok().map(Expr::Number)
+        } else {
+            match lower.as_str() {
+                "i" => Some(Value::I),
+                "pc" => Some(Value::PC),
+                "sp" => Some(Value::SP),
+                "dt" => Some(Value::DT),
+                "st" => Some(Value::ST),
+                _ => lower.strip_prefix('v')
+                    .filter(|digit| digit.len() == 1)
+                    .and_then(|digit| u8::from_str_radix(digit, 16).ok())
+                    .map(Value::V),
+            }.map(Expr::Value)
+        };
+        match expr {
+            Some(expr) => {
+                self.pos += len;
+                Ok(expr)
+            },
+            None => Err(self.error(&format!("don't know what {} is", word))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keypad::Keypad;
+    use crate::quirks::Quirks;
+
+    fn holds(text: &str) -> bool {
+        let mut env = Env::new(Keypad::new(), Quirks::default());
+        env.load_into_memory(&[0x60, 0x12]).unwrap();
+        env.registers_mut()[3] = 0x10;
+        env.set_index_register(0x300);
+        Condition::parse(text).unwrap().is_true(&env)
+    }
+
+    fn error(text: &str) -> ConditionError {
+        Condition::parse(text).unwrap_err()
+    }
+
+    #[test]
+    fn machine_state() {
+        assert!(holds("V3 == 0x10 && I > 0x2FF"));
+        assert!(holds("v3 == 16 && i == 0x300"));
+        assert!(holds("PC == 0x200 && SP == 0 && DT == 0 && ST == 0"));
+        assert!(holds("[0x200] == 0x60 && [PC + 1] == 0x12"));
+        assert!(holds("[0x10000] == 0 && [-1] == 0"));
+        assert!(!holds("V0"));
+        assert!(holds("V3"));
+    }
+
+    #[test]
+    fn precedence() {
+        assert!(holds("1 || 0 && 0"));
+        assert!(!holds("(1 || 0) && 0"));
+        assert!(holds("V3 & 0xF0 == 0x10"));
+        assert!(holds("1 | 2 == 3"));
+        assert!(holds("1 + 2 == 3"));
+        assert!(holds("10 - 2 - 3 == 5"));
+        assert!(holds("2 < 3 == 1"));
+        assert!(holds("1 <= 1 && 1 >= 1 && !(1 < 1)"));
+        assert!(holds("-1 < 0 && ~0 == -1 && !0"));
+        assert!(holds("3 & 1 && 6 ^ 6 == 0"));
+    }
+
+    #[test]
+    fn text_is_kept_trimmed() {
+        assert_eq!(Condition::parse("  V0 ==1 ").unwrap().to_string(), "V0 ==1");
+    }
+
+    #[test]
+    fn errors_point_at_the_problem() {
+        assert_eq!(error("V3 =="), ConditionError { column: 6, message: "expected a number, register or (".into() });
+        assert_eq!(error("(1 + 2"), ConditionError { column: 7, message: "expected )".into() });
+        assert_eq!(error("[I"), ConditionError { column: 3, message: "expected ]".into() });
+        assert_eq!(error("VG == 1"), ConditionError { column: 1, message: "don't know what VG is".into() });
+        assert_eq!(error("1 2"), ConditionError { column: 3, message: "unexpected text after the expression".into() });
+        assert_eq!(error("0xZZ").column, 1);
+    }
+}
@@ -0,0 +1,129 @@
+use std::fmt;
+
+use crate::instruction::Instruction;
+
+// Where ROMs are loaded, and so where disassembly starts.
+const START: usize = 0x200;
+
+// One line of a disassembly: either an instruction or a run of data bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Line {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    pub instruction: Option<Instruction>, // None for data
+}
+
+impl Line {
+    // Just the assembler source, without the address and bytes.
+    pub fn source(&self) -> String {
+        match self.instruction {
+            Some(Instruction::SetIndexLong) =>
+                format!("LD I, LONG 0x{:02X}{:02X}", self.bytes[2], self.bytes[3]),
+            Some(instr) => instr.to_string(),
+            None => {
+                let bytes: Vec<String> = self.bytes.iter().map(|b| format!("0x{:02X}", b)).collect();
+                format!("db {}", bytes.join(", "))
+            },
+        }
+    }
+}
+
+// Prints as assembler source with the address and raw bytes in a comment,
+// so a disassembly can be fed straight back into the assembler.
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let raw: Vec<String> = self.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        write!(f, "    {:<24} ; {:03X}: {}", self.source(), self.address, raw.join(" "))
+    }
+}
+
+// Disassembles a ROM as it would be loaded at 0x200. Only what can be reached
+// by following jumps, calls and skips from the start is treated as code,
+// everything else comes out as data.
+pub fn disassemble(rom: &[u8]) -> Vec<Line> {
+    let end = START + rom.len();
+    let byte = |addr: usize| rom[addr - START];
+    let decode = |addr: usize| -> Option<Instruction> {
+        if addr < START || addr + 1 >= end {
+            return None;
+        }
+        let instr = Instruction::decode((byte(addr) as u16) << 8 | byte(addr + 1) as u16)?;
+        // A long load cut off by the end of the ROM isn't code.
+        if addr + instr.size() as usize > end {
+            return None;
+        }
+        Some(instr)
+    };
+
+    // Walk every path through the program, marking where instructions start.
+    let mut code = vec![false; rom.len()];
+    let mut pending = vec![START];
+    while let Some(mut addr) = pending.pop() {
+        loop {
+            if addr < START || addr >= end || code[addr - START] {
+                break;
+            }
+            let instr = match decode(addr) {
+                Some(instr) => instr,
+                None => break,
+            };
+            code[addr - START] = true;
+            let next = addr + instr.size() as usize;
+            match instr {
+                Instruction::Jump(target) => {
+                    addr = target as usize;
+                    continue;
+                },
+                Instruction::Call(target) => pending.push(target as usize),
+                // The real target depends on a register. Usually NNN is a
+                // table of jumps though, so at least start there.
+                Instruction::JumpOffset(target) => {
+                    pending.push(target as usize);
+                    break;
+                },
+                Instruction::Return | Instruction::Exit => break,
+                Instruction::SkipIfEqual(..)
+                | Instruction::SkipIfNotEqual(..)
+                | Instruction::SkipIfRegistersEqual(..)
+                | Instruction::SkipIfRegistersNotEqual(..)
+                | Instruction::SkipIfKey(_)
+                | Instruction::SkipIfNotKey(_) => {
+                    // Skips hop over a whole F000 NNNN too.
+                    let skipped = decode(next).map_or(2, |instr| instr.size() as usize);
+                    pending.push(next + skipped);
+                },
+                _ => {},
+            }
+            addr = next;
+        }
+    }
+
+    let mut lines = Vec::new();
+    let mut addr = START;
+    while addr < end {
+        if code[addr - START] {
+            // Marked as code, so this always decodes.
+            let instr = decode(addr).unwrap();
+            let len = instr.size() as usize;
+            lines.push(Line {
+                address: addr as u16,
+                bytes: rom[addr - START..addr - START + len].to_vec(),
+                instruction: Some(instr),
+            });
+            addr += len;
+        } else {
+            // Up to 8 bytes of data per line, stopping short of the next instruction.
+            let mut len = 1;
+            while len < 8 && addr + len < end && !code[addr + len - START] {
+                len += 1;
+            }
+            lines.push(Line {
+                address: addr as u16,
+                bytes: rom[addr - START..addr - START + len].to_vec(),
+                instruction: None,
+            });
+            addr += len;
+        }
+    }
+    lines
+}
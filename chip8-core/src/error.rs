@@ -0,0 +1,58 @@
+use std::error::Error;
+use std::fmt;
+
+// Everything that can stop the interpreter. Errors raised while running carry
+// the address of the faulting instruction and the opcode itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Chip8Error {
+    UnknownOpcode { pc: u16, opcode: u16 },
+    // RETURN with no outer subroutine to return to.
+    StackUnderflow { pc: u16, opcode: u16 },
+    // CALL nested deeper than the stack allows.
+    StackOverflow { pc: u16, opcode: u16 },
+    // An instruction tried to touch memory past the end of RAM.
+    MemoryOutOfBounds { pc: u16, opcode: u16, address: usize },
+    // The ROM doesn't fit between the program start and the end of RAM.
+    RomTooLarge { size: usize, max: usize },
+}
+
+impl Chip8Error {
+    pub fn pc(&self) -> Option<u16> {
+        match *self {
+            Chip8Error::UnknownOpcode { pc, .. }
+            | Chip8Error::StackUnderflow { pc, .. }
+            | Chip8Error::StackOverflow { pc, .. }
+            | Chip8Error::MemoryOutOfBounds { pc, .. } => Some(pc),
+            Chip8Error::RomTooLarge { .. } => None,
+        }
+    }
+
+    pub fn opcode(&self) -> Option<u16> {
+        match *self {
+            Chip8Error::UnknownOpcode { opcode, .. }
+            | Chip8Error::StackUnderflow { opcode, .. }
+            | Chip8Error::StackOverflow { opcode, .. }
+            | Chip8Error::MemoryOutOfBounds { opcode, .. } => Some(opcode),
+            Chip8Error::RomTooLarge { .. } => None,
+        }
+    }
+}
+
+impl fmt::Display for Chip8Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Chip8Error::UnknownOpcode { pc, opcode } =>
+                write!(f, "unrecognized opcode {:04X} at {:03X}", opcode, pc),
+            Chip8Error::StackUnderflow { pc, opcode } =>
+                write!(f, "{:04X} at {:03X} returned with no outer subroutine to return to", opcode, pc),
+            Chip8Error::StackOverflow { pc, opcode } =>
+                write!(f, "{:04X} at {:03X} exceeded the maximum levels of recursion", opcode, pc),
+            Chip8Error::MemoryOutOfBounds { pc, opcode, address } =>
+                write!(f, "{:04X} at {:03X} accessed memory out of bounds at {:X}", opcode, pc, address),
+            Chip8Error::RomTooLarge { size, max } =>
+                write!(f, "ROM is {} bytes but only {} fit in memory", size, max),
+        }
+    }
+}
+
+impl Error for Chip8Error {}
@@ -0,0 +1,358 @@
+// Animated GIFs of the display, a frame at a time as the program runs.
+//
+// The picture is always 128x64 pixels times the scale, so low resolution
+// pixels are twice the size and a program switching between the two doesn't
+// change the size of the animation. Only the part of the picture that changed
+// is written for each frame, and a picture that stays the same is one frame
+// with a longer delay.
+//
+// GIF delays are in hundredths of a second, which 60hz doesn't divide, so
+// each frame's delay is worked out from when it started and ended on the
+// 60hz timeline. Players stretch delays under 2 hundredths to a tenth of a
+// second, so a picture that doesn't last that long is dropped for the one
+// after it rather than slowing the animation down.
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use crate::screenshot::{Palette, Screenshot};
+
+const WIDTH: usize = 128;
+const HEIGHT: usize = 64;
+const MIN_DELAY: u64 = 2;
+const MAX_CODES: u16 = 4096;
+
+pub struct GifWriter<W: Write> {
+    out: W,
+    scale: usize,
+    shown: Option<Vec<u8>>, // the picture as of the last frame written
+    pending: Option<Vec<u8>>, // the picture waiting to find out how long it lasts
+    start: u64, // the 60hz frame the pending picture was first shown on
+    frames: u64,
+}
+
+impl<W: Write> GifWriter<W> {
+    // Writes the header straight away. It loops forever.
+    pub fn new(mut out: W, palette: &Palette, scale: usize) -> io::Result<GifWriter<W>> {
+        let scale = scale.max(1);
+        out.write_all(b"GIF89a")?;
+        out.write_all(&(dimension(WIDTH * scale)?).to_le_bytes())?;
+        out.write_all(&(dimension(HEIGHT * scale)?).to_le_bytes())?;
+        // A global color table of 4 colors with 2 bits each, no background.
+        out.write_all(&[0x91, 0, 0])?;
+        out.write_all(&palette.concat())?;
+        out.write_all(b"\x21\xFF\x0BNETSCAPE2.0\x03\x01\x00\x00\x00")?;
+        Ok(GifWriter { out, scale, shown: None, pending: None, start: 0, frames: 0 })
+    }
+
+    // Adds a 60th of a second showing the picture.
+    pub fn frame(&mut self, screenshot: &Screenshot) -> io::Result<()> {
+        let picture = screenshot.scaled(self.scale * WIDTH / screenshot.width());
+        let now = self.frames;
+        self.frames += 1;
+        match self.pending.take() {
+            Some(pending) if pending == picture => self.pending = Some(pending),
+            Some(_) if centiseconds(now) - centiseconds(self.start) < MIN_DELAY => {
+                self.pending = Some(picture);
+            },
+            Some(pending) => {
+                self.write_frame(pending, centiseconds(now) - centiseconds(self.start))?;
+                self.pending = Some(picture);
+                self.start = now;
+            },
+            None => self.pending = Some(picture),
+        }
+        Ok(())
+    }
+
+    // Writes out the last picture and the end of the file.
+    pub fn finish(mut self) -> io::Result<W> {
+        if let Some(pending) = self.pending.take() {
+            let delay = centiseconds(self.frames) - centiseconds(self.start);
+            self.write_frame(pending, delay.max(MIN_DELAY))?;
+        }
+        self.out.write_all(&[0x3B])?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    fn write_frame(&mut self, picture: Vec<u8>, delay: u64) -> io::Result<()> {
+        let (width, height) = (WIDTH * self.scale, HEIGHT * self.scale);
+        // The rectangle around whatever changed, or a single pixel if nothing did.
+        let (left, top, right, bottom) = match &self.shown {
+            None => (0, 0, width, height),
+            Some(shown) => {
+                let changed = |i: usize| shown[i] != picture[i];
+                let rows: Vec<usize> = (0..height).filter(|y| (0..width).any(|x| changed(y * width + x))).collect();
+                let columns: Vec<usize> = (0..width).filter(|x| rows.iter().any(|y| changed(y * width + x))).collect();
+                match (rows.first(), rows.last(), columns.first(), columns.last()) {
+                    (Some(&top), Some(&bottom), Some(&left), Some(&right)) => (left, top, right + 1, bottom + 1),
+                    _ => (0, 0, 1, 1),
+                }
+            },
+        };
+
+        // Graphic control: drawn over the frame before, with the delay.
+        let delay = delay.min(u16::MAX as u64) as u16;
+        self.out.write_all(&[0x21, 0xF9, 0x04, 0x04])?;
+        self.out.write_all(&delay.to_le_bytes())?;
+        self.out.write_all(&[0, 0])?;
+
+        self.out.write_all(&[0x2C])?;
+        for value in [left, top, right - left, bottom - top] {
+            self.out.write_all(&(value as u16).to_le_bytes())?;
+        }
+        self.out.write_all(&[0])?;
+
+        let pixels: Vec<u8> = (top..bottom)
+            .flat_map(|y| picture[y * width + left..y * width + right].iter().copied())
+            .collect();
+        self.out.write_all(&[2])?; // the smallest code size there is, for 4 colors
+        for block in lzw(&pixels).chunks(255) {
+            self.out.write_all(&[block.len() as u8])?;
+            self.out.write_all(block)?;
+        }
+        self.out.write_all(&[0])?;
+        self.shown = Some(picture);
+        Ok(())
+    }
+}
+
+fn dimension(size: usize) -> io::Result<u16> {
+    u16::try_from(size).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "too big for a GIF"))
+}
+
+// When a 60hz frame starts, in hundredths of a second, rounded.
+fn centiseconds(frame: u64) -> u64 {
+    (frame * 100 + 30) / 60
+}
+
+// GIF's variable width LZW, for 2 bit pixels.
+fn lzw(pixels: &[u8]) -> Vec<u8> {
+    const CLEAR: u16 = 4;
+    const END: u16 = 5;
+    let mut out = Vec::new();
+    let (mut buffer, mut count) = (0u32, 0u32);
+    let mut put = |code: u16, size: u32, out: &mut Vec<u8>| {
+        buffer |= (code as u32) << count;
+        count += size;
+        while count >= 8 {
+            out.push(buffer as u8);
+            buffer >>= 8;
+            count -= 8;
+        }
+    };
+
+    let mut codes: HashMap<(u16, u8), u16> = HashMap::new();
+    let mut next = END + 1;
+    let mut size = 3;
+    put(CLEAR, size, &mut out);
+    let mut prefix = None;
+    for &pixel in pixels {
+        let Some(code) = prefix else {
+            prefix = Some(pixel as u16);
+            continue;
+        };
+        if let Some(&longer) = codes.get(&(code, pixel)) {
+            prefix = Some(longer);
+            continue;
+        }
+        put(code, size, &mut out);
+        if next < MAX_CODES {
+            codes.insert((code, pixel), next);
+            if next == 1 << size {
+                size += 1;
+            }
+            next += 1;
+        } else {
+            // The table's full, start again.
+            put(CLEAR, size, &mut out);
+            codes.clear();
+            next = END + 1;
+            size = 3;
+        }
+        prefix = Some(pixel as u16);
+    }
+    if let Some(code) = prefix {
+        put(code, size, &mut out);
+        // The decoder adds a code for this one too, which can make the end
+        // code a bit longer.
+        if next < MAX_CODES && next == 1 << size {
+            size += 1;
+        }
+    }
+    put(END, size, &mut out);
+    if count > 0 {
+        out.push(buffer as u8);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keypad::Keypad;
+    use crate::quirks::Quirks;
+    use crate::vm::Env;
+
+    const PALETTE: Palette = [[0, 0, 0], [255, 255, 255], [255, 0, 0], [0, 0, 255]];
+
+    // Decodes lzw's output, and says how many clear codes it had.
+    fn unlzw(data: &[u8]) -> (Vec<u8>, usize) {
+        let mut pos = 0;
+        let mut read = |size: usize| {
+            let code = (0..size).fold(0, |code, i| code | ((data[(pos + i) / 8] >> ((pos + i) % 8) & 1) as usize) << i);
+            pos += size;
+            code
+        };
+        let fresh: Vec<Vec<u8>> = vec![vec![0], vec![1], vec![2], vec![3], vec![], vec![]];
+        let (mut table, mut size, mut previous) = (fresh.clone(), 3, None::<Vec<u8>>);
+        let (mut out, mut clears) = (Vec::new(), 0);
+        loop {
+            let code = read(size);
+            match code {
+                4 => {
+                    (table, size, previous) = (fresh.clone(), 3, None);
+                    clears += 1;
+                    continue;
+                },
+                5 => break,
+                _ => {},
+            }
+            let entry = match table.get(code) {
+                Some(entry) => entry.clone(),
+                None => {
+                    assert_eq!(code, table.len(), "a code from the future");
+                    let previous = previous.as_ref().unwrap();
+                    [&previous[..], &previous[..1]].concat()
+                },
+            };
+            out.extend_from_slice(&entry);
+            if let Some(previous) = previous {
+                if table.len() < MAX_CODES as usize {
+                    table.push([&previous[..], &entry[..1]].concat());
+                }
+            }
+            if table.len() == 1 << size && size < 12 {
+                size += 1;
+            }
+            previous = Some(entry);
+        }
+        (out, clears)
+    }
+
+    #[test]
+    fn lzw_decodes_to_the_pixels() {
+        let mut seed = 1u32;
+        let noise: Vec<u8> = (0..40_000).map(|_| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (seed >> 16) as u8 & 3
+        }).collect();
+        let cases: [(Vec<u8>, usize); 6] = [
+            (vec![], 1),
+            (vec![3], 1),
+            (vec![0, 1, 2, 3, 0, 1, 2, 3, 0, 1, 2, 3], 1),
+            // Runs, where codes get used the moment they're made.
+            (vec![2; 1000], 1),
+            (vec![1; 128 * 64 * 16], 1),
+            // Enough that the table fills up and starts again.
+            (noise, 3),
+        ];
+        for (pixels, clears) in cases {
+            assert_eq!(unlzw(&lzw(&pixels)), (pixels.clone(), clears), "{} pixels", pixels.len());
+        }
+    }
+
+    // Pictures that differ from each other, from a program that draws a
+    // digit one pixel further along every frame.
+    fn pictures(count: usize) -> Vec<Screenshot> {
+        let mut env = Env::new(Keypad::new(), Quirks::default());
+        // LD F, V0; DRW V1, V2, 5; ADD V1, 1; JP 0x200
+        env.load_into_memory(&[0xF0, 0x29, 0xD1, 0x25, 0x71, 0x01, 0x12, 0x00]).unwrap();
+        (0..count).map(|_| {
+            env.run_frame().unwrap();
+            Screenshot::capture(&env)
+        }).collect()
+    }
+
+    // The delays, and the picture left showing after each frame.
+    fn play(gif: &[u8]) -> (Vec<u64>, Vec<Vec<u8>>) {
+        assert_eq!(gif[..6], *b"GIF89a");
+        let width = u16::from_le_bytes([gif[6], gif[7]]) as usize;
+        let height = u16::from_le_bytes([gif[8], gif[9]]) as usize;
+        assert_eq!(gif[10..13], [0x91, 0, 0]);
+        assert_eq!(gif[13..25], PALETTE.concat());
+        let mut rest = &gif[25 + 19..];
+        let mut canvas = vec![0; width * height];
+        let (mut delays, mut shown) = (Vec::new(), Vec::new());
+        loop {
+            match rest[0] {
+                0x21 => {
+                    assert_eq!(rest[1..4], [0xF9, 0x04, 0x04]);
+                    delays.push(u16::from_le_bytes([rest[4], rest[5]]) as u64);
+                    rest = &rest[8..];
+                },
+                0x2C => {
+                    let field = |i: usize| u16::from_le_bytes([rest[1 + 2 * i], rest[2 + 2 * i]]) as usize;
+                    let (left, top, w, h) = (field(0), field(1), field(2), field(3));
+                    assert_eq!(rest[9..11], [0, 2]);
+                    rest = &rest[11..];
+                    let mut data = Vec::new();
+                    while rest[0] != 0 {
+                        let len = rest[0] as usize;
+                        data.extend_from_slice(&rest[1..1 + len]);
+                        rest = &rest[1 + len..];
+                    }
+                    rest = &rest[1..];
+                    let (pixels, _) = unlzw(&data);
+                    assert_eq!(pixels.len(), w * h);
+                    for (i, row) in pixels.chunks(w).enumerate() {
+                        canvas[(top + i) * width + left..][..w].copy_from_slice(row);
+                    }
+                    shown.push(canvas.clone());
+                },
+                0x3B => break,
+                other => panic!("unexpected block {:02X}", other),
+            }
+        }
+        assert_eq!(rest, [0x3B]);
+        (delays, shown)
+    }
+
+    #[test]
+    fn frames_add_up_to_the_running_time() {
+        let pictures = pictures(12);
+        // Which picture each 60hz frame shows: some too short to keep, some
+        // held for a while.
+        let schedule = [0, 1, 2, 3, 3, 3, 4, 5, 5, 5, 5, 5, 5, 5, 6, 7, 7, 7, 7, 8, 9, 10, 11, 11, 11, 11];
+        for repeat in [1, 3, 10] {
+            let mut gif = GifWriter::new(Vec::new(), &PALETTE, 1).unwrap();
+            let mut frames = 0;
+            for _ in 0..repeat {
+                for &n in &schedule {
+                    gif.frame(&pictures[n]).unwrap();
+                    frames += 1;
+                }
+            }
+            let (delays, shown) = play(&gif.finish().unwrap());
+            assert_eq!(delays.iter().sum::<u64>(), (frames * 100 + 30) / 60, "{} frames", frames);
+            assert!(delays.iter().all(|&delay| delay >= MIN_DELAY));
+            // Each picture written is one of them, and it ends on the last.
+            let scaled: Vec<Vec<u8>> = pictures.iter().map(|picture| picture.scaled(2)).collect();
+            assert!(shown.iter().all(|picture| scaled.contains(picture)));
+            assert_eq!(shown.last(), scaled.last());
+        }
+    }
+
+    #[test]
+    fn a_still_picture_is_one_frame() {
+        let picture = &pictures(1)[0];
+        let mut gif = GifWriter::new(Vec::new(), &PALETTE, 1).unwrap();
+        for _ in 0..180 {
+            gif.frame(picture).unwrap();
+        }
+        let (delays, shown) = play(&gif.finish().unwrap());
+        assert_eq!(delays, [300]);
+        assert_eq!(shown, [picture.scaled(2)]);
+    }
+}
@@ -0,0 +1,13 @@
+// Everything the interpreter needs from the outside world goes through here,
+// so the core never has to know about windows, keyboards or SDL.
+// Randomness isn't asked of the host: Env owns its generator, see
+// Env::seed_random, so a run can be replayed from its seed.
+pub trait Host {
+    // Returns if the hex key (0-F) is currently held down.
+    fn is_key_pressed(&self, key: u8) -> bool;
+
+    // Used by FX0A. Returns the hex key that was pressed, or None if there
+    // hasn't been one yet, in which case the instruction is retried on the
+    // next step (this is what makes it block).
+    fn wait_for_key(&mut self) -> Option<u8>;
+}
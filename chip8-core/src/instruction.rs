@@ -0,0 +1,222 @@
+use std::fmt;
+
+pub(crate) mod nibble {
+    #[inline]
+    pub fn unpack(a: u8, b: u8) -> (u8, u8, u8, u8) {
+        ((a & 0b11110000) >> 4, a & 0b00001111,
+            (b & 0b11110000) >> 4, b & 0b00001111)
+    }
+
+    #[inline]
+    pub fn pack(a: u8, b: u8, c: u8, d: u8) -> u16 {
+        ((a as u16) << 12) | ((b as u16) << 8) | ((c as u16) << 4) | (d as u16)
+    }
+}
+
+// Every opcode the interpreter understands (CHIP-8, SUPER-CHIP and XO-CHIP).
+// X and Y are register numbers, NN a byte, NNN an address and N a nibble.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    ScrollDown(u8),           // 00CN
+    ScrollUp(u8),             // 00DN
+    ClearScreen,              // 00E0
+    Return,                   // 00EE
+    ScrollRight,              // 00FB
+    ScrollLeft,               // 00FC
+    Exit,                     // 00FD
+    Lores,                    // 00FE
+    Hires,                    // 00FF
+    Jump(u16),                // 1NNN
+    Call(u16),                // 2NNN
+    SkipIfEqual(u8, u8),      // 3XNN
+    SkipIfNotEqual(u8, u8),   // 4XNN
+    SkipIfRegistersEqual(u8, u8), // 5XY0
+    StoreRange(u8, u8),       // 5XY2
+    LoadRange(u8, u8),        // 5XY3
+    SetValue(u8, u8),         // 6XNN
+    AddValue(u8, u8),         // 7XNN
+    Set(u8, u8),              // 8XY0
+    Or(u8, u8),               // 8XY1
+    And(u8, u8),              // 8XY2
+    Xor(u8, u8),              // 8XY3
+    Add(u8, u8),              // 8XY4
+    Sub(u8, u8),              // 8XY5
+    ShiftRight(u8, u8),       // 8XY6
+    SubReverse(u8, u8),       // 8XY7
+    ShiftLeft(u8, u8),        // 8XYE
+    SkipIfRegistersNotEqual(u8, u8), // 9XY0
+    SetIndex(u16),            // ANNN
+    JumpOffset(u16),          // BNNN (BXNN with the jump quirk)
+    Random(u8, u8),           // CXNN
+    Draw(u8, u8, u8),         // DXYN
+    SkipIfKey(u8),            // EX9E
+    SkipIfNotKey(u8),         // EXA1
+    SetIndexLong,             // F000 NNNN, the address is the word after it
+    SelectPlanes(u8),         // FN01
+    LoadAudioPattern,         // F002
+    GetDelay(u8),             // FX07
+    WaitForKey(u8),           // FX0A
+    SetDelay(u8),             // FX15
+    SetSound(u8),             // FX18
+    AddToIndex(u8),           // FX1E
+    FontCharacter(u8),        // FX29
+    BigFontCharacter(u8),     // FX30
+    Bcd(u8),                  // FX33
+    SetPitch(u8),             // FX3A
+    Store(u8),                // FX55
+    Load(u8),                 // FX65
+    StoreFlags(u8),           // FX75
+    LoadFlags(u8),            // FX85
+}
+
+impl Instruction {
+    // None if the opcode isn't an instruction.
+    pub fn decode(opcode: u16) -> Option<Instruction> {
+        use Instruction::*;
+
+        let (n1, n2, n3, n4) = nibble::unpack((opcode >> 8) as u8, opcode as u8);
+        let nnn = opcode & 0xFFF;
+        let nn = opcode as u8;
+        let (x, y) = (n2, n3);
+
+        //https://en.wikipedia.org/wiki/CHIP-8
+        let instr = match n1 {
+            0 if n2 == 0 => match (n3, n4) {
+                (0xC, n) => ScrollDown(n),
+                (0xD, n) => ScrollUp(n),
+                (0xE, 0) => ClearScreen,
+                (0xE, 0xE) => Return,
+                (0xF, 0xB) => ScrollRight,
+                (0xF, 0xC) => ScrollLeft,
+                (0xF, 0xD) => Exit,
+                (0xF, 0xE) => Lores,
+                (0xF, 0xF) => Hires,
+                _ => return None,
+            },
+            1 => Jump(nnn),
+            2 => Call(nnn),
+            3 => SkipIfEqual(x, nn),
+            4 => SkipIfNotEqual(x, nn),
+            5 => match n4 {
+                0 => SkipIfRegistersEqual(x, y),
+                2 => StoreRange(x, y),
+                3 => LoadRange(x, y),
+                _ => return None,
+            },
+            6 => SetValue(x, nn),
+            7 => AddValue(x, nn),
+            8 => match n4 {
+                0 => Set(x, y),
+                1 => Or(x, y),
+                2 => And(x, y),
+                3 => Xor(x, y),
+                4 => Add(x, y),
+                5 => Sub(x, y),
+                6 => ShiftRight(x, y),
+                7 => SubReverse(x, y),
+                0xE => ShiftLeft(x, y),
+                _ => return None,
+            },
+            9 if n4 == 0 => SkipIfRegistersNotEqual(x, y),
+            0xA => SetIndex(nnn),
+            0xB => JumpOffset(nnn),
+            0xC => Random(x, nn),
+            0xD => Draw(x, y, n4),
+            0xE => match nn {
+                0x9E => SkipIfKey(x),
+                0xA1 => SkipIfNotKey(x),
+                _ => return None,
+            },
+            0xF => match nn {
+                0x00 if x == 0 => SetIndexLong,
+                0x01 => SelectPlanes(x),
+                0x02 if x == 0 => LoadAudioPattern,
+                0x07 => GetDelay(x),
+                0x0A => WaitForKey(x),
+                0x15 => SetDelay(x),
+                0x18 => SetSound(x),
+                0x1E => AddToIndex(x),
+                0x29 => FontCharacter(x),
+                0x30 => BigFontCharacter(x),
+                0x33 => Bcd(x),
+                0x3A => SetPitch(x),
+                0x55 => Store(x),
+                0x65 => Load(x),
+                0x75 => StoreFlags(x),
+                0x85 => LoadFlags(x),
+                _ => return None,
+            },
+            _ => return None,
+        };
+        Some(instr)
+    }
+
+    // In bytes. Only F000 NNNN is longer than the usual 2.
+    pub fn size(&self) -> u16 {
+        match self {
+            Instruction::SetIndexLong => 4,
+            _ => 2,
+        }
+    }
+}
+
+// The assembler's syntax, e.g. "LD V0, 0x1F". For F000 NNNN this is only
+// "LD I, LONG", the address has to be read from the next word.
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Instruction::*;
+
+        match *self {
+            ScrollDown(n) => write!(f, "SCD {}", n),
+            ScrollUp(n) => write!(f, "SCU {}", n),
+            ClearScreen => write!(f, "CLS"),
+            Return => write!(f, "RET"),
+            ScrollRight => write!(f, "SCR"),
+            ScrollLeft => write!(f, "SCL"),
+            Exit => write!(f, "EXIT"),
+            Lores => write!(f, "LOW"),
+            Hires => write!(f, "HIGH"),
+            Jump(nnn) => write!(f, "JP 0x{:03X}", nnn),
+            Call(nnn) => write!(f, "CALL 0x{:03X}", nnn),
+            SkipIfEqual(x, nn) => write!(f, "SE V{:X}, 0x{:02X}", x, nn),
+            SkipIfNotEqual(x, nn) => write!(f, "SNE V{:X}, 0x{:02X}", x, nn),
+            SkipIfRegistersEqual(x, y) => write!(f, "SE V{:X}, V{:X}", x, y),
+            StoreRange(x, y) => write!(f, "SAVE V{:X}, V{:X}", x, y),
+            LoadRange(x, y) => write!(f, "LOAD V{:X}, V{:X}", x, y),
+            SetValue(x, nn) => write!(f, "LD V{:X}, 0x{:02X}", x, nn),
+            AddValue(x, nn) => write!(f, "ADD V{:X}, 0x{:02X}", x, nn),
+            Set(x, y) => write!(f, "LD V{:X}, V{:X}", x, y),
+            Or(x, y) => write!(f, "OR V{:X}, V{:X}", x, y),
+            And(x, y) => write!(f, "AND V{:X}, V{:X}", x, y),
+            Xor(x, y) => write!(f, "XOR V{:X}, V{:X}", x, y),
+            Add(x, y) => write!(f, "ADD V{:X}, V{:X}", x, y),
+            Sub(x, y) => write!(f, "SUB V{:X}, V{:X}", x, y),
+            ShiftRight(x, y) => write!(f, "SHR V{:X}, V{:X}", x, y),
+            SubReverse(x, y) => write!(f, "SUBN V{:X}, V{:X}", x, y),
+            ShiftLeft(x, y) => write!(f, "SHL V{:X}, V{:X}", x, y),
+            SkipIfRegistersNotEqual(x, y) => write!(f, "SNE V{:X}, V{:X}", x, y),
+            SetIndex(nnn) => write!(f, "LD I, 0x{:03X}", nnn),
+            JumpOffset(nnn) => write!(f, "JP V0, 0x{:03X}", nnn),
+            Random(x, nn) => write!(f, "RND V{:X}, 0x{:02X}", x, nn),
+            Draw(x, y, n) => write!(f, "DRW V{:X}, V{:X}, {}", x, y, n),
+            SkipIfKey(x) => write!(f, "SKP V{:X}", x),
+            SkipIfNotKey(x) => write!(f, "SKNP V{:X}", x),
+            SetIndexLong => write!(f, "LD I, LONG"),
+            SelectPlanes(n) => write!(f, "PLANE {}", n),
+            LoadAudioPattern => write!(f, "AUDIO"),
+            GetDelay(x) => write!(f, "LD V{:X}, DT", x),
+            WaitForKey(x) => write!(f, "LD V{:X}, K", x),
+            SetDelay(x) => write!(f, "LD DT, V{:X}", x),
+            SetSound(x) => write!(f, "LD ST, V{:X}", x),
+            AddToIndex(x) => write!(f, "ADD I, V{:X}", x),
+            FontCharacter(x) => write!(f, "LD F, V{:X}", x),
+            BigFontCharacter(x) => write!(f, "LD HF, V{:X}", x),
+            Bcd(x) => write!(f, "LD B, V{:X}", x),
+            SetPitch(x) => write!(f, "PITCH V{:X}", x),
+            Store(x) => write!(f, "LD [I], V{:X}", x),
+            Load(x) => write!(f, "LD V{:X}, [I]", x),
+            StoreFlags(x) => write!(f, "LD R, V{:X}", x),
+            LoadFlags(x) => write!(f, "LD V{:X}, R", x),
+        }
+    }
+}
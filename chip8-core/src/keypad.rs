@@ -0,0 +1,57 @@
+use crate::host::Host;
+use crate::vm::Env;
+
+// A plain 16-key keypad that frontends (or tests) press and release keys on.
+#[derive(Default)]
+pub struct Keypad {
+    keys: u16, // bit n is set while key n is held
+    // The last key pressed and still held that FX0A hasn't consumed yet.
+    pressed_key: Option<u8>,
+}
+
+impl Keypad {
+    pub fn new() -> Keypad {
+        Keypad::default()
+    }
+
+    pub fn press(&mut self, key: u8) {
+        if key < 16 {
+            self.keys |= 1 << key;
+            self.pressed_key = Some(key);
+        }
+    }
+
+    // Bit n is set while key n is held.
+    pub fn keys(&self) -> u16 {
+        self.keys
+    }
+
+    pub fn release(&mut self, key: u8) {
+        if key < 16 {
+            self.keys &= !(1 << key);
+            if self.pressed_key == Some(key) {
+                self.pressed_key = None;
+            }
+        }
+    }
+}
+
+impl Host for Keypad {
+    fn is_key_pressed(&self, key: u8) -> bool {
+        key < 16 && self.keys & (1 << key) != 0
+    }
+
+    fn wait_for_key(&mut self) -> Option<u8> {
+        self.pressed_key.take()
+    }
+}
+
+impl Env<Keypad> {
+    pub fn press_key(&mut self, key: u8) {
+        self.host_mut().press(key);
+    }
+
+    pub fn release_key(&mut self, key: u8) {
+        self.host_mut().release(key);
+    }
+}
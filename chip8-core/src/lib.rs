@@ -0,0 +1,31 @@
+// The interpreter itself, with no knowledge of windows, audio or keyboards.
+// Frontends drive an `Env` by calling `step` and reading back the display.
+pub mod asm;
+pub mod audio;
+pub mod debug;
+pub mod disasm;
+pub mod gif;
+pub mod movie;
+pub mod octo;
+pub mod screenshot;
+pub mod script;
+pub mod trace;
+mod checksum;
+mod error;
+mod host;
+mod instruction;
+mod keypad;
+mod quirks;
+mod random;
+mod rewind;
+mod vm;
+
+pub use checksum::fnv1a;
+pub use error::Chip8Error;
+pub use host::Host;
+pub use instruction::Instruction;
+pub use keypad::Keypad;
+pub use quirks::Quirks;
+pub use random::RandomMode;
+pub use rewind::Rewind;
+pub use vm::{Env, StateError};
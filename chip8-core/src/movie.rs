@@ -0,0 +1,300 @@
+// Movies: every key pressed and released while a program ran, with what's
+// needed to run it again the same way, so a session can be played back
+// exactly. They're text, a key script with a header:
+//
+//   # chip8 movie
+//   rom 68e936a294ef2b91
+//   quirks shift_uses_vy load_store_increments_i vf_reset clip_sprites display_wait
+//   random xorshift
+//   seed 1234
+//   cycles-per-frame 9
+//   frames 3600
+//   60 press 5
+//   64 release 5
+//
+// `rom` is a hash of the ROM it was recorded on, `quirks` the ones that
+// were on, and `frames` how long it went on for.
+
+use std::fmt;
+
+use crate::checksum::fnv1a;
+use crate::keypad::Keypad;
+use crate::quirks::Quirks;
+use crate::random::RandomMode;
+use crate::script::{self, KeyEvent, KeyScript, ScriptError};
+use crate::vm::Env;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Movie {
+    pub rom_hash: u64,
+    pub quirks: Quirks,
+    pub random_mode: RandomMode,
+    pub seed: u64,
+    pub cycles_per_frame: u32,
+    pub frames: u64,
+    events: Vec<KeyEvent>, // in the order they happened
+}
+
+impl Movie {
+    // An empty one, to record onto. The Env should be set up the same way.
+    pub fn new(rom: &[u8], quirks: Quirks, random_mode: RandomMode, seed: u64, cycles_per_frame: u32) -> Movie {
+        Movie { rom_hash: fnv1a(rom), quirks, random_mode, seed, cycles_per_frame, frames: 0, events: Vec::new() }
+    }
+
+    pub fn matches(&self, rom: &[u8]) -> bool {
+        fnv1a(rom) == self.rom_hash
+    }
+
+    pub fn events(&self) -> &[KeyEvent] {
+        &self.events
+    }
+
+    // The keys to play back.
+    pub fn script(&self) -> KeyScript {
+        KeyScript::new(self.events.clone())
+    }
+
+    // Adds a press or release the frontend has just passed on to the Env.
+    pub fn record(&mut self, env: &Env<Keypad>, key: u8, pressed: bool) {
+        self.events.push(KeyEvent { frame: env.frame_count(), key, pressed });
+        self.frames = self.frames.max(env.frame_count());
+    }
+
+    // Marks the end, for after the last frame has run.
+    pub fn finish(&mut self, env: &Env<Keypad>) {
+        self.frames = env.frame_count();
+    }
+
+    // After rewinding or loading a save state, takes the movie back to the
+    // Env's frame, forgetting what came after so the recording goes on from
+    // there. The keys held in the Env are recorded again if need be.
+    pub fn rewind(&mut self, env: &Env<Keypad>) {
+        let frame = env.frame_count();
+        self.events.retain(|event| event.frame < frame);
+        self.frames = frame;
+        let recorded = self.events.iter().fold(0u16, |keys, event| {
+            if event.pressed { keys | 1 << event.key } else { keys & !(1 << event.key) }
+        });
+        let held = env.host().keys();
+        for key in (0..16).filter(|key| (recorded ^ held) & 1 << key != 0) {
+            self.record(env, key, held & 1 << key != 0);
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Movie, ScriptError> {
+        let mut rom_hash = None;
+        let mut quirks = None;
+        let mut random_mode = RandomMode::default();
+        let mut seed = None;
+        let mut cycles_per_frame = None;
+        let mut frames = None;
+        let mut events = Vec::new();
+        let mut last = 0;
+        for (i, line) in text.lines().enumerate() {
+            let error = |message: String| ScriptError { line: i + 1, message };
+            let line = line.split('#').next().unwrap_or("");
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let number = |radix| {
+                match words[..] {
+                    [_, value] => u64::from_str_radix(value, radix).map_err(|_| error(format!("{} isn't a number", value))),
+                    _ => Err(error(format!("{} needs a number", words[0]))),
+                }
+            };
+            match words.first().copied() {
+                None => {},
+                Some("rom") => rom_hash = Some(number(16)?),
+                Some("quirks") => {
+                    let mut set = Quirks {
+                        shift_uses_vy: false,
+                        jump_uses_vx: false,
+                        load_store_increments_i: false,
+                        vf_reset: false,
+                        clip_sprites: false,
+                        display_wait: false,
+                    };
+                    for &name in &words[1..] {
+                        *quirk(&mut set, name).ok_or_else(|| error(format!("{} isn't a quirk", name)))? = true;
+                    }
+                    quirks = Some(set);
+                },
+                Some("random") => {
+                    random_mode = words.get(1)
+                        .and_then(|name| RandomMode::from_name(name))
+                        .ok_or_else(|| error("random needs xorshift or vip".to_string()))?;
+                },
+                Some("seed") => seed = Some(number(10)?),
+                Some("cycles-per-frame") => {
+                    cycles_per_frame = Some(u32::try_from(number(10)?).map_err(|_| error("too many cycles".to_string()))?);
+                },
+                Some("frames") => frames = Some(number(10)?),
+                Some(_) => {
+                    let event = script::parse_event(&words).map_err(error)?;
+                    if event.frame < last {
+                        return Err(error("the frames have to go in order".to_string()));
+                    }
+                    last = event.frame;
+                    events.push(event);
+                },
+            }
+        }
+        let missing = |what: &str| ScriptError { line: 1, message: format!("the movie doesn't say {}", what) };
+        let movie = Movie {
+            rom_hash: rom_hash.ok_or_else(|| missing("which ROM it's for"))?,
+            quirks: quirks.ok_or_else(|| missing("which quirks were on"))?,
+            random_mode,
+            seed: seed.ok_or_else(|| missing("what the random seed was"))?,
+            cycles_per_frame: cycles_per_frame.ok_or_else(|| missing("how many cycles there are in a frame"))?,
+            frames: frames.ok_or_else(|| missing("how long it is"))?,
+            events,
+        };
+        Ok(movie)
+    }
+}
+
+impl fmt::Display for Movie {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "# chip8 movie")?;
+        writeln!(f, "rom {:016x}", self.rom_hash)?;
+        write!(f, "quirks")?;
+        let mut quirks = self.quirks;
+        for name in QUIRKS {
+            if *quirk(&mut quirks, name).unwrap() {
+                write!(f, " {}", name)?;
+            }
+        }
+        writeln!(f)?;
+        let random = match self.random_mode {
+            RandomMode::Xorshift => "xorshift",
+            RandomMode::CosmacVip => "vip",
+        };
+        writeln!(f, "random {}", random)?;
+        writeln!(f, "seed {}", self.seed)?;
+        writeln!(f, "cycles-per-frame {}", self.cycles_per_frame)?;
+        writeln!(f, "frames {}", self.frames)?;
+        for event in &self.events {
+            writeln!(f, "{}", event)?;
+        }
+        Ok(())
+    }
+}
+
+const QUIRKS: [&str; 6] = [
+    "shift_uses_vy", "jump_uses_vx", "load_store_increments_i", "vf_reset", "clip_sprites", "display_wait",
+];
+
+// A quirk by the name of its field.
+fn quirk<'a>(quirks: &'a mut Quirks, name: &str) -> Option<&'a mut bool> {
+    match name {
+        "shift_uses_vy" => Some(&mut quirks.shift_uses_vy),
+        "jump_uses_vx" => Some(&mut quirks.jump_uses_vx),
+        "load_store_increments_i" => Some(&mut quirks.load_store_increments_i),
+        "vf_reset" => Some(&mut quirks.vf_reset),
+        "clip_sprites" => Some(&mut quirks.clip_sprites),
+        "display_wait" => Some(&mut quirks.display_wait),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROM: [u8; 2] = [0x12, 0x00]; // JP 0x200
+
+    fn env() -> Env<Keypad> {
+        let mut env = Env::new(Keypad::new(), Quirks::default());
+        env.load_into_memory(&ROM).unwrap();
+        env
+    }
+
+    fn event(frame: u64, key: u8, pressed: bool) -> KeyEvent {
+        KeyEvent { frame, key, pressed }
+    }
+
+    // Runs to each event's frame and presses or releases the key, as a
+    // frontend would.
+    fn play(env: &mut Env<Keypad>, movie: &mut Movie, events: &[(u64, u8, bool)]) {
+        for &(frame, key, pressed) in events {
+            while env.frame_count() < frame {
+                env.run_frame().unwrap();
+            }
+            if pressed {
+                env.press_key(key);
+            } else {
+                env.release_key(key);
+            }
+            movie.record(env, key, pressed);
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut env = env();
+        let mut movie = Movie::new(&ROM, Quirks::xochip(), RandomMode::CosmacVip, 1234, 30);
+        play(&mut env, &mut movie, &[(0, 1, true), (3, 1, false), (3, 0xF, true), (70, 0xF, false)]);
+        env.run_frame().unwrap();
+        movie.finish(&env);
+
+        let text = movie.to_string();
+        assert!(text.starts_with("# chip8 movie\nrom "), "{}", text);
+        assert!(text.contains("\nquirks shift_uses_vy load_store_increments_i\n"), "{}", text);
+        assert!(text.ends_with("\nrandom vip\nseed 1234\ncycles-per-frame 30\nframes 71\n0 press 1\n3 release 1\n3 press f\n70 release f\n"));
+        let parsed = Movie::parse(&text).unwrap();
+        assert_eq!(parsed, movie);
+        assert_eq!(parsed.to_string(), text);
+        assert!(parsed.matches(&ROM));
+        assert!(!parsed.matches(&[0x12, 0x02]));
+
+        let plain = Movie::new(&ROM, Quirks::cosmac_vip(), RandomMode::Xorshift, 0, 9);
+        assert_eq!(Movie::parse(&plain.to_string()), Ok(plain));
+    }
+
+    #[test]
+    fn parse_errors() {
+        let header = "rom 1\nquirks\nseed 2\ncycles-per-frame 9\nframes 10\n";
+        let error = |text: &str| Movie::parse(text).unwrap_err();
+        assert_eq!(error(&format!("{}5 press 1\n4 press 2", header)), ScriptError {
+            line: 7,
+            message: "the frames have to go in order".to_string(),
+        });
+        assert_eq!(error("rom 1\nquirks fast\n").message, "fast isn't a quirk");
+        assert_eq!(error("rom xyz\n").message, "xyz isn't a number");
+        assert_eq!(error("seed\n").message, "seed needs a number");
+        assert_eq!(error("random lcg\n").message, "random needs xorshift or vip");
+        assert_eq!(error(&header.replace("seed 2\n", "")).message, "the movie doesn't say what the random seed was");
+        assert_eq!(error(&format!("{}1 press 10", header)).line, 6);
+    }
+
+    #[test]
+    fn rewind_forgets_what_came_after() {
+        let mut env = env();
+        let mut movie = Movie::new(&ROM, Quirks::default(), RandomMode::Xorshift, 0, 9);
+        play(&mut env, &mut movie, &[(2, 5, true), (4, 6, true)]);
+        while env.frame_count() < 5 {
+            env.run_frame().unwrap();
+        }
+        let state = env.save_state();
+        play(&mut env, &mut movie, &[(6, 5, false), (8, 6, false), (9, 7, true)]);
+
+        // Back at frame 5 with only 7 held now, 5 and 6 are let go and 7
+        // pressed there.
+        env.load_state(&state).unwrap();
+        movie.rewind(&env);
+        assert_eq!(movie.frames, 5);
+        assert_eq!(movie.events(), [
+            event(2, 5, true),
+            event(4, 6, true),
+            event(5, 5, false),
+            event(5, 6, false),
+            event(5, 7, true),
+        ]);
+
+        // Holding the keys the movie had down by then adds nothing.
+        env.release_key(7);
+        env.press_key(5);
+        env.press_key(6);
+        env.load_state(&state).unwrap();
+        movie.rewind(&env);
+        assert_eq!(movie.events(), [event(2, 5, true), event(4, 6, true)]);
+    }
+}
@@ -0,0 +1,1052 @@
+// A compiler for Octo (https://github.com/JohnEarnest/Octo), the language most
+// CHIP-8 homebrew is written in these days. Octo is a stream of whitespace
+// separated tokens, with `#` starting a comment:
+//
+//   : main
+//       i := sprite
+//       loop
+//           v0 += 1
+//           if v0 == 10 then v0 := 0
+//           sprite v0 v1 4
+//       again
+//   : sprite 0x60 0x90 0x90 0x60
+//
+// Everything from the reference compiler is here except `:stringmode`,
+// including the SUPER-CHIP and XO-CHIP instructions, macros and `:calc`.
+// Like Octo, the program starts with a jump to `main` unless `main` is the
+// very first thing in it.
+
+use std::collections::{HashMap, VecDeque};
+use std::f64::consts;
+use std::fs;
+use std::path::Path;
+use std::rc::Rc;
+
+use crate::asm::{AsmError, SourceLine, SourceMap};
+
+const ORIGIN: usize = 0x200;
+const MAX_EXPANSIONS: usize = 100_000;
+
+pub fn compile(source: &str) -> Result<Vec<u8>, AsmError> {
+    Compiler::new(source, Rc::from("<source>"))?.run().map(|(rom, _)| rom)
+}
+
+pub fn compile_file(path: &Path) -> Result<Vec<u8>, AsmError> {
+    compile_file_with_map(path).map(|(rom, _)| rom)
+}
+
+// Instructions from a macro map to the line they're written on in the macro.
+pub fn compile_file_with_map(path: &Path) -> Result<(Vec<u8>, SourceMap), AsmError> {
+    let name: Rc<str> = Rc::from(path.display().to_string());
+    let source = fs::read_to_string(path).map_err(|err| AsmError {
+        file: name.to_string(),
+        line: 0,
+        column: 0,
+        message: format!("couldn't read {}: {}", path.display(), err),
+    })?;
+    Compiler::new(&source, name)?.run()
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    text: String,
+    line: usize,
+    column: usize,
+}
+
+struct Macro {
+    args: Vec<String>,
+    body: Vec<Token>,
+    calls: usize,
+}
+
+// Where a name used before its label was written has to be patched in.
+#[derive(Debug, Clone, Copy)]
+enum Fixup {
+    Address,    // the NNN of the instruction at the address
+    Word,       // a 16-bit address
+    HighNibble, // :unpack, bits 8-11 into the low nibble of the second byte
+    HighByte,   // :unpack long, bits 8-15 into the second byte
+    LowByte,    // :unpack, bits 0-7 into the second byte
+}
+
+impl Fixup {
+    // The highest address that fits.
+    fn max(self) -> usize {
+        match self {
+            Fixup::Address | Fixup::HighNibble => 0xFFF,
+            Fixup::Word | Fixup::HighByte | Fixup::LowByte => 0xFFFF,
+        }
+    }
+}
+
+enum Operand {
+    Register(u8),
+    Value(u8),
+}
+
+struct Compiler {
+    file: Rc<str>,
+    tokens: VecDeque<Token>, // macro expansions are pushed back on the front
+    last: Token,             // for errors at the end of the file
+    rom: Vec<u8>,            // from 0x200
+    here: usize,
+    labels: HashMap<String, usize>,
+    constants: HashMap<String, f64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    fixups: Vec<(usize, Fixup, Token)>,
+    lines: Vec<SourceLine>,
+    loops: Vec<(usize, Vec<usize>, Token)>, // start, `while` jumps to patch, the `loop`
+    branches: Vec<(usize, Token)>,          // the jump over an `if` or `else` block
+    jump_to_main: bool,
+    expansions: usize,
+}
+
+impl Compiler {
+    fn new(source: &str, file: Rc<str>) -> Result<Compiler, AsmError> {
+        let tokens = tokenize(source).map_err(|(line, column, message)| AsmError {
+            file: file.to_string(),
+            line,
+            column,
+            message,
+        })?;
+        let last = tokens.back().cloned().unwrap_or(Token { text: String::new(), line: 1, column: 0 });
+        Ok(Compiler {
+            file,
+            tokens,
+            last,
+            // Room for `jump main`, dropped again if main comes first.
+            rom: vec![0, 0],
+            here: ORIGIN + 2,
+            labels: HashMap::new(),
+            constants: HashMap::new(),
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
+            fixups: Vec::new(),
+            lines: Vec::new(),
+            loops: Vec::new(),
+            branches: Vec::new(),
+            jump_to_main: true,
+            expansions: 0,
+        })
+    }
+
+    fn run(mut self) -> Result<(Vec<u8>, SourceMap), AsmError> {
+        while let Some(token) = self.tokens.pop_front() {
+            self.statement(token)?;
+        }
+
+        if let Some((_, _, token)) = self.loops.pop() {
+            return Err(self.error(&token, "loop without again"));
+        }
+        if let Some((_, token)) = self.branches.pop() {
+            return Err(self.error(&token, "begin without end"));
+        }
+        for (address, kind, token) in std::mem::take(&mut self.fixups) {
+            let value = match self.labels.get(&token.text) {
+                Some(&value) => value,
+                None => return Err(self.error(&token, format!("{} isn't defined", token.text))),
+            };
+            if value > kind.max() {
+                return Err(self.error(&token, format!("{} is at 0x{:X}, out of reach", token.text, value)));
+            }
+            let i = address - ORIGIN;
+            match kind {
+                Fixup::Address => {
+                    self.rom[i] |= (value >> 8) as u8;
+                    self.rom[i + 1] = value as u8;
+                },
+                Fixup::Word => {
+                    self.rom[i] = (value >> 8) as u8;
+                    self.rom[i + 1] = value as u8;
+                },
+                Fixup::HighNibble => self.rom[i + 1] |= (value >> 8) as u8 & 0xF,
+                Fixup::HighByte => self.rom[i + 1] = (value >> 8) as u8,
+                Fixup::LowByte => self.rom[i + 1] = value as u8,
+            }
+        }
+        if self.jump_to_main {
+            let main = match self.labels.get("main") {
+                Some(&main) => main,
+                None => {
+                    let last = self.last.clone();
+                    return Err(self.error(&last, "the program has no main label"));
+                },
+            };
+            self.rom[0] = 0x10 | (main >> 8) as u8;
+            self.rom[1] = main as u8;
+        }
+        Ok((self.rom, SourceMap::new(self.lines)))
+    }
+
+    fn error(&self, token: &Token, message: impl Into<String>) -> AsmError {
+        AsmError {
+            file: self.file.to_string(),
+            line: token.line,
+            column: token.column,
+            message: message.into(),
+        }
+    }
+
+    fn next(&mut self) -> Result<Token, AsmError> {
+        match self.tokens.pop_front() {
+            Some(token) => Ok(token),
+            None => {
+                let last = self.last.clone();
+                Err(self.error(&last, "unexpected end of file"))
+            },
+        }
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.front().map(|token| token.text.as_str())
+    }
+
+    fn expect(&mut self, text: &str) -> Result<Token, AsmError> {
+        let token = self.next()?;
+        if token.text != text {
+            return Err(self.error(&token, format!("expected {}, found {}", text, token.text)));
+        }
+        Ok(token)
+    }
+
+    fn emit(&mut self, byte: u8, at: &Token) -> Result<(), AsmError> {
+        if self.here >= 0x10000 {
+            return Err(self.error(at, "the program doesn't fit in memory"));
+        }
+        let i = self.here - ORIGIN;
+        if i >= self.rom.len() {
+            self.rom.resize(i + 1, 0);
+        }
+        self.rom[i] = byte;
+        self.here += 1;
+        Ok(())
+    }
+
+    fn inst(&mut self, opcode: u16, at: &Token) -> Result<(), AsmError> {
+        self.lines.push(SourceLine { address: self.here as u16, file: self.file.to_string(), line: at.line });
+        self.emit((opcode >> 8) as u8, at)?;
+        self.emit(opcode as u8, at)
+    }
+
+    // Emits a jump to be patched later, returning its address.
+    fn placeholder_jump(&mut self, at: &Token) -> Result<usize, AsmError> {
+        let address = self.here;
+        self.inst(0x1000, at)?;
+        Ok(address)
+    }
+
+    fn patch_jump(&mut self, address: usize, target: usize, at: &Token) -> Result<(), AsmError> {
+        let opcode = self.jump(target, at)?;
+        let i = address - ORIGIN;
+        self.rom[i] = (opcode >> 8) as u8;
+        self.rom[i + 1] = opcode as u8;
+        Ok(())
+    }
+
+    // A jump for control flow, which can only reach the first 4K.
+    fn jump(&self, target: usize, at: &Token) -> Result<u16, AsmError> {
+        if target > 0xFFF {
+            return Err(self.error(at, format!("{} jumps to 0x{:X}, out of reach", at.text, target)));
+        }
+        Ok(0x1000 | target as u16)
+    }
+
+    fn register(&self, token: &Token) -> Option<u8> {
+        if let Some(&register) = self.aliases.get(&token.text) {
+            return Some(register);
+        }
+        let lower = token.text.to_ascii_lowercase();
+        let digit = lower.strip_prefix('v')?;
+        if digit.len() != 1 {
+            return None;
+        }
+        u8::from_str_radix(digit, 16).ok()
+    }
+
+    fn expect_register(&mut self) -> Result<u8, AsmError> {
+        let token = self.next()?;
+        self.register(&token).ok_or_else(|| self.error(&token, format!("expected a register, found {}", token.text)))
+    }
+
+    // A number, constant or label that's already been defined.
+    fn known_value(&self, token: &Token) -> Option<f64> {
+        number(&token.text)
+            .or_else(|| self.constants.get(&token.text).copied())
+            .or_else(|| self.labels.get(&token.text).map(|&address| address as f64))
+    }
+
+    fn value(&self, token: &Token, min: i64, max: i64, what: &str) -> Result<i64, AsmError> {
+        let value = match self.known_value(token) {
+            Some(value) => value as i64,
+            None => return Err(self.error(token, format!("{} isn't defined", token.text))),
+        };
+        if value < min || value > max {
+            return Err(self.error(token, format!("{} doesn't fit in a {}", value, what)));
+        }
+        Ok(value)
+    }
+
+    fn short(&self, token: &Token) -> Result<u16, AsmError> {
+        Ok((self.value(token, -0x80, 0xFF, "byte")? & 0xFF) as u16)
+    }
+
+    fn nibble(&self, token: &Token) -> Result<u16, AsmError> {
+        Ok(self.value(token, 0, 0xF, "nibble")? as u16)
+    }
+
+    // An address, or a label still to come. `at` is where the fixup goes.
+    fn address(&mut self, token: &Token, kind: Fixup, at: usize) -> Result<u16, AsmError> {
+        if self.known_value(token).is_none() && is_name(&token.text) {
+            self.fixups.push((at, kind, token.clone()));
+            return Ok(0);
+        }
+        Ok(self.value(token, 0, kind.max() as i64, "address")? as u16)
+    }
+
+    fn operand(&self, token: &Token) -> Result<Operand, AsmError> {
+        match self.register(token) {
+            Some(register) => Ok(Operand::Register(register)),
+            None => Ok(Operand::Value(self.short(token)? as u8)),
+        }
+    }
+
+    fn define_label(&mut self, name: Token, address: usize) -> Result<(), AsmError> {
+        self.check_name(&name)?;
+        if name.text == "main" && self.jump_to_main && self.here == ORIGIN + 2 && self.rom.len() == 2 {
+            // main is first, so there's nothing to jump over.
+            self.rom.clear();
+            self.here = ORIGIN;
+            self.jump_to_main = false;
+            self.labels.insert(name.text, ORIGIN);
+            return Ok(());
+        }
+        self.labels.insert(name.text, address);
+        Ok(())
+    }
+
+    fn check_name(&self, name: &Token) -> Result<(), AsmError> {
+        if !is_name(&name.text) || self.register(name).is_some() {
+            return Err(self.error(name, format!("{} can't be used as a name", name.text)));
+        }
+        if self.labels.contains_key(&name.text) || self.constants.contains_key(&name.text) {
+            return Err(self.error(name, format!("{} is already defined", name.text)));
+        }
+        Ok(())
+    }
+
+    fn statement(&mut self, token: Token) -> Result<(), AsmError> {
+        if let Some(register) = self.register(&token) {
+            return self.assignment(register, token);
+        }
+        if let Some(value) = number(&token.text) {
+            if !(-128.0..=255.0).contains(&value) {
+                return Err(self.error(&token, format!("{} doesn't fit in a byte", value)));
+            }
+            return self.emit(value as i64 as u8, &token);
+        }
+
+        match token.text.as_str() {
+            ":" => {
+                let name = self.next()?;
+                let here = self.here;
+                self.define_label(name, here)?;
+            },
+            ":next" => {
+                let name = self.next()?;
+                let here = self.here;
+                self.define_label(name, here + 1)?;
+            },
+            ":alias" => {
+                let name = self.next()?;
+                let register = self.expect_register()?;
+                if self.labels.contains_key(&name.text) || self.constants.contains_key(&name.text) {
+                    return Err(self.error(&name, format!("{} is already defined", name.text)));
+                }
+                self.aliases.insert(name.text, register);
+            },
+            ":const" => {
+                let name = self.next()?;
+                let value = self.next()?;
+                let value = self.known_value(&value)
+                    .ok_or_else(|| self.error(&value, format!("{} isn't defined", value.text)))?;
+                self.check_name(&name)?;
+                self.constants.insert(name.text, value);
+            },
+            ":calc" => {
+                let name = self.next()?;
+                let value = self.calc()?;
+                self.check_name(&name)?;
+                self.constants.insert(name.text, value);
+            },
+            ":byte" => {
+                let value = if self.peek() == Some("{") {
+                    self.calc()? as i64
+                } else {
+                    let value = self.next()?;
+                    self.value(&value, -0x80, 0xFF, "byte")?
+                };
+                if !(-0x80..=0xFF).contains(&value) {
+                    return Err(self.error(&token, format!("{} doesn't fit in a byte", value)));
+                }
+                self.emit(value as u8, &token)?;
+            },
+            ":pointer" => {
+                let value = if self.peek() == Some("{") {
+                    self.calc()? as i64 as u16
+                } else {
+                    let value = self.next()?;
+                    let here = self.here;
+                    self.address(&value, Fixup::Word, here)?
+                };
+                self.inst(value, &token)?;
+            },
+            ":org" => {
+                let address = if self.peek() == Some("{") {
+                    self.calc()? as i64
+                } else {
+                    let address = self.next()?;
+                    self.value(&address, 0, 0xFFFF, "address")?
+                };
+                if !(ORIGIN as i64..0x10000).contains(&address) {
+                    return Err(self.error(&token, format!("can't put code at 0x{:X}", address)));
+                }
+                self.here = address as usize;
+            },
+            ":call" => {
+                let target = self.next()?;
+                let here = self.here;
+                let nnn = self.address(&target, Fixup::Address, here)?;
+                self.inst(0x2000 | nnn, &token)?;
+            },
+            ":unpack" => {
+                let high = self.next()?;
+                let target = self.next()?;
+                let here = self.here;
+                // v0 gets the top of the address, v1 the bottom byte.
+                let (kind, first) = if high.text == "long" {
+                    (Fixup::HighByte, 0x6000)
+                } else {
+                    (Fixup::HighNibble, 0x6000 | self.nibble(&high)? << 4)
+                };
+                let fixups = self.fixups.len();
+                let address = self.address(&target, kind, here)?;
+                if self.fixups.len() > fixups {
+                    self.fixups.push((here + 2, Fixup::LowByte, target));
+                }
+                let top = if let Fixup::HighByte = kind { address >> 8 } else { address >> 8 & 0xF };
+                self.inst(first | top, &token)?;
+                self.inst(0x6100 | (address & 0xFF), &token)?;
+            },
+            ":breakpoint" => {
+                self.next()?;
+            },
+            ":monitor" => {
+                self.next()?;
+                self.next()?;
+            },
+            ":proto" => {
+                self.next()?;
+            },
+            ":assert" => {
+                let message = if self.peek().is_some_and(|text| text.starts_with('"')) {
+                    let message = self.next()?;
+                    message.text.trim_matches('"').to_string()
+                } else {
+                    "assertion failed".to_string()
+                };
+                if self.calc()? == 0.0 {
+                    return Err(self.error(&token, message));
+                }
+            },
+            ":macro" => {
+                let name = self.next()?;
+                let mut args = Vec::new();
+                loop {
+                    let arg = self.next()?;
+                    if arg.text == "{" {
+                        break;
+                    }
+                    args.push(arg.text);
+                }
+                let mut body = Vec::new();
+                let mut depth = 0;
+                loop {
+                    let token = self.next()?;
+                    match token.text.as_str() {
+                        "{" => depth += 1,
+                        "}" if depth == 0 => break,
+                        "}" => depth -= 1,
+                        _ => {},
+                    }
+                    body.push(token);
+                }
+                self.macros.insert(name.text, Macro { args, body, calls: 0 });
+            },
+            ":stringmode" => return Err(self.error(&token, ":stringmode isn't supported")),
+
+            "return" | ";" => self.inst(0x00EE, &token)?,
+            "clear" => self.inst(0x00E0, &token)?,
+            "exit" => self.inst(0x00FD, &token)?,
+            "lores" => self.inst(0x00FE, &token)?,
+            "hires" => self.inst(0x00FF, &token)?,
+            "scroll-down" => {
+                let n = self.next()?;
+                let n = self.nibble(&n)?;
+                self.inst(0x00C0 | n, &token)?;
+            },
+            "scroll-up" => {
+                let n = self.next()?;
+                let n = self.nibble(&n)?;
+                self.inst(0x00D0 | n, &token)?;
+            },
+            "scroll-right" => self.inst(0x00FB, &token)?,
+            "scroll-left" => self.inst(0x00FC, &token)?,
+            "audio" => self.inst(0xF002, &token)?,
+            "plane" => {
+                let n = self.next()?;
+                let n = self.nibble(&n)?;
+                self.inst(0xF001 | n << 8, &token)?;
+            },
+            "pitch" => {
+                self.expect(":=")?;
+                let x = self.expect_register()? as u16;
+                self.inst(0xF03A | x << 8, &token)?;
+            },
+            "delay" | "buzzer" => {
+                self.expect(":=")?;
+                let x = self.expect_register()? as u16;
+                let op = if token.text == "delay" { 0xF015 } else { 0xF018 };
+                self.inst(op | x << 8, &token)?;
+            },
+            "bcd" | "saveflags" | "loadflags" => {
+                let x = self.expect_register()? as u16;
+                let op = match token.text.as_str() {
+                    "bcd" => 0xF033,
+                    "saveflags" => 0xF075,
+                    _ => 0xF085,
+                };
+                self.inst(op | x << 8, &token)?;
+            },
+            "save" | "load" => {
+                let x = self.expect_register()? as u16;
+                if self.peek() == Some("-") {
+                    self.next()?;
+                    let y = self.expect_register()? as u16;
+                    let op = if token.text == "save" { 0x5002 } else { 0x5003 };
+                    self.inst(op | x << 8 | y << 4, &token)?;
+                } else {
+                    let op = if token.text == "save" { 0xF055 } else { 0xF065 };
+                    self.inst(op | x << 8, &token)?;
+                }
+            },
+            "sprite" => {
+                let x = self.expect_register()? as u16;
+                let y = self.expect_register()? as u16;
+                let n = self.next()?;
+                let n = self.nibble(&n)?;
+                self.inst(0xD000 | x << 8 | y << 4 | n, &token)?;
+            },
+            "jump" | "jump0" | "native" => {
+                let target = self.next()?;
+                let here = self.here;
+                let nnn = self.address(&target, Fixup::Address, here)?;
+                let op = match token.text.as_str() {
+                    "jump" => 0x1000,
+                    "jump0" => 0xB000,
+                    _ => 0x0000,
+                };
+                self.inst(op | nnn, &token)?;
+            },
+            "i" => {
+                let op = self.next()?;
+                match op.text.as_str() {
+                    ":=" => {
+                        let value = self.next()?;
+                        match value.text.as_str() {
+                            "hex" | "bighex" => {
+                                let x = self.expect_register()? as u16;
+                                let op = if value.text == "hex" { 0xF029 } else { 0xF030 };
+                                self.inst(op | x << 8, &token)?;
+                            },
+                            "long" => {
+                                let target = self.next()?;
+                                let here = self.here;
+                                let nnnn = self.address(&target, Fixup::Word, here + 2)?;
+                                self.inst(0xF000, &token)?;
+                                self.inst(nnnn, &token)?;
+                            },
+                            _ => {
+                                let here = self.here;
+                                let nnn = self.address(&value, Fixup::Address, here)?;
+                                self.inst(0xA000 | nnn, &token)?;
+                            },
+                        }
+                    },
+                    "+=" => {
+                        let x = self.expect_register()? as u16;
+                        self.inst(0xF01E | x << 8, &token)?;
+                    },
+                    _ => return Err(self.error(&op, format!("i can't be used with {}", op.text))),
+                }
+            },
+            "loop" => {
+                self.loops.push((self.here, Vec::new(), token));
+            },
+            "while" => {
+                if self.loops.is_empty() {
+                    return Err(self.error(&token, "while outside of a loop"));
+                }
+                self.conditional(true)?;
+                let jump = self.placeholder_jump(&token)?;
+                self.loops.last_mut().unwrap().1.push(jump);
+            },
+            "again" => {
+                let (start, whiles, _) = match self.loops.pop() {
+                    Some(innermost) => innermost,
+                    None => return Err(self.error(&token, "again without loop")),
+                };
+                let opcode = self.jump(start, &token)?;
+                self.inst(opcode, &token)?;
+                for jump in whiles {
+                    let here = self.here;
+                    self.patch_jump(jump, here, &token)?;
+                }
+            },
+            "if" => {
+                let negated = {
+                    // then runs one instruction if the condition holds, so
+                    // skips it if it doesn't. begin jumps over the block
+                    // unless it holds, so skips the jump if it does.
+                    let block = self.tokens.iter()
+                        .position(|token| token.text == "then" || token.text == "begin")
+                        .map(|i| self.tokens[i].text == "begin");
+                    match block {
+                        Some(block) => block,
+                        None => return Err(self.error(&token, "if without then or begin")),
+                    }
+                };
+                self.conditional(negated)?;
+                let keyword = self.next()?;
+                if keyword.text != if negated { "begin" } else { "then" } {
+                    return Err(self.error(&keyword, format!("expected then or begin, found {}", keyword.text)));
+                }
+                if negated {
+                    let jump = self.placeholder_jump(&token)?;
+                    self.branches.push((jump, token));
+                }
+            },
+            "else" => {
+                let (jump, _) = match self.branches.pop() {
+                    Some(branch) => branch,
+                    None => return Err(self.error(&token, "else without begin")),
+                };
+                let end = self.placeholder_jump(&token)?;
+                let here = self.here;
+                self.patch_jump(jump, here, &token)?;
+                self.branches.push((end, token));
+            },
+            "end" => {
+                let (jump, _) = match self.branches.pop() {
+                    Some(branch) => branch,
+                    None => return Err(self.error(&token, "end without begin")),
+                };
+                let here = self.here;
+                self.patch_jump(jump, here, &token)?;
+            },
+            _ => {
+                if self.macros.contains_key(&token.text) {
+                    return self.expand(token);
+                }
+                if let Some(value) = self.constants.get(&token.text).copied() {
+                    if !(-128.0..=255.0).contains(&value) {
+                        return Err(self.error(&token, format!("{} doesn't fit in a byte", value)));
+                    }
+                    return self.emit(value as i64 as u8, &token);
+                }
+                if !is_name(&token.text) {
+                    return Err(self.error(&token, format!("unexpected {}", token.text)));
+                }
+                // Any other name calls the subroutine with that label.
+                let here = self.here;
+                let nnn = self.address(&token, Fixup::Address, here)?;
+                self.inst(0x2000 | nnn, &token)?;
+            },
+        }
+        Ok(())
+    }
+
+    fn assignment(&mut self, x: u8, token: Token) -> Result<(), AsmError> {
+        let x = x as u16;
+        let op = self.next()?;
+        let value = self.next()?;
+        let y = self.register(&value).map(|y| y as u16);
+        let opcode = match (op.text.as_str(), y) {
+            (":=", Some(y)) => 0x8000 | x << 8 | y << 4,
+            (":=", None) => match value.text.as_str() {
+                "key" => 0xF00A | x << 8,
+                "delay" => 0xF007 | x << 8,
+                "random" => {
+                    let mask = self.next()?;
+                    0xC000 | x << 8 | self.short(&mask)?
+                },
+                _ => 0x6000 | x << 8 | self.short(&value)?,
+            },
+            ("+=", Some(y)) => 0x8004 | x << 8 | y << 4,
+            ("+=", None) => 0x7000 | x << 8 | self.short(&value)?,
+            ("-=", Some(y)) => 0x8005 | x << 8 | y << 4,
+            ("-=", None) => 0x7000 | x << 8 | (self.short(&value)? as u8).wrapping_neg() as u16,
+            ("=-", Some(y)) => 0x8007 | x << 8 | y << 4,
+            ("|=", Some(y)) => 0x8001 | x << 8 | y << 4,
+            ("&=", Some(y)) => 0x8002 | x << 8 | y << 4,
+            ("^=", Some(y)) => 0x8003 | x << 8 | y << 4,
+            (">>=", Some(y)) => 0x8006 | x << 8 | y << 4,
+            ("<<=", Some(y)) => 0x800E | x << 8 | y << 4,
+            ("=-" | "|=" | "&=" | "^=" | ">>=" | "<<=", None) =>
+                return Err(self.error(&value, format!("{} needs a register, found {}", op.text, value.text))),
+            _ => return Err(self.error(&op, format!("unknown operator {}", op.text))),
+        };
+        self.inst(opcode, &token)
+    }
+
+    // Emits code that skips the next instruction when the condition is false,
+    // or when it's true if negated. The comparisons go through VF (or whatever
+    // compare-temp is an alias for).
+    fn conditional(&mut self, negated: bool) -> Result<(), AsmError> {
+        let x = self.expect_register()? as u16;
+        let op = self.next()?;
+        let mut comparison = op.text.clone();
+        if negated {
+            comparison = match comparison.as_str() {
+                "==" => "!=",
+                "!=" => "==",
+                "key" => "-key",
+                "-key" => "key",
+                "<" => ">=",
+                ">" => "<=",
+                ">=" => "<",
+                "<=" => ">",
+                other => return Err(self.error(&op, format!("unknown comparison {}", other))),
+            }.to_string();
+        }
+        if comparison == "key" {
+            return self.inst(0xE0A1 | x << 8, &op);
+        }
+        if comparison == "-key" {
+            return self.inst(0xE09E | x << 8, &op);
+        }
+
+        let value = self.next()?;
+        let operand = self.operand(&value)?;
+        let temp = self.aliases.get("compare-temp").copied().unwrap_or(0xF) as u16;
+        let load_temp = match operand {
+            Operand::Register(y) => 0x8000 | temp << 8 | (y as u16) << 4,
+            Operand::Value(n) => 0x6000 | temp << 8 | n as u16,
+        };
+        match comparison.as_str() {
+            "==" => match operand {
+                Operand::Register(y) => self.inst(0x9000 | x << 8 | (y as u16) << 4, &op),
+                Operand::Value(n) => self.inst(0x4000 | x << 8 | n as u16, &op),
+            },
+            "!=" => match operand {
+                Operand::Register(y) => self.inst(0x5000 | x << 8 | (y as u16) << 4, &op),
+                Operand::Value(n) => self.inst(0x3000 | x << 8 | n as u16, &op),
+            },
+            // temp -= x borrows (VF = 0) when x > temp
+            ">" | "<=" => {
+                self.inst(load_temp, &op)?;
+                self.inst(0x8005 | temp << 8 | x << 4, &op)?;
+                self.inst(if comparison == ">" { 0x3F01 } else { 0x4F01 }, &op)
+            },
+            // temp =- x borrows when temp > x
+            "<" | ">=" => {
+                self.inst(load_temp, &op)?;
+                self.inst(0x8007 | temp << 8 | x << 4, &op)?;
+                self.inst(if comparison == "<" { 0x3F01 } else { 0x4F01 }, &op)
+            },
+            other => Err(self.error(&op, format!("unknown comparison {}", other))),
+        }
+    }
+
+    fn expand(&mut self, name: Token) -> Result<(), AsmError> {
+        self.expansions += 1;
+        if self.expansions > MAX_EXPANSIONS {
+            return Err(self.error(&name, "too many macro expansions, is a macro calling itself?"));
+        }
+        let arg_count = self.macros[&name.text].args.len();
+        let mut args = HashMap::new();
+        for i in 0..arg_count {
+            let arg = self.next()?;
+            args.insert(self.macros[&name.text].args[i].clone(), arg);
+        }
+        let mac = self.macros.get_mut(&name.text).unwrap();
+        let calls = mac.calls;
+        mac.calls += 1;
+        for token in mac.body.iter().rev() {
+            let token = match args.get(&token.text) {
+                Some(arg) => arg.clone(),
+                None if token.text == "CALLS" => Token { text: calls.to_string(), ..token.clone() },
+                None => token.clone(),
+            };
+            self.tokens.push_front(token);
+        }
+        Ok(())
+    }
+
+    // A `:calc` style expression in braces. Octo has no operator precedence,
+    // everything groups to the right unless there are parentheses.
+    fn calc(&mut self) -> Result<f64, AsmError> {
+        let open = self.expect("{")?;
+        let mut tokens = Vec::new();
+        loop {
+            let token = self.next()?;
+            if token.text == "}" {
+                break;
+            }
+            tokens.push(token);
+        }
+        if tokens.is_empty() {
+            return Err(self.error(&open, "empty expression"));
+        }
+        let mut pos = 0;
+        let value = self.calc_binary(&tokens, &mut pos)?;
+        if let Some(token) = tokens.get(pos) {
+            return Err(self.error(token, format!("unexpected {}", token.text)));
+        }
+        Ok(value)
+    }
+
+    fn calc_binary(&self, tokens: &[Token], pos: &mut usize) -> Result<f64, AsmError> {
+        let left = self.calc_term(tokens, pos)?;
+        let op = match tokens.get(*pos) {
+            Some(token) if token.text != ")" => token,
+            _ => return Ok(left),
+        };
+        *pos += 1;
+        let right = self.calc_binary(tokens, pos)?;
+        let int = |f: fn(i64, i64) -> i64| f(left as i64, right as i64) as f64;
+        let bool = |b: bool| if b { 1.0 } else { 0.0 };
+        Ok(match op.text.as_str() {
+            "+" => left + right,
+            "-" => left - right,
+            "*" => left * right,
+            "/" => left / right,
+            "%" => left % right,
+            "pow" => left.powf(right),
+            "min" => left.min(right),
+            "max" => left.max(right),
+            "&" => int(|a, b| a & b),
+            "|" => int(|a, b| a | b),
+            "^" => int(|a, b| a ^ b),
+            "<<" => int(|a, b| a.wrapping_shl(b as u32)),
+            ">>" => int(|a, b| a.wrapping_shr(b as u32)),
+            "<" => bool(left < right),
+            "<=" => bool(left <= right),
+            ">" => bool(left > right),
+            ">=" => bool(left >= right),
+            "==" => bool(left == right),
+            "!=" => bool(left != right),
+            _ => return Err(self.error(op, format!("unknown operator {}", op.text))),
+        })
+    }
+
+    fn calc_term(&self, tokens: &[Token], pos: &mut usize) -> Result<f64, AsmError> {
+        let token = match tokens.get(*pos) {
+            Some(token) => token,
+            None => {
+                let last = tokens.last().unwrap_or(&self.last);
+                return Err(self.error(last, "expression ends too soon"));
+            },
+        };
+        *pos += 1;
+        let unary: Option<fn(f64) -> f64> = match token.text.as_str() {
+            "-" => Some(|a| -a),
+            "~" => Some(|a| !(a as i64) as f64),
+            "!" => Some(|a| if a == 0.0 { 1.0 } else { 0.0 }),
+            "sin" => Some(f64::sin),
+            "cos" => Some(f64::cos),
+            "tan" => Some(f64::tan),
+            "exp" => Some(f64::exp),
+            "log" => Some(f64::ln),
+            "abs" => Some(f64::abs),
+            "sqrt" => Some(f64::sqrt),
+            "sign" => Some(|a| if a == 0.0 { 0.0 } else { a.signum() }),
+            "ceil" => Some(f64::ceil),
+            "floor" => Some(f64::floor),
+            _ => None,
+        };
+        if let Some(f) = unary {
+            return Ok(f(self.calc_term(tokens, pos)?));
+        }
+        match token.text.as_str() {
+            "@" => {
+                let address = self.calc_term(tokens, pos)? as i64 - ORIGIN as i64;
+                Ok(usize::try_from(address).ok().and_then(|i| self.rom.get(i)).copied().unwrap_or(0) as f64)
+            },
+            "(" => {
+                let value = self.calc_binary(tokens, pos)?;
+                match tokens.get(*pos) {
+                    Some(close) if close.text == ")" => {
+                        *pos += 1;
+                        Ok(value)
+                    },
+                    _ => Err(self.error(token, "unclosed (")),
+                }
+            },
+            "HERE" => Ok(self.here as f64),
+            "PI" => Ok(consts::PI),
+            "E" => Ok(consts::E),
+            _ => self.known_value(token).ok_or_else(|| self.error(token, format!("{} isn't defined", token.text))),
+        }
+    }
+}
+
+fn number(text: &str) -> Option<f64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        i64::from_str_radix(hex, 16).ok()? as f64
+    } else if let Some(bin) = digits.strip_prefix("0b").or_else(|| digits.strip_prefix("0B")) {
+        i64::from_str_radix(bin, 2).ok()? as f64
+    } else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+    Some(if negative { -value } else { value })
+}
+
+fn is_name(text: &str) -> bool {
+    text.starts_with(|c: char| c.is_alphabetic() || c == '_')
+        && text.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+}
+
+// Tokens are separated by whitespace. Strings are kept whole, quotes included.
+fn tokenize(source: &str) -> Result<VecDeque<Token>, (usize, usize, String)> {
+    let mut tokens = VecDeque::new();
+    for (i, line) in source.lines().enumerate() {
+        let chars: Vec<char> = line.chars().collect();
+        let mut pos = 0;
+        while pos < chars.len() {
+            if chars[pos].is_whitespace() {
+                pos += 1;
+                continue;
+            }
+            if chars[pos] == '#' {
+                break;
+            }
+            let start = pos;
+            if chars[pos] == '"' {
+                pos += 1;
+                while pos < chars.len() && chars[pos] != '"' {
+                    pos += 1;
+                }
+                if pos == chars.len() {
+                    return Err((i + 1, start + 1, "unterminated string".to_string()));
+                }
+                pos += 1;
+            } else {
+                while pos < chars.len() && !chars[pos].is_whitespace() {
+                    pos += 1;
+                }
+            }
+            tokens.push_back(Token {
+                text: chars[start..pos].iter().collect(),
+                line: i + 1,
+                column: start + 1,
+            });
+        }
+    }
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(source: &str) -> AsmError {
+        compile(source).expect_err("should not compile")
+    }
+
+    #[test]
+    fn jumps_to_main_unless_it_comes_first() {
+        assert_eq!(compile(": main clear").unwrap(), [0x00, 0xE0]);
+        assert_eq!(compile(": data 1 2\n: main clear").unwrap(), [0x12, 0x04, 0x01, 0x02, 0x00, 0xE0]);
+    }
+
+    #[test]
+    fn labels_and_calls() {
+        // A name on its own calls the label, used before or after it's defined.
+        let rom = compile(": main sub jump main\n: sub v0 := 1 ;").unwrap();
+        assert_eq!(rom, [0x22, 0x04, 0x12, 0x00, 0x60, 0x01, 0x00, 0xEE]);
+    }
+
+    #[test]
+    fn if_then_and_if_else() {
+        // then skips the one instruction unless the condition holds.
+        assert_eq!(compile(": main if v0 == 5 then v1 := 2").unwrap(), [0x40, 0x05, 0x61, 0x02]);
+
+        let rom = compile(": main if v0 == 5 begin v1 := 1 else v1 := 2 end").unwrap();
+        assert_eq!(rom, [0x30, 0x05, 0x12, 0x08, 0x61, 0x01, 0x12, 0x0A, 0x61, 0x02]);
+
+        let err = error(": main if v0 == 5 begin v1 := 1");
+        assert_eq!((err.line, err.column, err.message.as_str()), (1, 8, "begin without end"));
+    }
+
+    #[test]
+    fn loop_while_again() {
+        let rom = compile(": main loop v0 += 1 while v0 != 10 again").unwrap();
+        assert_eq!(rom, [0x70, 0x01, 0x40, 0x0A, 0x12, 0x08, 0x12, 0x00]);
+
+        let err = error(": main\n  loop\n    v0 += 1\n");
+        assert_eq!((err.line, err.column, err.message.as_str()), (2, 3, "loop without again"));
+
+        // Nothing past 0xFFF can be jumped to.
+        let err = error(": main jump0 0\n:org 0x1000\n  loop again");
+        assert_eq!((err.line, err.column), (3, 8));
+        assert!(err.message.contains("out of reach"), "{}", err.message);
+    }
+
+    #[test]
+    fn unpack_only_reaches_4k_without_long() {
+        let err = error(": main :unpack 0xA far\n:org 0x1000 : far");
+        assert_eq!((err.line, err.column), (1, 20));
+        assert!(err.message.contains("out of reach"), "{}", err.message);
+
+        let rom = compile(": main :unpack long far\n:org 0x1000 : far").unwrap();
+        assert_eq!(rom[..4], [0x60, 0x10, 0x61, 0x00]);
+    }
+
+    #[test]
+    fn macros_and_calc() {
+        let source = "
+            :macro add-twice R N { R += N R += N }
+            :calc SIZE { 2 * 3 + 1 }
+            :calc SMALL { ( 2 * 3 ) + 1 }
+            : main
+                add-twice v3 SIZE
+                v0 := SMALL
+        ";
+        // No precedence in :calc, it goes right to left like Octo.
+        assert_eq!(compile(source).unwrap(), [0x73, 0x08, 0x73, 0x08, 0x60, 0x07]);
+
+        let err = error(":macro forever { forever }\n: main forever");
+        assert_eq!(err.line, 1);
+    }
+
+    #[test]
+    fn errors_point_at_the_problem() {
+        let err = error(": main\n  v0 := nowhere\n");
+        assert_eq!((err.file.as_str(), err.line, err.column), ("<source>", 2, 9));
+
+        let err = error(": main\n  jump later\n");
+        assert_eq!((err.line, err.column), (2, 8));
+        assert!(err.message.contains("later"), "{}", err.message);
+
+        let err = error(": main v0 := 256");
+        assert_eq!((err.line, err.column), (1, 14));
+    }
+}
@@ -0,0 +1,79 @@
+// Behaviours that changed between CHIP-8 interpreters over the years. ROMs are
+// usually written against one of them, so pick the preset matching the ROM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quirks {
+    // 8XY6/8XYE shift VY into VX. Otherwise VX is shifted in place.
+    pub shift_uses_vy: bool,
+    // BXNN jumps to XNN + VX. Otherwise BNNN jumps to NNN + V0.
+    pub jump_uses_vx: bool,
+    // FX55/FX65 leave I pointing past the last register stored/loaded.
+    pub load_store_increments_i: bool,
+    // 8XY1/8XY2/8XY3 set VF to 0.
+    pub vf_reset: bool,
+    // Sprites are cut off at the screen edges. Otherwise they wrap around.
+    pub clip_sprites: bool,
+    // DXYN waits for the next 60hz tick before drawing.
+    pub display_wait: bool,
+}
+
+impl Quirks {
+    // The original interpreter on the RCA COSMAC VIP.
+    pub const fn cosmac_vip() -> Quirks {
+        Quirks {
+            shift_uses_vy: true,
+            jump_uses_vx: false,
+            load_store_increments_i: true,
+            vf_reset: true,
+            clip_sprites: true,
+            display_wait: true,
+        }
+    }
+
+    // CHIP-48 on the HP-48 calculators.
+    pub const fn chip48() -> Quirks {
+        Quirks {
+            shift_uses_vy: false,
+            jump_uses_vx: true,
+            load_store_increments_i: false,
+            vf_reset: false,
+            clip_sprites: true,
+            display_wait: false,
+        }
+    }
+
+    // SUPER-CHIP 1.1, also on the HP-48. It inherits CHIP-48's behaviour,
+    // what sets it apart is the extended instruction set.
+    pub const fn superchip() -> Quirks {
+        Quirks::chip48()
+    }
+
+    // XO-CHIP as Octo runs it: the VIP's shifts and FX55/FX65, but sprites
+    // wrap around and nothing waits for the display.
+    pub const fn xochip() -> Quirks {
+        Quirks {
+            shift_uses_vy: true,
+            jump_uses_vx: false,
+            load_store_increments_i: true,
+            vf_reset: false,
+            clip_sprites: false,
+            display_wait: false,
+        }
+    }
+
+    // Looks up a preset by the name used on the command line.
+    pub fn from_name(name: &str) -> Option<Quirks> {
+        match name {
+            "vip" | "chip8" => Some(Quirks::cosmac_vip()),
+            "chip48" => Some(Quirks::chip48()),
+            "schip" | "superchip" => Some(Quirks::superchip()),
+            "xochip" | "xo-chip" => Some(Quirks::xochip()),
+            _ => None,
+        }
+    }
+}
+
+impl Default for Quirks {
+    fn default() -> Quirks {
+        Quirks::cosmac_vip()
+    }
+}
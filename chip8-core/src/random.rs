@@ -0,0 +1,88 @@
+// Where CXNN's random numbers come from. Both generators are fully determined
+// by their seed, so a run can be replayed exactly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RandomMode {
+    // xorshift64*, good quality and what most programs should get.
+    #[default]
+    Xorshift,
+    // Modeled on the COSMAC VIP interpreter: a pointer into the interpreter's
+    // own code (its second page, 0x100-0x1FF) moves along every 60hz tick,
+    // and each random number is the byte it points at added to the previous
+    // random number.
+    CosmacVip,
+}
+
+impl RandomMode {
+    pub fn from_name(name: &str) -> Option<RandomMode> {
+        match name {
+            "xorshift" => Some(RandomMode::Xorshift),
+            "vip" => Some(RandomMode::CosmacVip),
+            _ => None,
+        }
+    }
+}
+
+// Spreads the seed out so that small seeds (0, 1, 2...) still give unrelated
+// sequences, and so xorshift never starts from the all zero state it can't leave.
+pub(crate) fn seed_state(seed: u64) -> u64 {
+    let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^= z >> 31;
+    if z == 0 { 1 } else { z }
+}
+
+pub(crate) fn xorshift(state: &mut u64) -> u8 {
+    *state ^= *state >> 12;
+    *state ^= *state << 25;
+    *state ^= *state >> 27;
+    (state.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 56) as u8
+}
+
+// The VIP's state is the page pointer in the second byte and the last random
+// number in the first.
+pub(crate) fn vip(state: &mut u64) -> u8 {
+    let pointer = (*state >> 8) as u8;
+    let n = (*state as u8).wrapping_add(VIP_PAGE[pointer as usize]);
+    *state = (pointer as u64) << 8 | n as u64;
+    n
+}
+
+// The second page of the VIP's CHIP-8 interpreter, which its random routine
+// reads from. The emulated memory has fonts and zeros there instead.
+const VIP_PAGE: [u8; 256] = [
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x45, 0xA3, 0x98, 0x56, 0xD4, 0xF8, 0x81, 0xBC, 0xF8, 0x95,
+    0xAC, 0x22, 0xDC, 0x12, 0x56, 0xD4, 0x06, 0xB8, 0xD4, 0x06, 0xA8, 0xD4, 0x64, 0x0A, 0x01, 0xE6,
+    0x8A, 0xF4, 0xAA, 0x3B, 0x28, 0x9A, 0xFC, 0x01, 0xBA, 0xD4, 0xF8, 0x81, 0xBA, 0x06, 0xFA, 0x0F,
+    0xAA, 0x0A, 0xAA, 0xD4, 0xE6, 0x06, 0xBF, 0x93, 0xBE, 0xF8, 0x1B, 0xAE, 0x2A, 0x1A, 0xF8, 0x00,
+    0x5A, 0x0E, 0xF5, 0x3B, 0x4B, 0x56, 0x0A, 0xFC, 0x01, 0x5A, 0x30, 0x40, 0x4E, 0xF6, 0x3B, 0x3C,
+    0x9F, 0x56, 0x2A, 0x2A, 0xD4, 0x00, 0x22, 0x86, 0x52, 0xF8, 0xF0, 0xA7, 0x07, 0x5A, 0x87, 0xF3,
+    0x17, 0x1A, 0x3A, 0x5B, 0x12, 0xD4, 0x22, 0x86, 0x52, 0xF8, 0xF0, 0xA7, 0x0A, 0x57, 0x87, 0xF3,
+    0x17, 0x1A, 0x3A, 0x6B, 0x12, 0xD4, 0x15, 0x85, 0x22, 0x73, 0x95, 0x52, 0x25, 0x45, 0xA5, 0x86,
+    0xFA, 0x0F, 0xB5, 0xD4, 0x45, 0xE6, 0xF3, 0x3A, 0x82, 0x15, 0x15, 0xD4, 0x45, 0xE6, 0xF3, 0x3A,
+    0x88, 0xD4, 0x45, 0x07, 0x30, 0x8C, 0x45, 0x07, 0x30, 0x84, 0xE6, 0x62, 0x26, 0x45, 0xA3, 0x36,
+    0x88, 0xD4, 0x3E, 0x88, 0xD4, 0xF8, 0xF0, 0xA7, 0xE7, 0x45, 0xF4, 0xA5, 0x86, 0xFA, 0x0F, 0x3B,
+    0xB2, 0xFC, 0x01, 0xB5, 0xD4, 0x45, 0x56, 0xD4, 0x45, 0xE6, 0xF4, 0x56, 0xD4, 0x45, 0xFA, 0x0F,
+    0x3A, 0xC4, 0x07, 0x56, 0xD4, 0xAF, 0x22, 0xF8, 0xD3, 0x73, 0x8F, 0xF9, 0xF0, 0x52, 0xE6, 0x07,
+    0xD2, 0x56, 0xF8, 0xFF, 0xA6, 0xF8, 0x00, 0x7E, 0x56, 0xD4, 0x19, 0x89, 0xAE, 0x93, 0xBE, 0x99,
+    0xEE, 0xF4, 0x56, 0x76, 0xE6, 0xF4, 0xB9, 0x56, 0x45, 0xF2, 0x56, 0xD4, 0x45, 0xAA, 0x86, 0xFA,
+    0x0F, 0xBA, 0xD4, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xE0,
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vip_adds_the_page_byte_to_the_last_number() {
+        // Pointing at 0x180 (0xFA) with 5 as the last number.
+        let mut state = 0x8005;
+        let numbers: Vec<u8> = (0..4).map(|_| vip(&mut state)).collect();
+        assert_eq!(numbers, [0xFF, 0xF9, 0xF3, 0xED]);
+        assert_eq!(state, 0x80ED);
+
+        // A tick later the pointer is at 0x181 (0x0F).
+        state += 1 << 8;
+        assert_eq!(vip(&mut state), 0xFC);
+    }
+}
@@ -0,0 +1,114 @@
+use std::collections::VecDeque;
+
+use crate::host::Host;
+use crate::vm::Env;
+
+// Snapshots are compared in chunks this big. Smaller finds tighter diffs,
+// larger is quicker to compare.
+const CHUNK: usize = 32;
+
+// Steps a game backwards a frame at a time. Call `record` once per frame
+// while playing and `rewind` once per frame while going back.
+//
+// Only the latest save state is kept whole. Every frame before it is stored
+// as the bytes that differ from the frame after it, which for most games is
+// a handful of registers, a few bytes of RAM and a couple of display rows.
+pub struct Rewind {
+    current: Vec<u8>, // the last recorded (or rewound to) state
+    deltas: VecDeque<Delta>, // oldest first
+    max_frames: usize,
+    max_bytes: usize,
+    bytes_used: usize,
+}
+
+// The older frame's bytes at each spot where it differs from the newer one.
+struct Delta {
+    runs: Vec<(usize, usize)>, // (offset into the state, length)
+    data: Vec<u8>,
+}
+
+impl Delta {
+    fn size(&self) -> usize {
+        self.data.len() + self.runs.len() * std::mem::size_of::<(usize, usize)>()
+    }
+}
+
+impl Rewind {
+    // Keeps at most `max_frames` frames, using no more than roughly `max_bytes`
+    // for them. Whichever limit is hit first drops the oldest frames.
+    pub fn new(max_frames: usize, max_bytes: usize) -> Rewind {
+        Rewind {
+            current: Vec::new(),
+            deltas: VecDeque::new(),
+            max_frames,
+            max_bytes,
+            bytes_used: 0,
+        }
+    }
+
+    // How many frames can be stepped back.
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.current.clear();
+        self.deltas.clear();
+        self.bytes_used = 0;
+    }
+
+    pub fn record<H: Host>(&mut self, env: &Env<H>) {
+        let state = env.save_state();
+        if self.current.len() != state.len() {
+            self.clear();
+            self.current = state;
+            return;
+        }
+
+        let mut delta = Delta { runs: Vec::new(), data: Vec::new() };
+        let mut offset = 0;
+        for (old, new) in self.current.chunks(CHUNK).zip(state.chunks(CHUNK)) {
+            if old != new {
+                match delta.runs.last_mut() {
+                    // Merge with the previous run when the chunks are next to each other.
+                    Some((start, len)) if *start + *len == offset => *len += old.len(),
+                    _ => delta.runs.push((offset, old.len())),
+                }
+                delta.data.extend_from_slice(old);
+            }
+            offset += old.len();
+        }
+        self.current = state;
+
+        self.bytes_used += delta.size();
+        self.deltas.push_back(delta);
+        while self.deltas.len() > self.max_frames || self.bytes_used > self.max_bytes {
+            match self.deltas.pop_front() {
+                Some(oldest) => self.bytes_used -= oldest.size(),
+                None => break,
+            }
+        }
+    }
+
+    // Puts the machine back one frame. Returns false if there's nothing left
+    // to rewind to.
+    pub fn rewind<H: Host>(&mut self, env: &mut Env<H>) -> bool {
+        let delta = match self.deltas.pop_back() {
+            Some(delta) => delta,
+            None => return false,
+        };
+        self.bytes_used -= delta.size();
+
+        let mut data = &delta.data[..];
+        for &(offset, len) in delta.runs.iter() {
+            self.current[offset..offset + len].copy_from_slice(&data[..len]);
+            data = &data[len..];
+        }
+        // These states came from save_state, so they always load.
+        env.load_state(&self.current).is_ok()
+    }
+}
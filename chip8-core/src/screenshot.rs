@@ -0,0 +1,390 @@
+// Pictures of the display, as PNG or the plain (text) netpbm formats: PBM
+// for black and white, which is easy to diff and check in, and PPM for
+// color. Scaling repeats each pixel into a square.
+//
+// There's no image library here, so PNGs are compressed with a small
+// deflate of our own. It only looks for runs of the same color and for the
+// row above, which is most of what a screen of big square pixels is made of.
+
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::Path;
+
+use crate::checksum::crc32;
+use crate::host::Host;
+use crate::vm::Env;
+
+// RGB colors indexed by a pixel's value: neither plane, plane 0, plane 1, both.
+pub type Palette = [[u8; 3]; 4];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Png,
+    Pbm, // lit pixels are 1, which PBM shows as black
+    Ppm,
+}
+
+impl Format {
+    // By the file's extension.
+    pub fn from_path(path: &Path) -> Option<Format> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "png" => Some(Format::Png),
+            "pbm" => Some(Format::Pbm),
+            "ppm" => Some(Format::Ppm),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Screenshot {
+    width: usize,
+    height: usize,
+    pixels: Vec<u8>, // by row, each 0-3 like Env::pixel
+}
+
+impl Screenshot {
+    // At the current resolution, 64x32 or 128x64.
+    pub fn capture<H: Host>(env: &Env<H>) -> Screenshot {
+        let (width, height) = env.resolution();
+        let mut pixels = Vec::with_capacity(width * height);
+        for y in 0..height {
+            pixels.extend((0..width).map(|x| env.pixel(x, y)));
+        }
+        Screenshot { width, height, pixels }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> u8 {
+        self.pixels[y * self.width + x]
+    }
+
+    // Every pixel's value after scaling, by row, for encoding some other way.
+    pub fn scaled(&self, scale: usize) -> Vec<u8> {
+        self.rows(scale.max(1)).flatten().collect()
+    }
+
+    pub fn encode(&self, format: Format, palette: &Palette, scale: usize) -> Vec<u8> {
+        let scale = scale.max(1);
+        match format {
+            Format::Png => self.png(palette, scale),
+            Format::Pbm => self.netpbm("P1", None, scale, |value| vec![(value != 0) as u8]),
+            Format::Ppm => self.netpbm("P3", Some(255), scale, |value| palette[value as usize].to_vec()),
+        }
+    }
+
+    // Picks the format from the extension.
+    pub fn save(&self, path: &Path, palette: &Palette, scale: usize) -> io::Result<()> {
+        let format = Format::from_path(path).ok_or_else(|| {
+            io::Error::new(ErrorKind::InvalidInput, "screenshots can be .png, .pbm or .ppm")
+        })?;
+        fs::write(path, self.encode(format, palette, scale))
+    }
+
+    // The pixels scaled up, a row at a time.
+    fn rows(&self, scale: usize) -> impl Iterator<Item = Vec<u8>> + '_ {
+        (0..self.height * scale).map(move |y| {
+            let row = &self.pixels[y / scale * self.width..][..self.width];
+            row.iter().flat_map(|&value| std::iter::repeat_n(value, scale)).collect()
+        })
+    }
+
+    // A row of the image to a line, each pixel as one or more samples.
+    fn netpbm(&self, magic: &str, max: Option<u8>, scale: usize, samples: impl Fn(u8) -> Vec<u8>) -> Vec<u8> {
+        let mut out = format!("{}\n{} {}\n", magic, self.width * scale, self.height * scale);
+        if let Some(max) = max {
+            out += &format!("{}\n", max);
+        }
+        for row in self.rows(scale) {
+            let values: Vec<String> = row.iter()
+                .flat_map(|&value| samples(value))
+                .map(|sample| sample.to_string())
+                .collect();
+            out += &values.join(" ");
+            out += "\n";
+        }
+        out.into_bytes()
+    }
+
+    // An 8-bit paletted PNG.
+    fn png(&self, palette: &Palette, scale: usize) -> Vec<u8> {
+        let (width, height) = (self.width * scale, self.height * scale);
+        let mut image = Vec::with_capacity((width + 1) * height);
+        for row in self.rows(scale) {
+            image.push(0); // no filter
+            image.extend_from_slice(&row);
+        }
+
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        let mut header = Vec::new();
+        header.extend_from_slice(&(width as u32).to_be_bytes());
+        header.extend_from_slice(&(height as u32).to_be_bytes());
+        header.extend_from_slice(&[8, 3, 0, 0, 0]); // depth, paletted, deflate, no filter, not interlaced
+        chunk(&mut png, b"IHDR", &header);
+        chunk(&mut png, b"PLTE", palette.concat().as_slice());
+        chunk(&mut png, b"IDAT", &zlib(&image, width + 1));
+        chunk(&mut png, b"IEND", &[]);
+        png
+    }
+}
+
+fn chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn adler32(bytes: &[u8]) -> u32 {
+    let (a, b) = bytes.iter().fold((1u32, 0u32), |(a, b), &byte| {
+        let a = (a + byte as u32) % 65521;
+        (a, (b + a) % 65521)
+    });
+    b << 16 | a
+}
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+    35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193,
+    257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13,
+];
+const MAX_DISTANCE: usize = 32768;
+
+// Deflate's bits go in from the least significant end of each byte.
+struct Bits {
+    out: Vec<u8>,
+    buffer: u32,
+    count: u32,
+}
+
+impl Bits {
+    fn put(&mut self, value: u32, count: u32) {
+        self.buffer |= value << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.out.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    // Huffman codes go most significant bit first.
+    fn put_code(&mut self, code: u32, count: u32) {
+        self.put(code.reverse_bits() >> (32 - count), count);
+    }
+
+    // A symbol from the fixed literal/length code.
+    fn symbol(&mut self, symbol: u16) {
+        let symbol = symbol as u32;
+        match symbol {
+            0..=143 => self.put_code(0x30 + symbol, 8),
+            144..=255 => self.put_code(0x190 + symbol - 144, 9),
+            256..=279 => self.put_code(symbol - 256, 7),
+            _ => self.put_code(0xC0 + symbol - 280, 8),
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.out.push(self.buffer as u8);
+        }
+        self.out
+    }
+}
+
+// One block with the fixed Huffman codes, trying matches one byte back and
+// one row back.
+fn zlib(data: &[u8], stride: usize) -> Vec<u8> {
+    let mut bits = Bits { out: vec![0x78, 0x01], buffer: 0, count: 0 };
+    bits.put(1, 1); // the last block
+    bits.put(1, 2); // fixed codes
+    let mut i = 0;
+    while i < data.len() {
+        let (length, distance) = [1, stride].iter()
+            .filter(|&&distance| distance <= i && distance <= MAX_DISTANCE)
+            .map(|&distance| {
+                let length = (0..258.min(data.len() - i))
+                    .take_while(|&k| data[i + k] == data[i + k - distance])
+                    .count();
+                (length, distance)
+            })
+            .max()
+            .unwrap_or((0, 0));
+        if length < 3 {
+            bits.symbol(data[i] as u16);
+            i += 1;
+            continue;
+        }
+        let code = LENGTH_BASE.iter().rposition(|&base| base as usize <= length).unwrap_or(0);
+        bits.symbol(257 + code as u16);
+        bits.put((length - LENGTH_BASE[code] as usize) as u32, LENGTH_EXTRA[code] as u32);
+        let code = DISTANCE_BASE.iter().rposition(|&base| base as usize <= distance).unwrap_or(0);
+        bits.put_code(code as u32, 5);
+        bits.put((distance - DISTANCE_BASE[code] as usize) as u32, DISTANCE_EXTRA[code] as u32);
+        i += length;
+    }
+    bits.symbol(256); // end of block
+    let mut out = bits.finish();
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PALETTE: Palette = [[0, 0, 0], [255, 255, 255], [255, 0, 0], [0, 0, 255]];
+
+    #[test]
+    fn adler32_check_values() {
+        assert_eq!(adler32(b""), 1);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+        // Long enough for both sums to wrap around the modulus.
+        assert_eq!(adler32(&[0xFF; 6000]), 0xA497_59EA);
+    }
+
+    // Reads deflate's bits back in the order Bits puts them.
+    struct BitReader<'a> {
+        bytes: &'a [u8],
+        pos: usize, // in bits
+    }
+
+    impl BitReader<'_> {
+        fn bit(&mut self) -> u32 {
+            let bit = self.bytes[self.pos / 8] >> (self.pos % 8) & 1;
+            self.pos += 1;
+            bit as u32
+        }
+
+        fn bits(&mut self, count: u8) -> usize {
+            (0..count).fold(0, |value, i| value | (self.bit() as usize) << i)
+        }
+
+        fn code(&mut self, count: u8) -> u32 {
+            (0..count).fold(0, |code, _| code << 1 | self.bit())
+        }
+    }
+
+    // Just enough inflate for what zlib writes: one block of fixed codes.
+    fn inflate(zlib: &[u8]) -> Vec<u8> {
+        assert_eq!(zlib[..2], [0x78, 0x01]);
+        assert_eq!(u16::from_be_bytes([zlib[0], zlib[1]]) % 31, 0);
+        let mut bits = BitReader { bytes: &zlib[2..], pos: 0 };
+        assert_eq!(bits.bits(3), 0b011, "one last block with fixed codes");
+        let mut out = Vec::new();
+        loop {
+            let mut code = bits.code(7);
+            let symbol = if code < 0x18 {
+                256 + code
+            } else {
+                code = code << 1 | bits.bit();
+                match code {
+                    0x30..=0xBF => code - 0x30,
+                    0xC0..=0xC7 => 280 + code - 0xC0,
+                    _ => 144 + (code << 1 | bits.bit()) - 0x190,
+                }
+            } as usize;
+            match symbol {
+                0..=255 => out.push(symbol as u8),
+                256 => break,
+                _ => {
+                    let code = symbol - 257;
+                    let length = LENGTH_BASE[code] as usize + bits.bits(LENGTH_EXTRA[code]);
+                    let code = bits.code(5) as usize;
+                    let distance = DISTANCE_BASE[code] as usize + bits.bits(DISTANCE_EXTRA[code]);
+                    assert!(distance <= out.len());
+                    for _ in 0..length {
+                        out.push(out[out.len() - distance]);
+                    }
+                },
+            }
+        }
+        let end = 2 + bits.pos.div_ceil(8);
+        assert_eq!(zlib[end..], adler32(&out).to_be_bytes());
+        out
+    }
+
+    // Checks each chunk's CRC and returns the header, palette and pixels.
+    fn decode_png(png: &[u8]) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+        assert_eq!(png[..8], *b"\x89PNG\r\n\x1a\n");
+        let mut chunks = Vec::new();
+        let mut rest = &png[8..];
+        while !rest.is_empty() {
+            let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+            let (kind, data) = (&rest[4..8], &rest[8..8 + len]);
+            let crc = u32::from_be_bytes(rest[8 + len..12 + len].try_into().unwrap());
+            assert_eq!(crc, crc32(&rest[4..8 + len]));
+            chunks.push((kind.to_vec(), data.to_vec()));
+            rest = &rest[12 + len..];
+        }
+        let kinds: Vec<&[u8]> = chunks.iter().map(|(kind, _)| &kind[..]).collect();
+        assert_eq!(kinds, [b"IHDR", b"PLTE", b"IDAT", b"IEND"]);
+        (chunks[0].1.clone(), chunks[1].1.clone(), inflate(&chunks[2].1))
+    }
+
+    fn check_png(screenshot: &Screenshot, scale: usize) {
+        let (header, palette, image) = decode_png(&screenshot.encode(Format::Png, &PALETTE, scale));
+        let (width, height) = (screenshot.width * scale, screenshot.height * scale);
+        assert_eq!(header[..4], (width as u32).to_be_bytes());
+        assert_eq!(header[4..8], (height as u32).to_be_bytes());
+        assert_eq!(header[8..], [8, 3, 0, 0, 0]);
+        assert_eq!(palette, PALETTE.concat());
+        let pixels: Vec<u8> = image.chunks(width + 1)
+            .flat_map(|row| {
+                assert_eq!(row[0], 0);
+                row[1..].to_vec()
+            })
+            .collect();
+        assert_eq!(pixels, screenshot.scaled(scale));
+    }
+
+    #[test]
+    fn png_decodes_to_the_pixels() {
+        // Mostly blank, for long runs and matches a row up.
+        let mut pixels = vec![0; 128 * 64];
+        for x in 10..50 {
+            pixels[20 * 128 + x] = 1;
+            pixels[21 * 128 + x] = 3;
+        }
+        let blank = Screenshot { width: 128, height: 64, pixels };
+        check_png(&blank, 1);
+        check_png(&blank, 5);
+
+        // Noise, which is mostly literals.
+        let mut seed = 1u32;
+        let pixels = (0..64 * 32).map(|_| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (seed >> 16) as u8 & 3
+        }).collect();
+        let noise = Screenshot { width: 64, height: 32, pixels };
+        check_png(&noise, 1);
+        check_png(&noise, 3);
+    }
+
+    #[test]
+    fn netpbm() {
+        let screenshot = Screenshot { width: 2, height: 1, pixels: vec![0, 3] };
+        assert_eq!(screenshot.encode(Format::Pbm, &PALETTE, 1), b"P1\n2 1\n0 1\n");
+        assert_eq!(screenshot.encode(Format::Pbm, &PALETTE, 2), b"P1\n4 2\n0 0 1 1\n0 0 1 1\n");
+        assert_eq!(screenshot.encode(Format::Ppm, &PALETTE, 1), b"P3\n2 1\n255\n0 0 0 0 0 255\n");
+    }
+}
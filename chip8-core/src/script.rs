@@ -0,0 +1,177 @@
+// Key presses scripted ahead of time, for running a ROM with nobody at the
+// keyboard. A script has a line per press or release, timed in frames:
+//
+//   # frame  what     key
+//   60       press    5
+//   64       release  5
+//   120      press    a
+//
+// Frames count from 0, and whatever is due happens before the first
+// instruction of that frame. Keys are hex digits, `#` starts a comment.
+
+use std::error::Error;
+use std::fmt;
+
+use crate::keypad::Keypad;
+use crate::vm::Env;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub frame: u64,
+    pub key: u8,
+    pub pressed: bool,
+}
+
+// Written the same way as a line of a script.
+impl fmt::Display for KeyEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let action = if self.pressed { "press" } else { "release" };
+        write!(f, "{} {} {:x}", self.frame, action, self.key)
+    }
+}
+
+// A line of a script, split into words.
+pub(crate) fn parse_event(words: &[&str]) -> Result<KeyEvent, String> {
+    let [frame, action, key] = words[..] else {
+        return Err("expected a frame, press or release, and a key".to_string());
+    };
+    let frame = frame.parse().map_err(|_| format!("{} isn't a frame number", frame))?;
+    let pressed = match action {
+        "press" => true,
+        "release" => false,
+        _ => return Err(format!("{} should be press or release", action)),
+    };
+    let key = match u8::from_str_radix(key, 16) {
+        Ok(key) if key < 16 => key,
+        _ => return Err(format!("{} isn't a key, they go from 0 to F", key)),
+    };
+    Ok(KeyEvent { frame, key, pressed })
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScriptError {
+    pub line: usize, // from 1
+    pub message: String,
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for ScriptError {}
+
+#[derive(Debug, Clone, Default)]
+pub struct KeyScript {
+    events: Vec<KeyEvent>, // in order of frame
+    next: usize,           // the first one not yet applied
+}
+
+impl KeyScript {
+    pub fn new(mut events: Vec<KeyEvent>) -> KeyScript {
+        events.sort_by_key(|event| event.frame);
+        KeyScript { events, next: 0 }
+    }
+
+    pub fn parse(text: &str) -> Result<KeyScript, ScriptError> {
+        let mut events = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let error = |message: String| ScriptError { line: i + 1, message };
+            let line = line.split('#').next().unwrap_or("");
+            let words: Vec<&str> = line.split_whitespace().collect();
+            if !words.is_empty() {
+                events.push(parse_event(&words).map_err(error)?);
+            }
+        }
+        Ok(KeyScript::new(events))
+    }
+
+    pub fn events(&self) -> &[KeyEvent] {
+        &self.events
+    }
+
+    // Presses and releases whatever is due by the Env's current frame, and
+    // returns what that was. Call it before each step, it's cheap when
+    // nothing is due.
+    #[inline]
+    pub fn apply(&mut self, env: &mut Env<Keypad>) -> &[KeyEvent] {
+        let frame = env.frame_count();
+        let start = self.next;
+        while let Some(event) = self.events.get(self.next).copied().filter(|event| event.frame <= frame) {
+            if event.pressed {
+                env.press_key(event.key);
+            } else {
+                env.release_key(event.key);
+            }
+            self.next += 1;
+        }
+        &self.events[start..self.next]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quirks::Quirks;
+
+    fn event(frame: u64, key: u8, pressed: bool) -> KeyEvent {
+        KeyEvent { frame, key, pressed }
+    }
+
+    fn error(text: &str) -> ScriptError {
+        KeyScript::parse(text).unwrap_err()
+    }
+
+    #[test]
+    fn parse_event_words() {
+        assert_eq!(parse_event(&["60", "press", "5"]), Ok(event(60, 5, true)));
+        assert_eq!(parse_event(&["0", "release", "F"]), Ok(event(0, 15, false)));
+        assert_eq!(parse_event(&["1", "press", "g"]), Err("g isn't a key, they go from 0 to F".to_string()));
+        assert_eq!(parse_event(&["1", "press", "10"]), Err("10 isn't a key, they go from 0 to F".to_string()));
+        assert_eq!(parse_event(&["-1", "press", "1"]), Err("-1 isn't a frame number".to_string()));
+        assert_eq!(parse_event(&["1", "hold", "1"]), Err("hold should be press or release".to_string()));
+        assert!(parse_event(&["1", "press"]).is_err());
+        assert!(parse_event(&["1", "press", "1", "2"]).is_err());
+    }
+
+    #[test]
+    fn scripts_are_sorted_by_frame() {
+        let script = KeyScript::parse("# frame what key\n\n120 press a # late\n  60 press 5\n64 release 5\n60 press 6\n").unwrap();
+        assert_eq!(script.events(), [event(60, 5, true), event(60, 6, true), event(64, 5, false), event(120, 10, true)]);
+        let text: Vec<String> = script.events().iter().map(KeyEvent::to_string).collect();
+        assert_eq!(text, ["60 press 5", "60 press 6", "64 release 5", "120 press a"]);
+    }
+
+    #[test]
+    fn errors_say_which_line() {
+        assert_eq!(error("# comment\n1 press 1\n2 press 16"), ScriptError {
+            line: 3,
+            message: "16 isn't a key, they go from 0 to F".to_string(),
+        });
+        assert_eq!(error("\n\nsoon press 1").line, 3);
+        assert_eq!(error("1 press").to_string(), "line 1: expected a frame, press or release, and a key");
+    }
+
+    #[test]
+    fn apply_presses_whatever_is_due() {
+        let mut env = Env::new(Keypad::new(), Quirks::default());
+        // JP 0x200
+        env.load_into_memory(&[0x12, 0x00]).unwrap();
+        let mut script = KeyScript::parse("1 press 3\n0 press 1\n1 release 1\n3 press 2").unwrap();
+
+        assert_eq!(script.apply(&mut env), [event(0, 1, true)]);
+        assert_eq!(script.apply(&mut env), []);
+        assert_eq!(env.host().keys(), 1 << 1);
+        env.run_frame().unwrap();
+        assert_eq!(script.apply(&mut env), [event(1, 3, true), event(1, 1, false)]);
+        assert_eq!(env.host().keys(), 1 << 3);
+        // Frames that were skipped catch up at once.
+        env.run_frame().unwrap();
+        env.run_frame().unwrap();
+        env.run_frame().unwrap();
+        assert_eq!(script.apply(&mut env), [event(3, 2, true)]);
+        assert_eq!(env.host().keys(), 1 << 3 | 1 << 2);
+        assert_eq!(script.apply(&mut env), []);
+    }
+}
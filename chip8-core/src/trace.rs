@@ -0,0 +1,272 @@
+// Execution traces, a record of every instruction the interpreter runs, for
+// working out where it and another emulator part ways. As text there's a
+// line per instruction: the cycle it ran on, its address and opcode, the
+// instruction, the registers it changed and then I and the timers.
+//
+//           7 208 7101 ADD V1, 0x01         V1=02 I=0302 DT=00 ST=3C
+//
+// The binary format holds the same in about a fifth of the space, for long
+// runs. After a 4 byte header each instruction is
+//
+//   cycles skipped since the last one   LEB128, so one byte when there are none
+//   pc, opcode                          2 bytes each, big-endian
+//   which registers changed             2 bytes, bit x for Vx
+//   their new values                    a byte each, V0 first
+//   I, DT, ST                           2 + 1 + 1 bytes
+
+use std::fmt;
+use std::io::{self, ErrorKind, Read, Write};
+
+use crate::error::Chip8Error;
+use crate::host::Host;
+use crate::instruction::Instruction;
+use crate::vm::Env;
+
+const MAGIC: &[u8; 4] = b"C8T\x01";
+
+// One instruction, and the state it left behind.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Step {
+    pub cycle: u64, // how many instructions ran before it
+    pub pc: u16,
+    pub opcode: u16,
+    // V0-VF afterwards. Read back from a binary trace only the changed ones
+    // are sure to be right, the rest are as of the last step that had them.
+    pub registers: [u8; 16],
+    pub changed: u16, // bit x for Vx
+    pub index: u16,
+    pub delay: u8,
+    pub sound: u8,
+}
+
+impl Step {
+    // Runs one instruction with Env::step and says what it did.
+    pub fn run<H: Host>(env: &mut Env<H>) -> Result<Step, Chip8Error> {
+        let cycle = env.cycle_count();
+        let pc = env.program_counter();
+        let memory = env.memory();
+        let byte = |offset: u16| memory.get(pc.wrapping_add(offset) as usize).copied().unwrap_or(0);
+        let opcode = (byte(0) as u16) << 8 | byte(1) as u16;
+        let before = *env.registers();
+
+        env.step()?;
+
+        let registers = *env.registers();
+        let changed = (0..16)
+            .filter(|&x| before[x] != registers[x])
+            .fold(0, |mask, x| mask | 1 << x);
+        Ok(Step {
+            cycle,
+            pc,
+            opcode,
+            registers,
+            changed,
+            index: env.index_register(),
+            delay: env.delay_timer(),
+            sound: env.sound_timer(),
+        })
+    }
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let instruction = match Instruction::decode(self.opcode) {
+            // Its address isn't in the opcode, but it's where I ends up.
+            Some(Instruction::SetIndexLong) => format!("LD I, LONG 0x{:04X}", self.index),
+            Some(instr) => instr.to_string(),
+            None => "??".to_string(),
+        };
+        write!(f, "{:>12} {:03X} {:04X} {:<20}", self.cycle, self.pc, self.opcode, instruction)?;
+        for x in (0..16).filter(|x| self.changed & 1 << x != 0) {
+            write!(f, " V{:X}={:02X}", x, self.registers[x])?;
+        }
+        write!(f, " I={:04X} DT={:02X} ST={:02X}", self.index, self.delay, self.sound)
+    }
+}
+
+pub struct BinaryWriter<W: Write> {
+    out: W,
+    next_cycle: u64,
+}
+
+impl<W: Write> BinaryWriter<W> {
+    // Writes the header straight away.
+    pub fn new(mut out: W) -> io::Result<BinaryWriter<W>> {
+        out.write_all(MAGIC)?;
+        Ok(BinaryWriter { out, next_cycle: 0 })
+    }
+
+    // Steps have to be written in the order they ran.
+    pub fn write(&mut self, step: &Step) -> io::Result<()> {
+        let mut record = Vec::with_capacity(32);
+        let mut skipped = step.cycle.saturating_sub(self.next_cycle);
+        loop {
+            let byte = (skipped & 0x7F) as u8;
+            skipped >>= 7;
+            if skipped == 0 {
+                record.push(byte);
+                break;
+            }
+            record.push(byte | 0x80);
+        }
+        record.extend_from_slice(&step.pc.to_be_bytes());
+        record.extend_from_slice(&step.opcode.to_be_bytes());
+        record.extend_from_slice(&step.changed.to_be_bytes());
+        for x in (0..16).filter(|x| step.changed & 1 << x != 0) {
+            record.push(step.registers[x]);
+        }
+        record.extend_from_slice(&step.index.to_be_bytes());
+        record.push(step.delay);
+        record.push(step.sound);
+        self.next_cycle = step.cycle + 1;
+        self.out.write_all(&record)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+// Reads the steps back out of a binary trace, as an iterator.
+pub struct BinaryReader<R: Read> {
+    input: R,
+    next_cycle: u64,
+    registers: [u8; 16],
+}
+
+impl<R: Read> BinaryReader<R> {
+    // Fails if it doesn't start with the header.
+    pub fn new(mut input: R) -> io::Result<BinaryReader<R>> {
+        let mut magic = [0; 4];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(ErrorKind::InvalidData, "not a chip8 binary trace"));
+        }
+        Ok(BinaryReader { input, next_cycle: 0, registers: [0; 16] })
+    }
+
+    fn byte(&mut self) -> io::Result<u8> {
+        let mut byte = [0];
+        self.input.read_exact(&mut byte)?;
+        Ok(byte[0])
+    }
+
+    fn word(&mut self) -> io::Result<u16> {
+        let mut word = [0; 2];
+        self.input.read_exact(&mut word)?;
+        Ok(u16::from_be_bytes(word))
+    }
+
+    // None at a clean end of the file.
+    fn read_step(&mut self) -> io::Result<Option<Step>> {
+        let mut skipped = 0u64;
+        let mut shift = 0;
+        loop {
+            let byte = match self.byte() {
+                Ok(byte) => byte,
+                Err(err) if err.kind() == ErrorKind::UnexpectedEof && shift == 0 => return Ok(None),
+                Err(err) => return Err(err),
+            };
+            if shift > 63 {
+                return Err(io::Error::new(ErrorKind::InvalidData, "bad cycle count"));
+            }
+            skipped |= ((byte & 0x7F) as u64) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        let cycle = self.next_cycle.checked_add(skipped)
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "bad cycle count"))?;
+        let pc = self.word()?;
+        let opcode = self.word()?;
+        let changed = self.word()?;
+        for x in (0..16).filter(|x| changed & 1 << x != 0) {
+            self.registers[x] = self.byte()?;
+        }
+        let index = self.word()?;
+        let delay = self.byte()?;
+        let sound = self.byte()?;
+        self.next_cycle = cycle + 1;
+        Ok(Some(Step { cycle, pc, opcode, registers: self.registers, changed, index, delay, sound }))
+    }
+}
+
+impl<R: Read> Iterator for BinaryReader<R> {
+    type Item = io::Result<Step>;
+
+    fn next(&mut self) -> Option<io::Result<Step>> {
+        self.read_step().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keypad::Keypad;
+    use crate::quirks::Quirks;
+
+    // Every third step of a loop, so there are gaps in the cycles and
+    // registers that changed in between without being written.
+    fn steps() -> Vec<Step> {
+        let mut env = Env::new(Keypad::new(), Quirks::default());
+        // ADD V1, 1; ADD V2, 3; LD DT, V1; JP 0x200
+        env.load_into_memory(&[0x71, 0x01, 0x72, 0x03, 0xF1, 0x15, 0x12, 0x00]).unwrap();
+        let mut steps: Vec<Step> = (0..60)
+            .map(|_| Step::run(&mut env).unwrap())
+            .filter(|step| step.cycle % 3 == 0)
+            .collect();
+        let mut last = steps.last().unwrap().clone();
+        last.cycle += 1 << 40;
+        steps.push(last);
+        steps
+    }
+
+    fn write(steps: &[Step]) -> Vec<u8> {
+        let mut writer = BinaryWriter::new(Vec::new()).unwrap();
+        for step in steps {
+            writer.write(step).unwrap();
+        }
+        writer.out
+    }
+
+    #[test]
+    fn round_trip() {
+        let steps = steps();
+        assert!(steps.iter().any(|step| step.changed.count_ones() == 1));
+        let bytes = write(&steps);
+        let read: Vec<Step> = BinaryReader::new(&bytes[..]).unwrap().map(Result::unwrap).collect();
+        assert_eq!(read.len(), steps.len());
+        for (read, step) in read.iter().zip(&steps) {
+            assert_eq!(read.to_string(), step.to_string());
+            assert_eq!(read.cycle, step.cycle);
+        }
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let mut bytes = write(&steps());
+        bytes[3] = 2;
+        let err = BinaryReader::new(&bytes[..]).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        let err = BinaryReader::new(&MAGIC[..3]).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn truncated_records_fail() {
+        let steps = steps();
+        let bytes = write(&steps);
+        let read: Vec<io::Result<Step>> = BinaryReader::new(&bytes[..bytes.len() - 1]).unwrap().collect();
+        assert_eq!(read.len(), steps.len());
+        assert!(read[..steps.len() - 1].iter().all(Result::is_ok));
+        assert_eq!(read.last().unwrap().as_ref().unwrap_err().kind(), ErrorKind::UnexpectedEof);
+
+        // Cut off inside the cycle count, which only ends cleanly before it.
+        let mut bytes = write(&steps[..1]);
+        bytes.push(0x80);
+        let mut reader = BinaryReader::new(&bytes[..]).unwrap();
+        assert!(reader.next().unwrap().is_ok());
+        assert_eq!(reader.next().unwrap().unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    }
+}
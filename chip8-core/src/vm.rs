@@ -0,0 +1,1100 @@
+use crate::debug::{Access, MemoryWatch, Watchpoint};
+use crate::error::Chip8Error;
+use crate::host::Host;
+use crate::instruction::{nibble, Instruction};
+use crate::quirks::Quirks;
+use crate::random::{self, RandomMode};
+
+mod state;
+
+pub use state::StateError;
+
+pub struct Env<H: Host> {
+    host: H,
+    quirks: Quirks,
+
+    display_changed: bool,
+    fading_pixels: [u128; 64],
+    // 128x64 in hires mode. In lores mode only the top left 64x32 is used.
+    // XO-CHIP adds a second plane, the two bits together pick one of 4 colors.
+    display: [[u128; 64]; 2],
+    selected_planes: u8, // bitmask of the planes drawing/clearing/scrolling act on
+    hires: bool,
+    exited: bool, // set by 00FD
+    
+    program_counter: u16,
+    current_instr: (u8, u8, u8, u8), // 4 nibbles
+    
+    stack: [u16; 16], // holds the PCs for all subroutines nested above the current one in descending order.
+    stack_next_pos: u8,
+    
+    delay_timer: u8, // delay timer @60hz
+    sound_timer: u8, // beeps while not 0
+    // Time is counted in instructions. Every cycles_per_frame of them is a
+    // 60hz frame, which is when the timers tick.
+    cycles_per_frame: u32,
+    frame_cycle: u32, // how far into the current frame we are
+    cycle_count: u64,
+    frame_count: u64,
+    vblank: bool, // if this step is the first of a frame
+
+    memory: Vec<u8>, // 64K, XO-CHIP can address all of it
+    index_register: u16, // 16-bit, ref as "I"
+    variable_registers: [u8; 16], // v0-f (vf may be flag register)
+    rpl_flags: [u8; 16], // SCHIP's "RPL user flags", a spot to stash registers in
+
+    random_mode: RandomMode,
+    // xorshift's state, or for the VIP generator the page pointer in the
+    // second byte and the last random number in the first.
+    random_state: u64,
+
+    // XO-CHIP's sound: a 1-bit, 128 sample waveform looped while the sound timer runs.
+    audio_pattern: [u8; 16],
+    pitch: u8, // sets the playback rate of the pattern
+
+    // Set while a debugger has watchpoints, so loads and stores cost a
+    // single check otherwise.
+    watch: Option<Box<MemoryWatch>>,
+}
+
+/*
+First 512 bytes (0-1ff) were meant to be for the interpreter.
+Programs are located at addr 0x200.
+Fonts are 4x5 and are popularly located at addr 0x50-0x9F
+SCHIP's big 8x10 fonts follow right after at 0xA0-0x13F
+*/
+
+const MEMORY_SIZE: usize = 0x10000;
+const FONT_START_LOCATION: usize = 0x50;
+const BIG_FONT_START_LOCATION: usize = 0xA0;
+const PROGRAM_START_LOCATION: usize = 0x200;
+
+impl<H: Host> Env<H> {
+    pub fn new(host: H, quirks: Quirks) -> Env<H> {
+        let mut env = Env {
+            host,
+            quirks,
+            memory: vec![0; MEMORY_SIZE],
+            display: [[0; 64]; 2],
+            selected_planes: 1,
+            display_changed: false,
+            hires: false,
+            exited: false,
+            program_counter: 0x200,
+            index_register: 0,
+            stack: [0; 16],
+            delay_timer: 0,
+            sound_timer: 0,
+            cycles_per_frame: 9, // 540hz
+            frame_cycle: 0,
+            cycle_count: 0,
+            frame_count: 0,
+            vblank: false,
+            stack_next_pos: 0,
+            variable_registers: [0; 16],
+            current_instr: (0, 0, 0, 0), // tuple of nibbles
+            fading_pixels: [0; 64],
+            rpl_flags: [0; 16],
+            random_mode: RandomMode::default(),
+            random_state: random::seed_state(0),
+            // A plain square wave until the program loads its own.
+            audio_pattern: [0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF,
+                0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF],
+            pitch: 64,
+            watch: None,
+        };
+
+        // 5 cols, 16 rows
+        const FONTS: [u8; 5 * 16] = [
+            0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
+            0x20, 0x60, 0x20, 0x20, 0x70, // 1
+            0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
+            0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
+            0x90, 0x90, 0xF0, 0x10, 0x10, // 4
+            0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
+            0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
+            0xF0, 0x10, 0x20, 0x40, 0x40, // 7
+            0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
+            0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
+            0xF0, 0x90, 0xF0, 0x90, 0x90, // A
+            0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
+            0xF0, 0x80, 0x80, 0x80, 0xF0, // C
+            0xE0, 0x90, 0x90, 0x90, 0xE0, // D
+            0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
+            0xF0, 0x80, 0xF0, 0x80, 0x80  // F
+        ];
+
+        // 10 rows each, only 0-9 existed on SCHIP but A-F are commonly added
+        const BIG_FONTS: [u8; 10 * 16] = [
+            0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, // 0
+            0x18, 0x78, 0x78, 0x18, 0x18, 0x18, 0x18, 0x18, 0xFF, 0xFF, // 1
+            0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // 2
+            0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 3
+            0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0x03, 0x03, // 4
+            0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 5
+            0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 6
+            0xFF, 0xFF, 0x03, 0x03, 0x06, 0x0C, 0x18, 0x18, 0x18, 0x18, // 7
+            0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 8
+            0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 9
+            0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, // A
+            0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, // B
+            0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C, // C
+            0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+            0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // E
+            0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0  // F
+        ];
+
+        env.memory[FONT_START_LOCATION..FONT_START_LOCATION + FONTS.len()].copy_from_slice(&FONTS);
+        env.memory[BIG_FONT_START_LOCATION..BIG_FONT_START_LOCATION + BIG_FONTS.len()]
+            .copy_from_slice(&BIG_FONTS);
+
+        env
+    }
+
+    pub fn host(&self) -> &H {
+        &self.host
+    }
+
+    pub fn host_mut(&mut self) -> &mut H {
+        &mut self.host
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    pub fn is_beeping(&self) -> bool {
+        self.sound_timer > 0
+    }
+
+    // Restarts the random number generator. The same seed (and mode) always
+    // gives the same numbers.
+    pub fn seed_random(&mut self, mode: RandomMode, seed: u64) {
+        self.random_mode = mode;
+        self.random_state = match mode {
+            RandomMode::Xorshift => random::seed_state(seed),
+            // Only the starting pointer and previous number matter here.
+            RandomMode::CosmacVip => seed & 0xFFFF,
+        };
+    }
+
+    pub fn random_mode(&self) -> RandomMode {
+        self.random_mode
+    }
+
+    // How many instructions run per 60hz frame, i.e. the clock speed / 60.
+    pub fn set_cycles_per_frame(&mut self, cycles: u32) {
+        self.cycles_per_frame = cycles.max(1);
+    }
+
+    pub fn cycles_per_frame(&self) -> u32 {
+        self.cycles_per_frame
+    }
+
+    // Instructions executed (or waited on) since the machine was created.
+    pub fn cycle_count(&self) -> u64 {
+        self.cycle_count
+    }
+
+    // 60hz frames elapsed since the machine was created.
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    // Instructions into the current frame, from 0 to cycles_per_frame - 1.
+    pub fn frame_cycle(&self) -> u32 {
+        self.frame_cycle
+    }
+
+    // Has the program asked to exit (00FD)? Stepping does nothing after this.
+    pub fn has_exited(&self) -> bool {
+        self.exited
+    }
+
+    // (width, height) in pixels of the current display mode.
+    pub fn resolution(&self) -> (usize, usize) {
+        if self.hires {
+            (128, 64)
+        } else {
+            (64, 32)
+        }
+    }
+
+    // The color (0-3) of a pixel. Bit 0 comes from plane 0, bit 1 from plane 1.
+    pub fn pixel(&self, x: usize, y: usize) -> u8 {
+        let bit = |plane: &[u128; 64]| ((plane[y] >> (127 - x)) & 1) as u8;
+        bit(&self.display[0]) | bit(&self.display[1]) << 1
+    }
+
+    // Each row holds up to 128 pixels, the leftmost being the most significant bit.
+    pub fn plane(&self, plane: usize) -> &[u128; 64] {
+        &self.display[plane]
+    }
+
+    // The pixels the last sprite draw turned off, per row.
+    pub fn fading_pixels(&self) -> &[u128; 64] {
+        &self.fading_pixels
+    }
+
+    // The 128 1-bit samples the beeper loops through, most significant bit first.
+    pub fn audio_pattern(&self) -> &[u8; 16] {
+        &self.audio_pattern
+    }
+
+    // How many pattern samples per second to play, set by FX3A.
+    pub fn playback_rate(&self) -> f32 {
+        4000.0 * 2f32.powf((self.pitch as f32 - 64.0) / 48.0)
+    }
+
+    // The address of the next instruction to run.
+    pub fn program_counter(&self) -> u16 {
+        self.program_counter
+    }
+
+    pub fn index_register(&self) -> u16 {
+        self.index_register
+    }
+
+    // V0-VF
+    pub fn registers(&self) -> &[u8; 16] {
+        &self.variable_registers
+    }
+
+    // The addresses of the CALLs we're nested inside, outermost first.
+    pub fn stack(&self) -> &[u16] {
+        &self.stack[..self.stack_next_pos as usize]
+    }
+
+    pub fn delay_timer(&self) -> u8 {
+        self.delay_timer
+    }
+
+    pub fn sound_timer(&self) -> u8 {
+        self.sound_timer
+    }
+
+    // All 64K, the ROM starts at 0x200.
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    // The setters below are for debuggers poking at a paused program.
+    pub fn set_program_counter(&mut self, pc: u16) {
+        self.program_counter = pc;
+    }
+
+    pub fn set_index_register(&mut self, i: u16) {
+        self.index_register = i;
+    }
+
+    pub fn registers_mut(&mut self) -> &mut [u8; 16] {
+        &mut self.variable_registers
+    }
+
+    pub fn set_delay_timer(&mut self, value: u8) {
+        self.delay_timer = value;
+    }
+
+    pub fn set_sound_timer(&mut self, value: u8) {
+        self.sound_timer = value;
+    }
+
+    pub fn memory_mut(&mut self) -> &mut [u8] {
+        &mut self.memory
+    }
+
+    // Whether the last step changed the display and it needs to be redrawn.
+    pub fn display_changed(&self) -> bool {
+        self.display_changed
+    }
+
+    // Copies the ROM into emulator memory.
+    // Changes the program counter to prepare for execution.
+    pub fn load_into_memory(&mut self, rom: &[u8]) -> Result<(), Chip8Error> {
+        let max = self.memory.len() - PROGRAM_START_LOCATION;
+        if rom.len() > max {
+            return Err(Chip8Error::RomTooLarge { size: rom.len(), max });
+        }
+        self.program_counter = PROGRAM_START_LOCATION as u16;
+        self.memory[PROGRAM_START_LOCATION..PROGRAM_START_LOCATION + rom.len()].copy_from_slice(rom);
+        Ok(())
+    }
+
+    #[inline]
+    fn opcode(&self) -> u16 {
+        let (a, b, c, d) = self.current_instr;
+        nibble::pack(a, b, c, d)
+    }
+
+    pub(crate) fn set_watchpoints(&mut self, watchpoints: &[Watchpoint]) {
+        self.watch = if watchpoints.is_empty() {
+            None
+        } else {
+            Some(Box::new(MemoryWatch::new(watchpoints)))
+        };
+    }
+
+    // The first watched load or store since this was last called.
+    pub(crate) fn take_watch_hit(&mut self) -> Option<(u16, Access)> {
+        self.watch.as_mut().and_then(|watch| watch.take_hit())
+    }
+
+    #[inline]
+    fn read_memory(&self, address: usize) -> Result<u8, Chip8Error> {
+        if let Some(watch) = &self.watch {
+            watch.check(address, Access::Read);
+        }
+        self.fetch_memory(address)
+    }
+
+    // Reads without telling the watchpoints, for the instructions themselves.
+    #[inline]
+    fn fetch_memory(&self, address: usize) -> Result<u8, Chip8Error> {
+        match self.memory.get(address) {
+            Some(&byte) => Ok(byte),
+            None => Err(Chip8Error::MemoryOutOfBounds {
+                pc: self.program_counter,
+                opcode: self.opcode(),
+                address,
+            }),
+        }
+    }
+
+    // Faults on the first address of a range that's outside memory, so an
+    // instruction touching several bytes can check before changing anything.
+    #[inline]
+    fn check_range(&self, start: usize, len: usize) -> Result<(), Chip8Error> {
+        if start + len > self.memory.len() {
+            return Err(Chip8Error::MemoryOutOfBounds {
+                pc: self.program_counter,
+                opcode: self.opcode(),
+                address: start.max(self.memory.len()),
+            });
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn write_memory(&mut self, address: usize, value: u8) -> Result<(), Chip8Error> {
+        if let Some(watch) = &self.watch {
+            watch.check(address, Access::Write);
+        }
+        match self.memory.get_mut(address) {
+            Some(byte) => {
+                *byte = value;
+                Ok(())
+            },
+            None => Err(Chip8Error::MemoryOutOfBounds {
+                pc: self.program_counter,
+                opcode: self.opcode(),
+                address,
+            }),
+        }
+    }
+
+    // Only the columns that exist in the current display mode are set.
+    #[inline]
+    fn row_mask(&self) -> u128 {
+        !0u128 << (128 - self.resolution().0)
+    }
+
+    // Only the selected planes are cleared or scrolled.
+    #[inline]
+    fn selected_planes_mut(&mut self) -> impl Iterator<Item = &mut [u128; 64]> {
+        let selected = self.selected_planes;
+        self.display.iter_mut()
+            .enumerate()
+            .filter(move |(i, _)| selected & (1 << i) != 0)
+            .map(|(_, plane)| plane)
+    }
+
+    #[inline]
+    fn display_clear(&mut self) {
+        for plane in self.selected_planes_mut() {
+            *plane = [0; 64];
+        }
+        self.display_changed = true;
+    }
+
+    #[inline]
+    fn scroll_down(&mut self, n: u8) {
+        let height = self.resolution().1;
+        let n = (n as usize).min(height);
+        for plane in self.selected_planes_mut() {
+            plane.copy_within(0..height - n, n);
+            plane[..n].fill(0);
+        }
+        self.display_changed = true;
+    }
+
+    #[inline]
+    fn scroll_up(&mut self, n: u8) {
+        let height = self.resolution().1;
+        let n = (n as usize).min(height);
+        for plane in self.selected_planes_mut() {
+            plane.copy_within(n..height, 0);
+            plane[height - n..height].fill(0);
+        }
+        self.display_changed = true;
+    }
+
+    #[inline]
+    fn scroll_right(&mut self) {
+        let mask = self.row_mask();
+        for plane in self.selected_planes_mut() {
+            for row in plane.iter_mut() {
+                *row = (*row >> 4) & mask;
+            }
+        }
+        self.display_changed = true;
+    }
+
+    #[inline]
+    fn scroll_left(&mut self) {
+        for plane in self.selected_planes_mut() {
+            for row in plane.iter_mut() {
+                *row <<= 4;
+            }
+        }
+        self.display_changed = true;
+    }
+
+    #[inline]
+    fn exit(&mut self) {
+        self.exited = true;
+    }
+
+    #[inline]
+    fn set_hires(&mut self, hires: bool) {
+        self.hires = hires;
+        self.display = [[0; 64]; 2];
+        self.display_changed = true;
+    }
+
+    // Skipping has to hop over all 4 bytes of XO-CHIP's F000 NNNN.
+    #[inline]
+    fn skip_next_instruction(&mut self) {
+        let next = self.program_counter as usize + 2;
+        let long = self.memory.get(next) == Some(&0xF0) && self.memory.get(next + 1) == Some(&0x00);
+        self.program_counter = self.program_counter.wrapping_add(if long { 4 } else { 2 });
+    }
+
+    #[inline]
+    fn subroutine_return(&mut self) -> Result<(), Chip8Error> {
+        if self.stack_next_pos > 0 {
+            self.stack_next_pos -= 1;
+            self.program_counter = self.stack[self.stack_next_pos as usize];
+            Ok(())
+        } else {
+            Err(Chip8Error::StackUnderflow { pc: self.program_counter, opcode: self.opcode() })
+        }
+    }
+
+    #[inline]
+    fn goto(&mut self) {
+        let (_, b, c, d) = self.current_instr;
+        // You subtract 2 because the interpreter will step forward 2
+        self.program_counter = nibble::pack(0, b, c, d).wrapping_sub(2);
+    }
+
+    #[inline]
+    fn call_subroutine(&mut self) -> Result<(), Chip8Error> {
+        if (self.stack_next_pos as usize) < self.stack.len() {
+            let (_, b, c, d) = self.current_instr;
+            self.stack[self.stack_next_pos as usize] = self.program_counter;
+            self.stack_next_pos += 1;
+            // Same as goto, the interpreter will step forward 2
+            self.program_counter = nibble::pack(0, b, c, d).wrapping_sub(2);
+            Ok(())
+        } else {
+            Err(Chip8Error::StackOverflow { pc: self.program_counter, opcode: self.opcode() })
+        }
+    }
+
+    #[inline]
+    fn skip_if_register_equals_value(&mut self) {
+        let (_, x, a, b) = self.current_instr;
+        if self.variable_registers[x as usize] == 
+            nibble::pack(0, 0, a, b) as u8 {
+            self.skip_next_instruction();
+        }
+    }
+
+    #[inline]
+    fn skip_if_register_not_equals_value(&mut self) {
+        let (_, x, a, b) = self.current_instr;
+        if self.variable_registers[x as usize] != 
+            nibble::pack(0, 0, a, b) as u8 {
+            self.skip_next_instruction();
+        }
+    }
+
+    #[inline]
+    fn store_register_range_in_memory(&mut self) -> Result<(), Chip8Error> {
+        let (_, x, y, _) = self.current_instr;
+        self.check_range(self.index_register as usize, x.abs_diff(y) as usize + 1)?;
+        // The range can go either way, VX is always stored at I.
+        for i in 0..=x.abs_diff(y) {
+            let register = if x <= y { x + i } else { x - i } as usize;
+            self.write_memory(self.index_register as usize + i as usize, self.variable_registers[register])?;
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn load_register_range_from_memory(&mut self) -> Result<(), Chip8Error> {
+        let (_, x, y, _) = self.current_instr;
+        self.check_range(self.index_register as usize, x.abs_diff(y) as usize + 1)?;
+        for i in 0..=x.abs_diff(y) {
+            let register = if x <= y { x + i } else { x - i } as usize;
+            self.variable_registers[register] = self.read_memory(self.index_register as usize + i as usize)?;
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn skip_if_registers_equal(&mut self) {
+        let (_, x, y, _) = self.current_instr;
+        if self.variable_registers[x as usize] ==
+            self.variable_registers[y as usize] {
+            self.skip_next_instruction();
+        }
+    }
+
+    #[inline]
+    fn register_set_value(&mut self) {
+        let (_, x, a, b) = self.current_instr;
+        self.variable_registers[x as usize] = nibble::pack(0, 0, a, b) as u8;
+    }
+
+    #[inline]
+    fn register_add_value(&mut self) {
+        let (_, x, a, b) = self.current_instr;
+        // 7XNN doesn't touch the carry flag, the sum just wraps around.
+        self.variable_registers[x as usize] =
+            self.variable_registers[x as usize].wrapping_add(nibble::pack(0, 0, a, b) as u8);
+    }
+
+    #[inline]
+    fn register_set_register(&mut self) {
+        let (_, x, y, _) = self.current_instr;
+        self.variable_registers[x as usize] = self.variable_registers[y as usize];
+    }
+
+    #[inline]
+    fn register_or_register(&mut self) {
+        let (_, x, y, _) = self.current_instr;
+        self.variable_registers[x as usize] |= self.variable_registers[y as usize];
+        if self.quirks.vf_reset {
+            self.variable_registers[15] = 0;
+        }
+    }
+
+    #[inline]
+    fn register_and_register(&mut self) {
+        let (_, x, y, _) = self.current_instr;
+        self.variable_registers[x as usize] &= self.variable_registers[y as usize];
+        if self.quirks.vf_reset {
+            self.variable_registers[15] = 0;
+        }
+    }
+
+    #[inline]
+    fn register_xor_register(&mut self) {
+        let (_, x, y, _) = self.current_instr;
+        self.variable_registers[x as usize] ^= self.variable_registers[y as usize];
+        if self.quirks.vf_reset {
+            self.variable_registers[15] = 0;
+        }
+    }
+
+    #[inline]
+    fn register_add_register(&mut self) {
+        let (_, x, y, _) = self.current_instr;
+        let sum = self.variable_registers[x as usize].overflowing_add(self.variable_registers[y as usize]);
+        self.variable_registers[x as usize] = sum.0;
+        self.variable_registers[15] = sum.1 as u8;
+    }
+
+    #[inline]
+    fn register_sub_register(&mut self) {
+        let (_, x, y, _) = self.current_instr;
+        let diff = self.variable_registers[x as usize].overflowing_sub(self.variable_registers[y as usize]);
+        self.variable_registers[x as usize] = diff.0;
+        self.variable_registers[15] = (!diff.1) as u8;
+    }
+
+    #[inline]
+    fn register_right_shift(&mut self) {
+        let (_, x, y, _) = self.current_instr;
+        let v = self.shift_source(x, y);
+        self.variable_registers[x as usize] = v >> 1;
+        // VF is written last so the flag wins if X is F.
+        self.variable_registers[15] = v & 1;
+    }
+
+    #[inline]
+    fn register_set_register_sub_register(&mut self) {
+        let (_, x, y, _) = self.current_instr;
+        let diff = self.variable_registers[y as usize].overflowing_sub(self.variable_registers[x as usize]);
+        self.variable_registers[x as usize] = diff.0;
+        self.variable_registers[15] = (!diff.1) as u8;
+    }
+
+    #[inline]
+    fn register_left_shift(&mut self) {
+        let (_, x, y, _) = self.current_instr;
+        let v = self.shift_source(x, y);
+        self.variable_registers[x as usize] = v << 1;
+        self.variable_registers[15] = (v >> 7) & 1;
+    }
+
+    #[inline]
+    fn shift_source(&self, x: u8, y: u8) -> u8 {
+        if self.quirks.shift_uses_vy {
+            self.variable_registers[y as usize]
+        } else {
+            self.variable_registers[x as usize]
+        }
+    }
+
+    #[inline]
+    fn skip_if_registers_not_equal(&mut self) {
+        let (_, x, y, _) = self.current_instr;
+        if self.variable_registers[x as usize] != 
+            self.variable_registers[y as usize] {
+            self.skip_next_instruction();
+        }
+    }
+
+    #[inline]
+    fn set_index_register_to_value(&mut self) {
+        let (_, a, b, c) = self.current_instr;
+        self.index_register = nibble::pack(0, a, b, c);
+    }
+
+    #[inline]
+    fn goto_register_plus_value(&mut self) {
+        let (_, a, b, c) = self.current_instr;
+        // BNNN adds V0, but with the quirk it's read as BXNN and adds VX.
+        let offset = if self.quirks.jump_uses_vx {
+            self.variable_registers[a as usize]
+        } else {
+            self.variable_registers[0]
+        };
+        // Subtract 2 for the step forward, same as goto
+        self.program_counter = 
+            (offset as u16 + nibble::pack(0, a, b, c)).wrapping_sub(2);
+    }
+
+    #[inline]
+    fn random_byte(&mut self) -> u8 {
+        match self.random_mode {
+            RandomMode::Xorshift => random::xorshift(&mut self.random_state),
+            RandomMode::CosmacVip => random::vip(&mut self.random_state),
+        }
+    }
+
+    #[inline]
+    fn set_register_rand_and_value(&mut self) {
+        let (_, x, a, b) = self.current_instr;
+        let n = self.random_byte();
+        self.variable_registers[x as usize] = n & nibble::pack(0, 0, a, b) as u8;
+    }
+
+    #[inline]
+    fn draw_sprite(&mut self) -> Result<(), Chip8Error> {
+        if self.quirks.display_wait && !self.vblank {
+            // Keep retrying the draw until the next tick.
+            self.program_counter = self.program_counter.wrapping_sub(2);
+            return Ok(());
+        }
+
+        let (_, x, y, h) = self.current_instr;
+        let (width, height) = self.resolution();
+        // The starting position always wraps around the screen.
+        let x = self.variable_registers[x as usize] as usize % width;
+        let y = self.variable_registers[y as usize] as usize % height;
+        // DXY0 is SCHIP's 16x16 sprite, 2 bytes per row.
+        let (sprite_width, rows) = if h == 0 { (16, 16) } else { (8, h as usize) };
+        let bytes_per_row = sprite_width / 8;
+        let mask = self.row_mask();
+
+        // Every row is read before anything is drawn, so a sprite running off
+        // the end of memory faults with the display as it was.
+        // With both XO-CHIP planes selected, the second plane's sprite follows the first in memory.
+        let mut sprite = [(0, 0, 0); 32]; // plane, y, bits
+        let mut count = 0;
+        let mut addr = self.index_register as usize;
+        for plane in 0..self.display.len() {
+            if self.selected_planes & (1 << plane) == 0 {
+                continue;
+            }
+            for i in 0..rows {
+                let mut y = y + i;
+                if y >= height {
+                    if self.quirks.clip_sprites {
+                        break;
+                    }
+                    y %= height;
+                }
+                let row_addr = addr + i * bytes_per_row;
+                let bits = if sprite_width == 16 {
+                    (self.read_memory(row_addr)? as u128) << 8 | self.read_memory(row_addr + 1)? as u128
+                } else {
+                    self.read_memory(row_addr)? as u128
+                };
+                sprite[count] = (plane, y, bits);
+                count += 1;
+            }
+            addr += rows * bytes_per_row;
+        }
+
+        // this footprint tells if a certain column anywhere in the rows was flipped from 1 to 0.
+        let mut pixel_set_to_zero = false;
+        let first_plane = sprite[0].0;
+        for &(plane, y, bits) in &sprite[..count] {
+            // First, this moves the sprite's pixels to the leftmost column,
+            // and then you shift it right x times.
+            let sprite_row = bits << (128 - sprite_width);
+            let mut row = sprite_row >> x;
+            if !self.quirks.clip_sprites {
+                // Bring the pixels falling off the right edge back in on the left.
+                row = if width == 128 {
+                    sprite_row.rotate_right(x as u32)
+                } else {
+                    row | row << width
+                };
+            }
+            let row = row & mask;
+            let fading_row = self.display[plane][y] & row;
+            if plane == first_plane {
+                self.fading_pixels[y] = fading_row;
+            } else {
+                self.fading_pixels[y] |= fading_row;
+            }
+            if fading_row != 0 {
+                pixel_set_to_zero = true;
+            }
+            self.display[plane][y] ^= row;
+        }
+
+        // if a pixel was switched to OFF, anywhere
+        self.display_changed = true;
+        self.variable_registers[15] = pixel_set_to_zero as u8;
+        Ok(())
+    }
+
+    #[inline]
+    fn skip_if_key_pressed_equals_register(&mut self) {
+        if self.host.is_key_pressed(self.variable_registers[self.current_instr.1 as usize]) {
+            self.skip_next_instruction();
+        }
+    }
+
+    #[inline]
+    fn skip_if_key_pressed_not_equals_register(&mut self) {
+        if !self.host.is_key_pressed(self.variable_registers[self.current_instr.1 as usize]) {
+            self.skip_next_instruction();
+        }
+    }
+
+    #[inline]
+    fn set_index_register_long(&mut self) -> Result<(), Chip8Error> {
+        // F000 is followed by a whole word holding the address
+        let pc = self.program_counter as usize;
+        self.index_register = (self.fetch_memory(pc + 2)? as u16) << 8 | self.fetch_memory(pc + 3)? as u16;
+        self.program_counter = self.program_counter.wrapping_add(2);
+        Ok(())
+    }
+
+    #[inline]
+    fn select_planes(&mut self) {
+        self.selected_planes = self.current_instr.1 & 0b11;
+    }
+
+    #[inline]
+    fn load_audio_pattern(&mut self) -> Result<(), Chip8Error> {
+        self.check_range(self.index_register as usize, self.audio_pattern.len())?;
+        for i in 0..self.audio_pattern.len() {
+            self.audio_pattern[i] = self.read_memory(self.index_register as usize + i)?;
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn set_pitch_to_register(&mut self) {
+        self.pitch = self.variable_registers[self.current_instr.1 as usize];
+    }
+
+    #[inline]
+    fn set_register_to_delay_timer(&mut self) {
+        self.variable_registers[self.current_instr.1 as usize] = self.delay_timer;
+    }
+
+    #[inline]
+    fn set_register_to_blocking_key(&mut self) {
+        match self.host.wait_for_key() {
+            Some(key) => self.variable_registers[self.current_instr.1 as usize] = key,
+            // Undo the step forward so this instruction runs again.
+            None => self.program_counter = self.program_counter.wrapping_sub(2),
+        }
+    }
+
+    #[inline]
+    fn set_delay_timer_to_register(&mut self) {
+        self.delay_timer = self.variable_registers[self.current_instr.1 as usize];
+    }
+
+    #[inline]
+    fn set_sound_timer_to_register(&mut self) {
+        self.sound_timer = self.variable_registers[self.current_instr.1 as usize];
+    }
+
+    #[inline]
+    fn add_register_to_index_register(&mut self) {
+        self.index_register = self.index_register
+            .wrapping_add(self.variable_registers[self.current_instr.1 as usize] as u16);
+    }
+
+    #[inline]
+    fn set_index_register_to_sprite_location_of_register(&mut self) {
+        // Each sprite is 5 bytes wide, so to find its addr, you count in increments of 5
+        self.index_register = FONT_START_LOCATION as u16 + 
+            self.variable_registers[self.current_instr.1 as usize] as u16 * 5;
+    }
+
+    #[inline]
+    fn set_index_register_to_big_sprite_location_of_register(&mut self) {
+        // Big sprites are 10 bytes each
+        self.index_register = BIG_FONT_START_LOCATION as u16 +
+            (self.variable_registers[self.current_instr.1 as usize] & 0xF) as u16 * 10;
+    }
+
+    #[inline]
+    fn bcd_of_register_in_index_register(&mut self) -> Result<(), Chip8Error> {
+        let v = self.variable_registers[self.current_instr.1 as usize];
+        let i = self.index_register as usize;
+        self.check_range(i, 3)?;
+        self.write_memory(i, v / 100)?;
+        self.write_memory(i + 1, v % 100 / 10)?;
+        self.write_memory(i + 2, v % 10)
+    }
+
+    #[inline]
+    fn store_registers_up_to_in_memory(&mut self) -> Result<(), Chip8Error> {
+        self.check_range(self.index_register as usize, self.current_instr.1 as usize + 1)?;
+        for i in 0..=self.current_instr.1 as usize {
+            self.write_memory(self.index_register as usize + i, self.variable_registers[i])?;
+        }
+        self.increment_index_register_after_load_store();
+        Ok(())
+    }
+
+    #[inline]
+    fn loads_registers_up_to_in_memory(&mut self) -> Result<(), Chip8Error> {
+        self.check_range(self.index_register as usize, self.current_instr.1 as usize + 1)?;
+        for i in 0..=self.current_instr.1 as usize {
+            self.variable_registers[i] = self.read_memory(self.index_register as usize + i)?;
+        }
+        self.increment_index_register_after_load_store();
+        Ok(())
+    }
+
+    #[inline]
+    fn store_registers_up_to_in_rpl_flags(&mut self) {
+        let x = self.current_instr.1 as usize;
+        self.rpl_flags[..=x].copy_from_slice(&self.variable_registers[..=x]);
+    }
+
+    #[inline]
+    fn load_registers_up_to_from_rpl_flags(&mut self) {
+        let x = self.current_instr.1 as usize;
+        self.variable_registers[..=x].copy_from_slice(&self.rpl_flags[..=x]);
+    }
+
+    #[inline]
+    fn increment_index_register_after_load_store(&mut self) {
+        if self.quirks.load_store_increments_i {
+            self.index_register = self.index_register.wrapping_add(self.current_instr.1 as u16 + 1);
+        }
+    }
+
+    // Executes a single instruction.
+    // On error the program counter is left at the faulting instruction.
+    pub fn step(&mut self) -> Result<(), Chip8Error> {
+        self.display_changed = false;
+        if self.exited {
+            return Ok(());
+        }
+
+        self.vblank = self.frame_cycle == 0;
+
+        self.current_instr = (0, 0, 0, 0);
+        self.current_instr = nibble::unpack(
+            self.fetch_memory(self.program_counter as usize)?,
+            self.fetch_memory(self.program_counter as usize + 1)?
+        );
+
+        let instr = match Instruction::decode(self.opcode()) {
+            Some(instr) => instr,
+            None => return Err(Chip8Error::UnknownOpcode { pc: self.program_counter, opcode: self.opcode() }),
+        };
+
+        // The operands are read back out of current_instr by each instruction.
+        match instr {
+            Instruction::ScrollDown(n) => self.scroll_down(n),
+            Instruction::ScrollUp(n) => self.scroll_up(n),
+            Instruction::ClearScreen => self.display_clear(),
+            Instruction::Return => self.subroutine_return()?,
+            Instruction::ScrollRight => self.scroll_right(),
+            Instruction::ScrollLeft => self.scroll_left(),
+            Instruction::Exit => self.exit(),
+            Instruction::Lores => self.set_hires(false),
+            Instruction::Hires => self.set_hires(true),
+            Instruction::Jump(_) => self.goto(),
+            Instruction::Call(_) => self.call_subroutine()?,
+            Instruction::SkipIfEqual(..) => self.skip_if_register_equals_value(),
+            Instruction::SkipIfNotEqual(..) => self.skip_if_register_not_equals_value(),
+            Instruction::SkipIfRegistersEqual(..) => self.skip_if_registers_equal(),
+            Instruction::StoreRange(..) => self.store_register_range_in_memory()?,
+            Instruction::LoadRange(..) => self.load_register_range_from_memory()?,
+            Instruction::SetValue(..) => self.register_set_value(),
+            Instruction::AddValue(..) => self.register_add_value(),
+            Instruction::Set(..) => self.register_set_register(),
+            Instruction::Or(..) => self.register_or_register(),
+            Instruction::And(..) => self.register_and_register(),
+            Instruction::Xor(..) => self.register_xor_register(),
+            Instruction::Add(..) => self.register_add_register(),
+            Instruction::Sub(..) => self.register_sub_register(),
+            Instruction::ShiftRight(..) => self.register_right_shift(),
+            Instruction::SubReverse(..) => self.register_set_register_sub_register(),
+            Instruction::ShiftLeft(..) => self.register_left_shift(),
+            Instruction::SkipIfRegistersNotEqual(..) => self.skip_if_registers_not_equal(),
+            Instruction::SetIndex(_) => self.set_index_register_to_value(),
+            Instruction::JumpOffset(_) => self.goto_register_plus_value(),
+            Instruction::Random(..) => self.set_register_rand_and_value(),
+            Instruction::Draw(..) => self.draw_sprite()?,
+            Instruction::SkipIfKey(_) => self.skip_if_key_pressed_equals_register(),
+            Instruction::SkipIfNotKey(_) => self.skip_if_key_pressed_not_equals_register(),
+            Instruction::SetIndexLong => self.set_index_register_long()?,
+            Instruction::SelectPlanes(_) => self.select_planes(),
+            Instruction::LoadAudioPattern => self.load_audio_pattern()?,
+            Instruction::GetDelay(_) => self.set_register_to_delay_timer(),
+            Instruction::WaitForKey(_) => self.set_register_to_blocking_key(),
+            Instruction::SetDelay(_) => self.set_delay_timer_to_register(),
+            Instruction::SetSound(_) => self.set_sound_timer_to_register(),
+            Instruction::AddToIndex(_) => self.add_register_to_index_register(),
+            Instruction::FontCharacter(_) => self.set_index_register_to_sprite_location_of_register(),
+            Instruction::BigFontCharacter(_) => self.set_index_register_to_big_sprite_location_of_register(),
+            Instruction::Bcd(_) => self.bcd_of_register_in_index_register()?,
+            Instruction::SetPitch(_) => self.set_pitch_to_register(),
+            Instruction::Store(_) => self.store_registers_up_to_in_memory()?,
+            Instruction::Load(_) => self.loads_registers_up_to_in_memory()?,
+            Instruction::StoreFlags(_) => self.store_registers_up_to_in_rpl_flags(),
+            Instruction::LoadFlags(_) => self.load_registers_up_to_from_rpl_flags(),
+        }
+
+        // Each instr is 2 bytes. Jumps to 0 leave the PC at 0xFFFE for this
+        // to wrap back around.
+        self.program_counter = self.program_counter.wrapping_add(2);
+        self.end_cycle();
+        Ok(())
+    }
+
+    fn end_cycle(&mut self) {
+        self.cycle_count += 1;
+        self.frame_cycle += 1;
+        if self.frame_cycle < self.cycles_per_frame {
+            return;
+        }
+
+        self.frame_cycle = 0;
+        self.frame_count += 1;
+        if self.delay_timer > 0 {
+            self.delay_timer -= 1;
+        }
+        if self.sound_timer > 0 {
+            self.sound_timer -= 1;
+        }
+        if self.random_mode == RandomMode::CosmacVip {
+            // The VIP moved its random pointer along in the 60hz interrupt.
+            self.random_state = self.random_state.wrapping_add(1 << 8) & 0xFFFF;
+        }
+    }
+
+    // Steps up to the end of the current frame, which normally is
+    // cycles_per_frame instructions. Stops early if the program exits.
+    // Afterwards display_changed tells if anything was drawn during the frame.
+    pub fn run_frame(&mut self) -> Result<(), Chip8Error> {
+        let mut changed = false;
+        loop {
+            let result = self.step();
+            changed |= self.display_changed;
+            self.display_changed = changed;
+            result?;
+            if self.frame_cycle == 0 || self.exited {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keypad::Keypad;
+
+    fn env_with(rom: &[u8]) -> Env<Keypad> {
+        let mut env = Env::new(Keypad::new(), Quirks::default());
+        env.load_into_memory(rom).unwrap();
+        env
+    }
+
+    #[test]
+    fn jump_to_zero() {
+        let mut env = env_with(&[0x10, 0x00]);
+        env.step().unwrap();
+        assert_eq!(env.program_counter(), 0);
+    }
+
+    #[test]
+    fn call_zero() {
+        let mut env = env_with(&[0x20, 0x00]);
+        env.step().unwrap();
+        assert_eq!(env.program_counter(), 0);
+        assert_eq!(env.stack(), &[0x200]);
+    }
+
+    #[test]
+    fn sprite_past_the_end_of_memory_draws_nothing() {
+        let mut env = env_with(&[0xD0, 0x05]);
+        env.memory_mut()[0xFFFE..].copy_from_slice(&[0xFF, 0xFF]);
+        env.set_index_register(0xFFFE);
+        env.registers_mut()[0xF] = 7;
+        let err = env.step().unwrap_err();
+        assert_eq!(err, Chip8Error::MemoryOutOfBounds { pc: 0x200, opcode: 0xD005, address: 0x10000 });
+        assert!(env.plane(0).iter().all(|&row| row == 0));
+        assert_eq!(env.registers()[0xF], 7);
+    }
+
+    #[test]
+    fn store_past_the_end_of_memory_stores_nothing() {
+        let mut env = env_with(&[0xF3, 0x55]);
+        env.registers_mut()[..4].copy_from_slice(&[1, 2, 3, 4]);
+        env.set_index_register(0xFFFE);
+        let err = env.step().unwrap_err();
+        assert_eq!(err, Chip8Error::MemoryOutOfBounds { pc: 0x200, opcode: 0xF355, address: 0x10000 });
+        assert_eq!(&env.memory()[0xFFFE..], &[0, 0]);
+        assert_eq!(env.index_register(), 0xFFFE);
+    }
+
+    #[test]
+    fn wait_for_key_at_zero() {
+        let mut env = env_with(&[]);
+        env.memory_mut()[..2].copy_from_slice(&[0xF0, 0x0A]);
+        env.set_program_counter(0);
+        env.step().unwrap();
+        assert_eq!(env.program_counter(), 0);
+    }
+}
@@ -0,0 +1,406 @@
+// Save states. The whole machine is written out as:
+//
+//   "CH8S"  magic
+//   u16     format version
+//   ...     the machine, see `write_body`
+//   u32     CRC-32 of everything before it
+//
+// All numbers are big-endian.
+
+use std::error::Error;
+use std::fmt;
+
+use super::{Env, MEMORY_SIZE};
+use crate::checksum::crc32;
+use crate::host::Host;
+use crate::quirks::Quirks;
+use crate::random::RandomMode;
+
+const MAGIC: &[u8; 4] = b"CH8S";
+const VERSION: u16 = 3;
+// Must match what write_body writes.
+const BODY_LENGTH: usize = 1 // quirks
+    + 2 + 2 + 1 + 16 * 2 // PC, I, stack pointer, stack
+    + 16 + 2 // V registers, timers
+    + 4 + 4 + 8 + 8 // cycles per frame, cycle within the frame, cycle and frame counts
+    + 3 // hires, exited, selected planes
+    + 16 + 16 + 1 // RPL flags, audio pattern, pitch
+    + 1 + 8 // random mode and state
+    + 2 * 64 * 16 // display planes
+    + MEMORY_SIZE;
+const STATE_LENGTH: usize = MAGIC.len() + 2 + BODY_LENGTH + 4;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StateError {
+    NotAState,
+    UnsupportedVersion(u16),
+    WrongLength { expected: usize, found: usize },
+    ChecksumMismatch,
+    // The checksum is right but what it holds can't be a machine.
+    Invalid(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StateError::NotAState => write!(f, "not a save state"),
+            StateError::UnsupportedVersion(version) =>
+                write!(f, "save state version {} isn't supported (expected {})", version, VERSION),
+            StateError::WrongLength { expected, found } =>
+                write!(f, "save state is {} bytes, expected {}", found, expected),
+            StateError::ChecksumMismatch => write!(f, "save state is corrupted (bad checksum)"),
+            StateError::Invalid(reason) => write!(f, "save state is invalid: {}", reason),
+        }
+    }
+}
+
+impl Error for StateError {}
+
+impl<H: Host> Env<H> {
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(STATE_LENGTH);
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&VERSION.to_be_bytes());
+        self.write_body(&mut out);
+        let checksum = crc32(&out);
+        out.extend_from_slice(&checksum.to_be_bytes());
+        out
+    }
+
+    // Nothing is changed unless the whole state checks out.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        if data.len() < MAGIC.len() + 2 || &data[..MAGIC.len()] != MAGIC {
+            return Err(StateError::NotAState);
+        }
+        let version = u16::from_be_bytes([data[4], data[5]]);
+        if version != VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+        if data.len() != STATE_LENGTH {
+            return Err(StateError::WrongLength { expected: STATE_LENGTH, found: data.len() });
+        }
+        let (body, checksum) = data.split_at(data.len() - 4);
+        if crc32(body) != u32::from_be_bytes([checksum[0], checksum[1], checksum[2], checksum[3]]) {
+            return Err(StateError::ChecksumMismatch);
+        }
+
+        let body = Body::read(&mut Reader { data: &body[MAGIC.len() + 2..] })?;
+        self.apply_body(body);
+        self.current_instr = (0, 0, 0, 0);
+        self.fading_pixels = [0; 64];
+        self.display_changed = true;
+        Ok(())
+    }
+
+    fn write_body(&self, out: &mut Vec<u8>) {
+        out.push(quirks_to_bits(self.quirks));
+        out.extend_from_slice(&self.program_counter.to_be_bytes());
+        out.extend_from_slice(&self.index_register.to_be_bytes());
+        out.push(self.stack_next_pos);
+        for addr in self.stack.iter() {
+            out.extend_from_slice(&addr.to_be_bytes());
+        }
+        out.extend_from_slice(&self.variable_registers);
+        out.push(self.delay_timer);
+        out.push(self.sound_timer);
+        out.extend_from_slice(&self.cycles_per_frame.to_be_bytes());
+        out.extend_from_slice(&self.frame_cycle.to_be_bytes());
+        out.extend_from_slice(&self.cycle_count.to_be_bytes());
+        out.extend_from_slice(&self.frame_count.to_be_bytes());
+        out.push(self.hires as u8);
+        out.push(self.exited as u8);
+        out.push(self.selected_planes);
+        out.extend_from_slice(&self.rpl_flags);
+        out.extend_from_slice(&self.audio_pattern);
+        out.push(self.pitch);
+        out.push(match self.random_mode {
+            RandomMode::Xorshift => 0,
+            RandomMode::CosmacVip => 1,
+        });
+        out.extend_from_slice(&self.random_state.to_be_bytes());
+        for plane in self.display.iter() {
+            for row in plane.iter() {
+                out.extend_from_slice(&row.to_be_bytes());
+            }
+        }
+        out.extend_from_slice(&self.memory);
+    }
+
+    fn apply_body(&mut self, body: Body) {
+        self.quirks = body.quirks;
+        self.program_counter = body.program_counter;
+        self.index_register = body.index_register;
+        self.stack_next_pos = body.stack_next_pos;
+        self.stack = body.stack;
+        self.variable_registers = body.variable_registers;
+        self.delay_timer = body.delay_timer;
+        self.sound_timer = body.sound_timer;
+        self.cycles_per_frame = body.cycles_per_frame;
+        self.frame_cycle = body.frame_cycle;
+        self.cycle_count = body.cycle_count;
+        self.frame_count = body.frame_count;
+        self.hires = body.hires;
+        self.exited = body.exited;
+        self.selected_planes = body.selected_planes;
+        self.rpl_flags = body.rpl_flags;
+        self.audio_pattern = body.audio_pattern;
+        self.pitch = body.pitch;
+        self.random_mode = body.random_mode;
+        self.random_state = body.random_state;
+        self.display = body.display;
+        self.memory.copy_from_slice(body.memory);
+    }
+}
+
+// The machine as read back from a state, checked before any of it is used
+// so a bad state can't leave the Env half loaded or unable to run.
+struct Body<'a> {
+    quirks: Quirks,
+    program_counter: u16,
+    index_register: u16,
+    stack_next_pos: u8,
+    stack: [u16; 16],
+    variable_registers: [u8; 16],
+    delay_timer: u8,
+    sound_timer: u8,
+    cycles_per_frame: u32,
+    frame_cycle: u32,
+    cycle_count: u64,
+    frame_count: u64,
+    hires: bool,
+    exited: bool,
+    selected_planes: u8,
+    rpl_flags: [u8; 16],
+    audio_pattern: [u8; 16],
+    pitch: u8,
+    random_mode: RandomMode,
+    random_state: u64,
+    display: [[u128; 64]; 2],
+    memory: &'a [u8],
+}
+
+impl<'a> Body<'a> {
+    // The length has already been checked, so this can't run out of data.
+    fn read(r: &mut Reader<'a>) -> Result<Body<'a>, StateError> {
+        let quirks = quirks_from_bits(r.u8());
+        let program_counter = r.u16();
+        let index_register = r.u16();
+        let stack_next_pos = r.u8();
+        let mut stack = [0; 16];
+        for addr in stack.iter_mut() {
+            *addr = r.u16();
+        }
+        let variable_registers = r.array();
+        let delay_timer = r.u8();
+        let sound_timer = r.u8();
+        let cycles_per_frame = r.u32();
+        let frame_cycle = r.u32();
+        let cycle_count = r.u64();
+        let frame_count = r.u64();
+        let hires = r.u8() != 0;
+        let exited = r.u8() != 0;
+        let selected_planes = r.u8();
+        let rpl_flags = r.array();
+        let audio_pattern = r.array();
+        let pitch = r.u8();
+        let random_mode = match r.u8() {
+            0 => RandomMode::Xorshift,
+            1 => RandomMode::CosmacVip,
+            _ => return Err(StateError::Invalid("unknown random mode")),
+        };
+        let random_state = r.u64();
+        let mut display = [[0; 64]; 2];
+        for plane in display.iter_mut() {
+            for row in plane.iter_mut() {
+                *row = r.u128();
+            }
+        }
+        let memory = r.bytes(MEMORY_SIZE);
+
+        if stack_next_pos as usize > stack.len() {
+            return Err(StateError::Invalid("stack pointer past the end of the stack"));
+        }
+        if cycles_per_frame == 0 {
+            return Err(StateError::Invalid("no cycles per frame"));
+        }
+        if frame_cycle >= cycles_per_frame {
+            return Err(StateError::Invalid("cycle past the end of the frame"));
+        }
+        if selected_planes > 0b11 {
+            return Err(StateError::Invalid("selected planes that don't exist"));
+        }
+
+        Ok(Body {
+            quirks,
+            program_counter,
+            index_register,
+            stack_next_pos,
+            stack,
+            variable_registers,
+            delay_timer,
+            sound_timer,
+            cycles_per_frame,
+            frame_cycle,
+            cycle_count,
+            frame_count,
+            hires,
+            exited,
+            selected_planes,
+            rpl_flags,
+            audio_pattern,
+            pitch,
+            random_mode,
+            random_state,
+            display,
+            memory,
+        })
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> &'a [u8] {
+        let (head, tail) = self.data.split_at(n);
+        self.data = tail;
+        head
+    }
+
+    fn array<const N: usize>(&mut self) -> [u8; N] {
+        self.bytes(N).try_into().unwrap()
+    }
+
+    fn u8(&mut self) -> u8 {
+        self.bytes(1)[0]
+    }
+
+    fn u16(&mut self) -> u16 {
+        u16::from_be_bytes(self.bytes(2).try_into().unwrap())
+    }
+
+    fn u32(&mut self) -> u32 {
+        u32::from_be_bytes(self.bytes(4).try_into().unwrap())
+    }
+
+    fn u64(&mut self) -> u64 {
+        u64::from_be_bytes(self.bytes(8).try_into().unwrap())
+    }
+
+    fn u128(&mut self) -> u128 {
+        u128::from_be_bytes(self.bytes(16).try_into().unwrap())
+    }
+}
+
+fn quirks_to_bits(quirks: Quirks) -> u8 {
+    (quirks.shift_uses_vy as u8)
+        | (quirks.jump_uses_vx as u8) << 1
+        | (quirks.load_store_increments_i as u8) << 2
+        | (quirks.vf_reset as u8) << 3
+        | (quirks.clip_sprites as u8) << 4
+        | (quirks.display_wait as u8) << 5
+}
+
+fn quirks_from_bits(bits: u8) -> Quirks {
+    Quirks {
+        shift_uses_vy: bits & 1 != 0,
+        jump_uses_vx: bits & 1 << 1 != 0,
+        load_store_increments_i: bits & 1 << 2 != 0,
+        vf_reset: bits & 1 << 3 != 0,
+        clip_sprites: bits & 1 << 4 != 0,
+        display_wait: bits & 1 << 5 != 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keypad::Keypad;
+
+    // Where some of the fields are, from the start of the state.
+    const STACK_POINTER: usize = 11;
+    const CYCLES_PER_FRAME: usize = 62;
+    const FRAME_CYCLE: usize = 66;
+    const SELECTED_PLANES: usize = 88;
+
+    fn env() -> Env<Keypad> {
+        let mut env = Env::new(Keypad::new(), Quirks::default());
+        env.seed_random(RandomMode::Xorshift, 1);
+        // Draws the font's 0, calls a subroutine and waits for a key there.
+        env.load_into_memory(&[0xA0, 0x50, 0xD0, 0x05, 0x22, 0x08, 0x00, 0x00, 0xF1, 0x0A]).unwrap();
+        // The draw waits for the start of a frame.
+        while env.program_counter() != 0x208 {
+            env.step().unwrap();
+        }
+        env.step().unwrap();
+        env
+    }
+
+    // Changes part of a state and puts the checksum right again.
+    fn patch(state: &mut Vec<u8>, offset: usize, bytes: &[u8]) {
+        state.truncate(state.len() - 4);
+        state[offset..offset + bytes.len()].copy_from_slice(bytes);
+        let checksum = crc32(state);
+        state.extend_from_slice(&checksum.to_be_bytes());
+    }
+
+    fn rejects(state: &[u8], expected: StateError) {
+        let mut other = Env::new(Keypad::new(), Quirks::default());
+        let before = other.save_state();
+        assert_eq!(other.load_state(state), Err(expected));
+        assert_eq!(other.save_state(), before, "a bad state changed the machine");
+    }
+
+    #[test]
+    fn round_trip() {
+        let env = env();
+        let state = env.save_state();
+        assert_eq!(state.len(), STATE_LENGTH);
+
+        let mut other = Env::new(Keypad::new(), Quirks::default());
+        other.load_state(&state).unwrap();
+        assert_eq!(other.save_state(), state);
+        assert_eq!(other.program_counter(), env.program_counter());
+        assert_eq!(other.stack(), env.stack());
+        assert_eq!(other.plane(0), env.plane(0));
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let state = env().save_state();
+        rejects(b"PNG", StateError::NotAState);
+
+        let mut version = state.clone();
+        version[5] = 99;
+        rejects(&version, StateError::UnsupportedVersion(99));
+
+        rejects(&state[..state.len() - 1], StateError::WrongLength { expected: STATE_LENGTH, found: STATE_LENGTH - 1 });
+
+        let mut corrupted = state.clone();
+        corrupted[1000] ^= 1;
+        rejects(&corrupted, StateError::ChecksumMismatch);
+    }
+
+    #[test]
+    fn rejects_impossible_machines() {
+        let state = env().save_state();
+        assert_eq!(state[STACK_POINTER], 1);
+
+        let mut stack = state.clone();
+        patch(&mut stack, STACK_POINTER, &[17]);
+        rejects(&stack, StateError::Invalid("stack pointer past the end of the stack"));
+
+        let mut cycles = state.clone();
+        patch(&mut cycles, CYCLES_PER_FRAME, &0u32.to_be_bytes());
+        rejects(&cycles, StateError::Invalid("no cycles per frame"));
+
+        let mut frame = state.clone();
+        patch(&mut frame, FRAME_CYCLE, &u32::MAX.to_be_bytes());
+        rejects(&frame, StateError::Invalid("cycle past the end of the frame"));
+
+        let mut planes = state.clone();
+        patch(&mut planes, SELECTED_PLANES, &[4]);
+        rejects(&planes, StateError::Invalid("selected planes that don't exist"));
+    }
+}
@@ -0,0 +1,159 @@
+// Runs the small test ROMs in tests/roms with no frontend, for a fixed number
+// of frames, and compares the display they leave behind with the reference
+// image for them in tests/golden. The images are plain PBM so a change shows
+// up in a diff. When a change to the display is meant to happen,
+//
+//   CHIP8_BLESS=1 cargo test -p chip8-core --test golden
+//
+// writes the images afresh instead of comparing against them.
+//
+// The well-known conformance suites (Timendus' chip8-test-suite, corax89's
+// opcode test and the like) aren't run here. They're other people's
+// binaries under their own licenses, which this repository would have to
+// carry, and when one fails the diff is a picture of their UI to decode
+// rather than a few lines of asm to read. The ROMs here are small and each
+// covers the instructions it's named for, quirks and all.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use chip8_core::asm;
+use chip8_core::screenshot::{Format, Screenshot};
+use chip8_core::script::KeyScript;
+use chip8_core::{Env, Keypad, Quirks, RandomMode};
+
+// Only the first entry matters for PBM, which has lit pixels as 1.
+const PALETTE: [[u8; 3]; 4] = [[0, 0, 0], [255, 255, 255], [255, 255, 255], [255, 255, 255]];
+
+struct Case<'a> {
+    rom: &'a str,     // tests/roms/<rom>.asm
+    profile: &'a str, // a --quirks name, which is in the image's name too
+    frames: u64,
+    keys: &'a str, // a key script
+}
+
+impl<'a> Case<'a> {
+    fn new(rom: &'a str, profile: &'a str) -> Case<'a> {
+        Case { rom, profile, frames: 120, keys: "" }
+    }
+}
+
+fn root() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests")
+}
+
+fn run(case: &Case) -> Screenshot {
+    let source = root().join("roms").join(format!("{}.asm", case.rom));
+    let rom = asm::assemble_file(&source).unwrap_or_else(|err| panic!("{}", err));
+    let quirks = Quirks::from_name(case.profile).expect("a known quirks profile");
+    let mut keys = KeyScript::parse(case.keys).unwrap_or_else(|err| panic!("key script {}", err));
+
+    let mut env = Env::new(Keypad::new(), quirks);
+    env.seed_random(RandomMode::default(), 0);
+    env.load_into_memory(&rom).unwrap();
+    while env.frame_count() < case.frames && !env.has_exited() {
+        keys.apply(&mut env);
+        if let Err(err) = env.step() {
+            panic!("{} faulted at {:03X}: {}", case.rom, env.program_counter(), err);
+        }
+    }
+    Screenshot::capture(&env)
+}
+
+// Reads back a plain PBM as written by Screenshot, a row of 0s and 1s a line.
+fn parse_pbm(text: &str) -> (usize, usize, Vec<u8>) {
+    let mut words = text.split_whitespace();
+    assert_eq!(words.next(), Some("P1"), "not a plain PBM");
+    let mut number = || words.next().and_then(|word| word.parse::<usize>().ok()).expect("a PBM size");
+    let (width, height) = (number(), number());
+    let pixels: Vec<u8> = words.map(|word| (word == "1") as u8).collect();
+    assert_eq!(pixels.len(), width * height, "PBM is the wrong size");
+    (width, height, pixels)
+}
+
+// Fails with a map of where the two differ: `+` for pixels lit that
+// shouldn't be, `-` for ones that should be lit and aren't.
+fn check(case: Case) {
+    let actual = run(&case);
+    let pbm = actual.encode(Format::Pbm, &PALETTE, 1);
+    let path = root().join("golden").join(format!("{}-{}.pbm", case.rom, case.profile));
+
+    if env::var_os("CHIP8_BLESS").is_some() {
+        fs::write(&path, &pbm).unwrap();
+        return;
+    }
+    let expected = fs::read_to_string(&path)
+        .unwrap_or_else(|err| panic!("couldn't read {}: {}, bless it first", path.display(), err));
+    if expected.as_bytes() == pbm {
+        return;
+    }
+
+    let (width, height, pixels) = parse_pbm(&expected);
+    if (width, height) != (actual.width(), actual.height()) {
+        panic!(
+            "{}: the display is {}x{}, expected {}x{}",
+            path.display(), actual.width(), actual.height(), width, height,
+        );
+    }
+    let mut map = String::new();
+    let mut different = 0;
+    for y in 0..height {
+        for x in 0..width {
+            let lit = actual.pixel(x, y) != 0;
+            map.push(match (pixels[y * width + x] != 0, lit) {
+                (false, false) => '.',
+                (true, true) => '#',
+                (false, true) => '+',
+                (true, false) => '-',
+            });
+            different += (lit != (pixels[y * width + x] != 0)) as usize;
+        }
+        map.push('\n');
+    }
+    panic!("{}: {} pixels differ\n{}", path.display(), different, map);
+}
+
+#[test]
+fn arithmetic_vip() {
+    check(Case::new("arithmetic", "vip"));
+}
+
+#[test]
+fn arithmetic_schip() {
+    check(Case::new("arithmetic", "schip"));
+}
+
+#[test]
+fn memory_vip() {
+    check(Case::new("memory", "vip"));
+}
+
+#[test]
+fn memory_schip() {
+    check(Case::new("memory", "schip"));
+}
+
+#[test]
+fn display_vip() {
+    check(Case::new("display", "vip"));
+}
+
+#[test]
+fn hires_schip() {
+    check(Case::new("hires", "schip"));
+}
+
+#[test]
+fn keypad_vip() {
+    // 7 for FX0A, then A held down from frame 20.
+    check(Case {
+        keys: "5 press 7\n8 release 7\n20 press a\n",
+        ..Case::new("keypad", "vip")
+    });
+}
+
+#[test]
+fn xochip_xochip() {
+    check(Case::new("xochip", "xochip"));
+}
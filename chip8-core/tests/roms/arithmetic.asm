@@ -0,0 +1,81 @@
+; The ALU instructions, each shown as its result and then VF. The OR, AND
+; and XOR flags and both shifts depend on the quirks.
+    LD VC, 1
+    LD VD, 1
+
+    ; 7XNN wraps around and leaves VF alone
+    LD VF, 0x5A
+    LD V2, 0xFF
+    ADD V2, 0x02
+    LD V3, VF
+    CALL result
+
+    ; 8XY4 with and without a carry
+    LD V2, 0xF0
+    LD V4, 0x20
+    ADD V2, V4
+    LD V3, VF
+    CALL result
+    LD V2, 0x10
+    ADD V2, V4
+    LD V3, VF
+    CALL result
+
+    ; 8XY5, VF is 1 when there's no borrow
+    LD V2, 0x30
+    LD V4, 0x10
+    SUB V2, V4
+    LD V3, VF
+    CALL result
+    LD V2, 0x10
+    LD V4, 0x30
+    SUB V2, V4
+    LD V3, VF
+    CALL result
+
+    ; 8XY7 the other way round
+    LD V2, 0x10
+    LD V4, 0x30
+    SUBN V2, V4
+    LD V3, VF
+    CALL result
+    LD V2, 0x30
+    LD V4, 0x10
+    SUBN V2, V4
+    LD V3, VF
+    CALL result
+
+    ; 8XY1, 8XY2 and 8XY3, which reset VF on the VIP
+    LD V2, 0xC3
+    LD V4, 0x3C
+    LD VF, 1
+    OR V2, V4
+    LD V3, VF
+    CALL result
+    LD V2, 0xF0
+    LD VF, 1
+    AND V2, V4
+    LD V3, VF
+    CALL result
+    LD V2, 0xF0
+    LD VF, 1
+    XOR V2, V4
+    LD V3, VF
+    CALL result
+
+    ; 8XY6 and 8XYE, which shift VY on the VIP and VX everywhere else
+    LD V2, 0x81
+    LD V4, 0x04
+    SHR V2, V4
+    LD V3, VF
+    CALL result
+    LD V2, 0x81
+    LD V4, 0x40
+    SHL V2, V4
+    LD V3, VF
+    CALL result
+
+done:
+    JP done
+
+include "show.asm"
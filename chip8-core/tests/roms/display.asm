@@ -0,0 +1,46 @@
+; Drawing: XOR and the collision flag, the starting position wrapping
+; around, and sprites clipped at the edges.
+    LD VC, 1
+    LD VD, 1
+
+    ; A box drawn, drawn over and drawn again, with VF each time.
+    LD V5, 40
+    LD V6, 2
+    LD I, box
+    DRW V5, V6, 8
+    LD V7, VF
+    DRW V5, V6, 8
+    LD V8, VF
+    LD V6, 4
+    DRW V5, V6, 8
+    LD V9, VF
+    LD V6, 6
+    DRW V5, V6, 8
+    LD VA, VF
+    LD V0, V7
+    CALL digit
+    LD V0, V8
+    CALL digit
+    LD V0, V9
+    CALL digit
+    LD V0, VA
+    CALL digit
+
+    ; Past the right edge the position wraps, so this lands at 2, 20.
+    LD I, box
+    LD V5, 66
+    LD V6, 52
+    DRW V5, V6, 8
+
+    ; Right at the corner it's cut off.
+    LD V5, 60
+    LD V6, 28
+    DRW V5, V6, 8
+
+done:
+    JP done
+
+include "show.asm"
+
+box:
+    db 0xFF, 0x81, 0xBD, 0xA5, 0xA5, 0xBD, 0x81, 0xFF
@@ -0,0 +1,36 @@
+; The SUPER-CHIP display: 128x64, the big font and scrolling.
+    HIGH
+    LD V5, 0
+    LD V6, 2
+    LD V7, 0
+big:
+    LD HF, V7
+    DRW V5, V6, 10
+    ADD V5, 12
+    ADD V7, 1
+    SE V7, 10
+    JP big
+
+    ; 16x16 sprite in the bottom right, then everything moved down 4 and
+    ; right 4.
+    LD V5, 100
+    LD V6, 40
+    LD I, ring
+    DRW V5, V6, 0
+    SCD 4
+    SCR
+
+    ; A number drawn with the small font, which hires doesn't scale up.
+    LD VC, 1
+    LD VD, 50
+    LD V0, 0x12
+    CALL show
+
+done:
+    JP done
+
+include "show.asm"
+
+ring:
+    dw 0x07E0, 0x1818, 0x2004, 0x4002, 0x4002, 0x8001, 0x8001, 0x8001
+    dw 0x8001, 0x8001, 0x8001, 0x4002, 0x4002, 0x2004, 0x1818, 0x07E0
@@ -0,0 +1,31 @@
+; FX0A and the key skips, driven by the key script in the test. Shows the
+; key FX0A got, then whether A is held and B isn't.
+    LD VC, 1
+    LD VD, 1
+
+    LD V0, K
+    CALL digit
+
+    ; Give the script time to press A.
+    LD V5, 30
+    LD DT, V5
+wait:
+    LD V5, DT
+    SE V5, 0
+    JP wait
+
+    LD V5, 0xA
+    LD V0, 0
+    SKNP V5
+    LD V0, 1
+    CALL digit
+    LD V5, 0xB
+    LD V0, 0
+    SKP V5
+    LD V0, 1
+    CALL digit
+
+done:
+    JP done
+
+include "show.asm"
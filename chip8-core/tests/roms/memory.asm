@@ -0,0 +1,55 @@
+; FX33, FX55, FX65 and FX1E. Whether FX55 moves I depends on the quirks.
+    LD VC, 1
+    LD VD, 1
+
+    ; BCD of 254
+    LD V5, 254
+    LD I, buffer
+    LD B, V5
+    LD V2, [I]
+    LD V5, V1
+    LD V6, V2
+    CALL digit
+    LD V0, V5
+    CALL digit
+    LD V0, V6
+    CALL digit
+    CALL newline
+
+    ; Store four registers and load the first back from wherever I is left.
+    ; On the VIP that's past them, where memory is still 0.
+    LD V0, 0x11
+    LD V1, 0x22
+    LD V2, 0x33
+    LD V3, 0x44
+    LD I, buffer
+    LD [I], V3
+    LD V0, [I]
+    CALL show
+
+    ; Store them again from a fresh I and read them back one by one, with
+    ; FX1E stepping I along.
+    LD V0, 0x11
+    LD V1, 0x22
+    LD V2, 0x33
+    LD V3, 0x44
+    LD I, buffer
+    LD [I], V3
+    LD V7, 0
+    LD V8, 1
+next:
+    LD I, buffer
+    ADD I, V7
+    LD V0, [I]
+    CALL show
+    ADD V7, V8
+    SE V7, 4
+    JP next
+
+done:
+    JP done
+
+include "show.asm"
+
+buffer:
+    db 0, 0, 0, 0, 0, 0, 0, 0
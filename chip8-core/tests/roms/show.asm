@@ -0,0 +1,37 @@
+; Shared by the test ROMs for putting numbers on the screen. VC, VD is
+; where the next one goes, and each routine moves VC along after it.
+
+; V0 as two hex digits. Uses V1.
+show:
+    LD V1, V0
+    SHR V1, V1
+    SHR V1, V1
+    SHR V1, V1
+    SHR V1, V1
+    LD F, V1
+    DRW VC, VD, 5
+    ADD VC, 5
+; Just the low digit of V0. Uses V1.
+digit:
+    LD V1, 0x0F
+    AND V1, V0
+    LD F, V1
+    DRW VC, VD, 5
+    ADD VC, 6
+    RET
+
+; An instruction's result in V2 and its flag in V3, three to a row.
+result:
+    LD V0, V2
+    CALL show
+    LD V0, V3
+    CALL digit
+    ADD VC, 3
+    SNE VC, 61
+    CALL newline
+    RET
+
+newline:
+    LD VC, 1
+    ADD VD, 7
+    RET
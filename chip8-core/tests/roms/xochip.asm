@@ -0,0 +1,78 @@
+; XO-CHIP: the second plane, memory past 4K through LD I, LONG, SAVE and
+; LOAD of a range of registers, and scrolling up. Either plane lights a pixel
+; in the golden image, so what the planes did is shown as numbers too.
+FAR = 0x8000
+
+    LD VC, 1
+    LD VD, 3
+
+    ; A block in plane 1, then in plane 2 on top of it, which doesn't
+    ; collide. Drawn in both planes at once it does, and clears both.
+    LD I, block
+    LD V5, 40
+    LD V6, 20
+    PLANE 1
+    DRW V5, V6, 4
+    PLANE 2
+    DRW V5, V6, 4
+    LD V7, VF
+    PLANE 3
+    DRW V5, V6, 4
+    LD V8, VF
+    ; Left in plane 2 alone, a row further down.
+    PLANE 2
+    ADD V6, 1
+    DRW V5, V6, 4
+
+    ; The font is in plane 1.
+    PLANE 1
+    LD V0, V7
+    CALL digit
+    LD V0, V8
+    CALL digit
+    CALL newline
+
+    ; Two bytes out at 0x8000 and back.
+    LD V0, 0xAB
+    LD V1, 0xCD
+    LD I, LONG FAR
+    LD [I], V1
+    LD V0, 0
+    LD V1, 0
+    LD I, LONG FAR
+    LD V1, [I]
+    LD V7, V1
+    CALL show
+    LD V0, V7
+    CALL show
+    CALL newline
+
+    ; SAVE leaves I alone. LOAD backwards reverses them.
+    LD V2, 0x12
+    LD V3, 0x34
+    LD V4, 0x56
+    SAVE V2, V4
+    LD V2, 0
+    LD V3, 0
+    LD V4, 0
+    LOAD V4, V2
+    LD V0, V4
+    CALL show
+    LD V0, V3
+    CALL show
+    LD V0, V2
+    CALL show
+
+    ; Everything, in both planes, up 2.
+    PLANE 3
+    SCU 2
+
+done:
+    JP done
+
+include "show.asm"
+
+; Plane 1's rows, then plane 2's.
+block:
+    db 0xF0, 0xF0, 0xF0, 0xF0
+    db 0xF0, 0xF0, 0xF0, 0xF0
@@ -0,0 +1,37 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use chip8_core::{asm, octo};
+
+// chip8 asm <source> [-o <rom>]
+// Assembles to a ROM next to the source unless told otherwise, e.g. pong.asm -> pong.ch8
+// Octo source (.8o) goes through the Octo compiler instead.
+pub fn run(args: &[String]) {
+    let (source, out) = match args {
+        [source] => (source, Path::new(source).with_extension("ch8")),
+        [source, flag, out] if flag == "-o" => (source, PathBuf::from(out)),
+        _ => {
+            eprintln!("usage: chip8 asm <source> [-o <rom>]");
+            process::exit(2);
+        },
+    };
+    let path = Path::new(source);
+    let result = if path.extension().is_some_and(|ext| ext == "8o") {
+        octo::compile_file(path)
+    } else {
+        asm::assemble_file(path)
+    };
+    let rom = match result {
+        Ok(rom) => rom,
+        Err(err) => {
+            eprintln!("chip8: {}", err);
+            process::exit(1);
+        },
+    };
+    if let Err(err) = fs::write(&out, &rom) {
+        eprintln!("chip8: couldn't write {}: {}", out.display(), err);
+        process::exit(1);
+    }
+    eprintln!("chip8: wrote {} bytes to {}", rom.len(), out.display());
+}
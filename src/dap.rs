@@ -0,0 +1,677 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread;
+
+use chip8_core::asm::{self, SourceMap};
+use chip8_core::debug::{Debugger, Stop};
+use chip8_core::{octo, Chip8Error, Env, Instruction, Keypad};
+
+use crate::debugger::{line_at, Session};
+
+mod json;
+
+use json::{object, Json};
+
+// The variablesReference of each scope.
+const REGISTERS: i64 = 1;
+const TIMERS: i64 = 2;
+
+// A debug adapter, so editors that speak the Debug Adapter Protocol can run
+// and debug a ROM. The editor sends the ROM (or its assembler or Octo source)
+// in its launch request, and breakpoints go on source lines through the
+// assembler's source map, or on addresses from the disassembly view. There's
+// one thread, and a stack frame for the PC and each call on the stack.
+pub struct Dap {
+    requests: Receiver<Json>,
+    out: Box<dyn Write>,
+    seq: i64,
+    debugger: Debugger,
+    source_map: SourceMap,
+    // The files in the source map, with their canonical paths to compare
+    // with the editor's.
+    sources: Vec<(String, PathBuf)>,
+    line_breakpoints: HashMap<String, Vec<u16>>, // by file in the source map
+    instruction_breakpoints: Vec<u16>,
+    stop_on_entry: bool,
+    // Events to send once the request that caused them has its response.
+    events: Vec<(&'static str, Json)>,
+    exited: bool,
+    quit: bool,
+}
+
+impl Dap {
+    // Talks to the editor over stdin and stdout, or the first connection on
+    // a local port.
+    pub fn connect(transport: &str) -> io::Result<Dap> {
+        let (send, requests) = mpsc::channel();
+        let out: Box<dyn Write> = if transport == "stdio" {
+            thread::spawn(move || read_messages(io::stdin(), send));
+            Box::new(io::stdout())
+        } else {
+            let port: u16 = transport.parse()
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "--dap needs stdio or a port"))?;
+            let listener = TcpListener::bind(("127.0.0.1", port))?;
+            eprintln!("chip8: waiting for the editor on localhost:{}", port);
+            let (stream, _) = listener.accept()?;
+            let input = stream.try_clone()?;
+            thread::spawn(move || read_messages(input, send));
+            Box::new(stream)
+        };
+        let mut debugger = Debugger::new();
+        debugger.pause();
+        debugger.take_stop();
+        Ok(Dap {
+            requests,
+            out,
+            seq: 0,
+            debugger,
+            source_map: SourceMap::default(),
+            sources: Vec::new(),
+            line_breakpoints: HashMap::new(),
+            instruction_breakpoints: Vec::new(),
+            stop_on_entry: false,
+            events: Vec::new(),
+            exited: false,
+            quit: false,
+        })
+    }
+
+    // Answers the editor until it sends a launch request, and returns the
+    // path and ROM from it. None if the editor gave up first.
+    pub fn launch(&mut self) -> Option<(String, Vec<u8>)> {
+        loop {
+            let request = self.requests.recv().ok()?;
+            let result = match request.get("command").as_str().unwrap_or("") {
+                "initialize" => Ok(object([
+                    ("supportsConfigurationDoneRequest", true.into()),
+                    ("supportsInstructionBreakpoints", true.into()),
+                    ("supportsDisassembleRequest", true.into()),
+                    ("supportsReadMemoryRequest", true.into()),
+                    ("supportsWriteMemoryRequest", true.into()),
+                    ("supportsSetVariable", true.into()),
+                    ("supportsTerminateRequest", true.into()),
+                ])),
+                "launch" => match self.load(request.get("arguments")) {
+                    Ok(rom) => {
+                        self.respond(&request, Ok(object([])));
+                        // Breakpoints can only be placed once there's a source map.
+                        self.event("initialized", object([]));
+                        let program = request.get("arguments").get("program").as_str().unwrap_or("");
+                        return Some((program.to_string(), rom));
+                    },
+                    Err(message) => Err(message),
+                },
+                "disconnect" => {
+                    self.respond(&request, Ok(object([])));
+                    return None;
+                },
+                command => Err(format!("{} before launch", command)),
+            };
+            self.respond(&request, result);
+        }
+    }
+
+    fn load(&mut self, arguments: &Json) -> Result<Vec<u8>, String> {
+        let program = arguments.get("program").as_str().ok_or("launch needs a program")?;
+        self.stop_on_entry = arguments.get("stopOnEntry").as_bool().unwrap_or(false);
+        let path = fs::canonicalize(program).map_err(|err| format!("couldn't open {}: {}", program, err))?;
+        let (rom, source_map) = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("8o") => octo::compile_file_with_map(&path).map_err(|err| err.to_string())?,
+            Some("asm" | "s") => asm::assemble_file_with_map(&path).map_err(|err| err.to_string())?,
+            _ => {
+                let rom = fs::read(&path).map_err(|err| format!("couldn't read {}: {}", program, err))?;
+                (rom, SourceMap::default())
+            },
+        };
+        for line in source_map.lines() {
+            if !self.sources.iter().any(|(file, _)| *file == line.file) {
+                let canonical = fs::canonicalize(&line.file).unwrap_or_else(|_| PathBuf::from(&line.file));
+                self.sources.push((line.file.clone(), canonical));
+            }
+        }
+        self.source_map = source_map;
+        Ok(rom)
+    }
+
+    fn write(&mut self, message: Json) {
+        self.seq += 1;
+        let mut fields = vec![("seq".to_string(), Json::from(self.seq))];
+        if let Json::Object(rest) = message {
+            fields.extend(rest);
+        }
+        let body = Json::Object(fields).to_string();
+        let written = write!(self.out, "Content-Length: {}\r\n\r\n{}", body.len(), body)
+            .and_then(|_| self.out.flush());
+        if written.is_err() {
+            self.quit = true;
+        }
+    }
+
+    fn respond(&mut self, request: &Json, result: Result<Json, String>) {
+        let mut response = object([
+            ("type", "response".into()),
+            ("request_seq", request.get("seq").clone()),
+            ("success", result.is_ok().into()),
+            ("command", request.get("command").clone()),
+        ]);
+        match result {
+            Ok(body) => response.push("body", body),
+            Err(message) => response.push("message", message),
+        }
+        self.write(response);
+    }
+
+    fn event(&mut self, event: &str, body: Json) {
+        self.write(object([
+            ("type", "event".into()),
+            ("event", event.into()),
+            ("body", body),
+        ]));
+    }
+
+    fn handle(&mut self, request: &Json, env: &mut Env<Keypad>) -> Result<Json, String> {
+        let arguments = request.get("arguments");
+        match request.get("command").as_str().unwrap_or("") {
+            "setBreakpoints" => {
+                let path = arguments.get("source").get("path").as_str().unwrap_or("");
+                let file = self.source_file(path);
+                let mut addresses = Vec::new();
+                let mut breakpoints = Vec::new();
+                for breakpoint in arguments.get("breakpoints").as_array() {
+                    let line = breakpoint.get("line").as_i64().unwrap_or(0).max(0) as usize;
+                    let source = file.as_ref().and_then(|file| self.source_map.address_of(file, line));
+                    breakpoints.push(match source {
+                        Some(source) => {
+                            addresses.push(source.address);
+                            object([
+                                ("verified", true.into()),
+                                ("line", source.line.into()),
+                                ("instructionReference", reference(source.address).into()),
+                            ])
+                        },
+                        None => object([
+                            ("verified", false.into()),
+                            ("line", line.into()),
+                            ("message", "no code on or after this line".into()),
+                        ]),
+                    });
+                }
+                if let Some(file) = file {
+                    self.line_breakpoints.insert(file, addresses);
+                }
+                self.sync_breakpoints();
+                Ok(object([("breakpoints", breakpoints.into())]))
+            },
+            "setInstructionBreakpoints" => {
+                let mut breakpoints = Vec::new();
+                self.instruction_breakpoints.clear();
+                for breakpoint in arguments.get("breakpoints").as_array() {
+                    let address = breakpoint.get("instructionReference").as_str()
+                        .and_then(parse_reference)
+                        .map(|address| address + breakpoint.get("offset").as_i64().unwrap_or(0))
+                        .filter(|address| (0..0x10000).contains(address));
+                    breakpoints.push(match address {
+                        Some(address) => {
+                            self.instruction_breakpoints.push(address as u16);
+                            object([
+                                ("verified", true.into()),
+                                ("instructionReference", reference(address as u16).into()),
+                            ])
+                        },
+                        None => object([("verified", false.into())]),
+                    });
+                }
+                self.sync_breakpoints();
+                Ok(object([("breakpoints", breakpoints.into())]))
+            },
+            "setExceptionBreakpoints" => Ok(object([])),
+            "configurationDone" => {
+                if self.stop_on_entry {
+                    let event = stopped("entry", None);
+                    self.events.push(event);
+                } else {
+                    self.debugger.resume();
+                }
+                Ok(object([]))
+            },
+            "threads" => Ok(object([
+                ("threads", vec![object([("id", Json::from(1usize)), ("name", "chip8".into())])].into()),
+            ])),
+            "stackTrace" => {
+                let mut frames = vec![self.frame(env, 0, env.program_counter())];
+                for (i, &call) in env.stack().iter().rev().enumerate() {
+                    frames.push(self.frame(env, i + 1, call));
+                }
+                let total = frames.len();
+                Ok(object([("stackFrames", frames.into()), ("totalFrames", total.into())]))
+            },
+            "scopes" => Ok(object([("scopes", vec![
+                object([("name", "Registers".into()), ("variablesReference", REGISTERS.into()), ("expensive", false.into())]),
+                object([("name", "Timers".into()), ("variablesReference", TIMERS.into()), ("expensive", false.into())]),
+            ].into())])),
+            "variables" => {
+                let names: &[&str] = match arguments.get("variablesReference").as_i64() {
+                    Some(REGISTERS) => &[
+                        "V0", "V1", "V2", "V3", "V4", "V5", "V6", "V7",
+                        "V8", "V9", "VA", "VB", "VC", "VD", "VE", "VF", "I", "PC", "SP",
+                    ],
+                    Some(TIMERS) => &["DT", "ST"],
+                    _ => &[],
+                };
+                let variables: Vec<Json> = names.iter().map(|&name| variable(env, name)).collect();
+                Ok(object([("variables", variables.into())]))
+            },
+            "setVariable" => {
+                let name = arguments.get("name").as_str().unwrap_or("");
+                let value = arguments.get("value").as_str()
+                    .and_then(parse_number)
+                    .ok_or("the value should be a number, in hex with 0x")?;
+                match name {
+                    "I" => env.set_index_register(value as u16),
+                    "PC" => env.set_program_counter(value as u16),
+                    "DT" => env.set_delay_timer(value as u8),
+                    "ST" => env.set_sound_timer(value as u8),
+                    _ => match name.strip_prefix('V').and_then(|x| u8::from_str_radix(x, 16).ok()) {
+                        Some(x) if x < 16 => env.registers_mut()[x as usize] = value as u8,
+                        _ => return Err(format!("{} can't be changed", name)),
+                    },
+                }
+                Ok(object([("value", variable(env, name).get("value").clone())]))
+            },
+            "continue" => {
+                self.debugger.resume();
+                Ok(object([("allThreadsContinued", true.into())]))
+            },
+            "next" => {
+                // Over calls, by running to the instruction after.
+                let pc = env.program_counter();
+                match line_at(env, pc).instruction {
+                    Some(Instruction::Call(_)) => self.debugger.run_to(pc.wrapping_add(2)),
+                    _ => self.step(env),
+                }
+                Ok(object([]))
+            },
+            "stepIn" => {
+                self.step(env);
+                Ok(object([]))
+            },
+            "stepOut" => {
+                match env.stack().last() {
+                    Some(&call) => self.debugger.run_to(call.wrapping_add(2)),
+                    None => self.step(env),
+                }
+                Ok(object([]))
+            },
+            "pause" => {
+                self.debugger.pause();
+                self.debugger.take_stop();
+                let event = stopped("pause", None);
+                self.events.push(event);
+                Ok(object([]))
+            },
+            "readMemory" => {
+                let address = memory_address(arguments)?;
+                let memory = env.memory();
+                let count = arguments.get("count").as_i64().unwrap_or(0).max(0) as usize;
+                let start = address.min(memory.len());
+                let end = address.saturating_add(count).min(memory.len());
+                Ok(object([
+                    ("address", reference(start as u16).into()),
+                    ("data", base64_encode(&memory[start..end]).into()),
+                    ("unreadableBytes", (count - (end - start)).into()),
+                ]))
+            },
+            "writeMemory" => {
+                let address = memory_address(arguments)?;
+                let data = arguments.get("data").as_str()
+                    .and_then(base64_decode)
+                    .ok_or("the data isn't base64")?;
+                let memory = env.memory_mut();
+                if address.checked_add(data.len()).is_none_or(|end| end > memory.len()) {
+                    return Err("that's past the end of memory".to_string());
+                }
+                memory[address..address + data.len()].copy_from_slice(&data);
+                Ok(object([("bytesWritten", data.len().into())]))
+            },
+            "disassemble" => {
+                let start = offset_reference(arguments)?;
+                let count = arguments.get("instructionCount").as_i64().unwrap_or(0).max(0);
+                // Instructions are mostly 2 bytes, which is as close as
+                // counting backwards gets.
+                let mut address = arguments.get("instructionOffset").as_i64().unwrap_or(0)
+                    .checked_mul(2)
+                    .and_then(|offset| start.checked_add(offset))
+                    .ok_or("the instruction offset is out of range")?;
+                let mut instructions = Vec::new();
+                for _ in 0..count {
+                    if !(0..env.memory().len() as i64).contains(&address) {
+                        instructions.push(object([
+                            ("address", format!("0x{:X}", address.max(0)).into()),
+                            ("instruction", "".into()),
+                            ("presentationHint", "invalid".into()),
+                        ]));
+                        address += 2;
+                        continue;
+                    }
+                    let line = line_at(env, address as u16);
+                    let bytes: Vec<String> = line.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+                    let mut instruction = object([
+                        ("address", reference(line.address).into()),
+                        ("instructionBytes", bytes.join(" ").into()),
+                        ("instruction", line.source().into()),
+                    ]);
+                    self.add_source(&mut instruction, line.address);
+                    instructions.push(instruction);
+                    address += line.bytes.len() as i64;
+                }
+                Ok(object([("instructions", instructions.into())]))
+            },
+            "terminate" => {
+                self.quit = true;
+                self.events.push(("terminated", object([])));
+                Ok(object([]))
+            },
+            "disconnect" => {
+                self.quit = true;
+                Ok(object([]))
+            },
+            "initialize" | "launch" => Err("the ROM is already running".to_string()),
+            command => Err(format!("{} isn't supported", command)),
+        }
+    }
+
+    fn step(&mut self, env: &mut Env<Keypad>) {
+        self.debugger.pause();
+        self.debugger.take_stop();
+        let event = match self.debugger.step(env) {
+            Err(err) => stopped("exception", Some(err.to_string())),
+            Ok(()) => stopped("step", None),
+        };
+        self.events.push(event);
+    }
+
+    // Both kinds of breakpoint end up in the one Debugger.
+    fn sync_breakpoints(&mut self) {
+        let old: Vec<u16> = self.debugger.breakpoints().collect();
+        for address in old {
+            self.debugger.remove_breakpoint(address);
+        }
+        let new = self.line_breakpoints.values().flatten().chain(self.instruction_breakpoints.iter());
+        for &address in new {
+            self.debugger.add_breakpoint(address);
+        }
+    }
+
+    // The name a path from the editor has in the source map.
+    fn source_file(&self, path: &str) -> Option<String> {
+        let canonical = fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path));
+        self.sources.iter()
+            .find(|(_, source)| *source == canonical)
+            .map(|(file, _)| file.clone())
+    }
+
+    // Adds the source, line and column of the instruction at address, if
+    // it's in the source map. Returns whether it was.
+    fn add_source(&self, json: &mut Json, address: u16) -> bool {
+        let line = match self.source_map.line_at(address) {
+            Some(line) => line,
+            None => return false,
+        };
+        let path = match self.sources.iter().find(|(file, _)| *file == line.file) {
+            Some((_, path)) => path,
+            None => return false,
+        };
+        let name = path.file_name().map_or(String::new(), |name| name.to_string_lossy().into_owned());
+        json.push("source", object([("name", name.into()), ("path", path.display().to_string().into())]));
+        json.push("line", line.line);
+        json.push("column", 1usize);
+        true
+    }
+
+    fn frame(&self, env: &Env<Keypad>, id: usize, address: u16) -> Json {
+        let line = line_at(env, address);
+        let mut frame = object([
+            ("id", id.into()),
+            ("name", format!("0x{:03X} {}", address, line.source()).into()),
+            ("instructionPointerReference", reference(address).into()),
+        ]);
+        if !self.add_source(&mut frame, address) {
+            frame.push("line", 0usize);
+            frame.push("column", 0usize);
+        }
+        frame
+    }
+}
+
+impl Session for Dap {
+    fn poll(&mut self, env: &mut Env<Keypad>) {
+        loop {
+            let request = match self.requests.try_recv() {
+                Ok(request) => request,
+                Err(TryRecvError::Empty) => return,
+                // The editor went away.
+                Err(TryRecvError::Disconnected) => {
+                    self.quit = true;
+                    return;
+                },
+            };
+            if request.get("type").as_str() != Some("request") {
+                continue;
+            }
+            let result = self.handle(&request, env);
+            self.respond(&request, result);
+            for (event, body) in std::mem::take(&mut self.events) {
+                self.event(event, body);
+            }
+            if self.quit {
+                return;
+            }
+        }
+    }
+
+    fn run_frame(&mut self, env: &mut Env<Keypad>) -> Result<(), Chip8Error> {
+        let result = self.debugger.run_frame(env);
+        if let Err(err) = &result {
+            let (event, body) = stopped("exception", Some(err.to_string()));
+            self.event(event, body);
+        } else if env.has_exited() {
+            if !self.exited {
+                self.exited = true;
+                self.event("exited", object([("exitCode", Json::from(0usize))]));
+                self.event("terminated", object([]));
+            }
+        } else if let Some(stop) = self.debugger.take_stop() {
+            let reason = match stop {
+                Stop::Breakpoint(pc) if self.instruction_breakpoints.contains(&pc) => "instruction breakpoint",
+                Stop::Breakpoint(_) => "breakpoint",
+                Stop::RunTo(_) => "step",
+                Stop::Paused => "pause",
+                Stop::Watchpoint { .. } | Stop::Condition(_) => "data breakpoint",
+            };
+            let (event, body) = stopped(reason, None);
+            self.event(event, body);
+        }
+        result
+    }
+
+    fn wants_quit(&self) -> bool {
+        self.quit
+    }
+}
+
+// The body of a stopped event.
+fn stopped(reason: &str, text: Option<String>) -> (&'static str, Json) {
+    let mut body = object([
+        ("reason", reason.into()),
+        ("threadId", Json::from(1usize)),
+        ("allThreadsStopped", true.into()),
+    ]);
+    if let Some(text) = text {
+        body.push("text", text);
+    }
+    ("stopped", body)
+}
+
+// Messages are JSON with an HTTP-like Content-Length header.
+fn read_messages(input: impl Read, send: Sender<Json>) {
+    let mut input = BufReader::new(input);
+    loop {
+        let mut length = None;
+        loop {
+            let mut header = String::new();
+            match input.read_line(&mut header) {
+                Ok(0) | Err(_) => return,
+                Ok(_) => {},
+            }
+            let header = header.trim();
+            if header.is_empty() {
+                break;
+            }
+            if let Some(value) = header.strip_prefix("Content-Length:") {
+                length = value.trim().parse().ok();
+            }
+        }
+        let mut body = vec![0; match length {
+            Some(length) => length,
+            None => continue,
+        }];
+        if input.read_exact(&mut body).is_err() {
+            return;
+        }
+        match Json::parse(&String::from_utf8_lossy(&body)) {
+            Ok(message) => if send.send(message).is_err() {
+                return;
+            },
+            Err(err) => eprintln!("chip8: bad message from the editor: {}", err),
+        }
+    }
+}
+
+fn variable(env: &Env<Keypad>, name: &str) -> Json {
+    let (value, memory) = match name {
+        "I" => (format!("0x{:04X}", env.index_register()), true),
+        "PC" => (format!("0x{:03X}", env.program_counter()), true),
+        "SP" => (env.stack().len().to_string(), false),
+        "DT" => (env.delay_timer().to_string(), false),
+        "ST" => (env.sound_timer().to_string(), false),
+        _ => {
+            let x = usize::from_str_radix(&name[1..], 16).unwrap_or(0);
+            (format!("0x{:02X}", env.registers()[x]), false)
+        },
+    };
+    let mut variable = object([
+        ("name", name.into()),
+        ("value", value.clone().into()),
+        ("variablesReference", Json::from(0usize)),
+    ]);
+    // Lets the editor open a memory view where I and the PC point.
+    if memory {
+        variable.push("memoryReference", value);
+    }
+    variable
+}
+
+fn reference(address: u16) -> String {
+    format!("0x{:03X}", address)
+}
+
+// Memory references are addresses, in hex with 0x.
+fn parse_reference(text: &str) -> Option<i64> {
+    parse_number(text).map(|n| n as i64)
+}
+
+fn parse_number(text: &str) -> Option<u32> {
+    let text = text.trim();
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+// The memoryReference plus the offset, if any.
+fn offset_reference(arguments: &Json) -> Result<i64, String> {
+    arguments.get("memoryReference").as_str()
+        .and_then(parse_reference)
+        .ok_or("that isn't an address")?
+        .checked_add(arguments.get("offset").as_i64().unwrap_or(0))
+        .ok_or_else(|| "the offset is out of range".to_string())
+}
+
+fn memory_address(arguments: &Json) -> Result<usize, String> {
+    let address = offset_reference(arguments)?;
+    usize::try_from(address).map_err(|_| "that's before the start of memory".to_string())
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = (chunk[0] as u32) << 16
+            | (*chunk.get(1).unwrap_or(&0) as u32) << 8
+            | *chunk.get(2).unwrap_or(&0) as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(text.len() / 4 * 3);
+    let mut bits = 0u32;
+    let mut count = 0;
+    for c in text.bytes().filter(|&c| c != b'=') {
+        let value = BASE64.iter().position(|&b| b == c)? as u32;
+        bits = bits << 6 | value;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            out.push((bits >> count) as u8);
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64_round_trips() {
+        for (bytes, text) in [
+            (&b""[..], ""),
+            (b"f", "Zg=="),
+            (b"fo", "Zm8="),
+            (b"foo", "Zm9v"),
+            (b"foob", "Zm9vYg=="),
+            (b"\x00\xFF\x3E\x3F", "AP8+Pw=="),
+        ] {
+            assert_eq!(base64_encode(bytes), text);
+            assert_eq!(base64_decode(text).as_deref(), Some(bytes));
+        }
+        let all: Vec<u8> = (0..=255).collect();
+        assert_eq!(base64_decode(&base64_encode(&all)), Some(all));
+        assert_eq!(base64_decode("Zm9v!"), None);
+    }
+
+    #[test]
+    fn memory_references_and_offsets() {
+        let arguments = |text: &str| Json::parse(text).unwrap();
+        assert_eq!(memory_address(&arguments(r#"{"memoryReference":"0x200"}"#)), Ok(0x200));
+        assert_eq!(memory_address(&arguments(r#"{"memoryReference":"0x200","offset":-2}"#)), Ok(0x1FE));
+        assert_eq!(memory_address(&arguments(r#"{"memoryReference":"512","offset":1}"#)), Ok(0x201));
+        assert!(memory_address(&arguments(r#"{"memoryReference":"0x1","offset":-2}"#)).is_err());
+        assert!(memory_address(&arguments(r#"{"memoryReference":"pc"}"#)).is_err());
+        assert_eq!(offset_reference(&arguments(r#"{"memoryReference":"0xFFFFFFFF","offset":8e15}"#)),
+            Ok(0xFFFF_FFFF + 8_000_000_000_000_000));
+    }
+}
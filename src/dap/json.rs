@@ -0,0 +1,390 @@
+use std::fmt;
+
+// Just enough JSON for the debug adapter protocol. Objects keep their keys
+// in order, and numbers are all f64 like in JavaScript.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+static NULL: Json = Json::Null;
+
+pub fn object<const N: usize>(fields: [(&str, Json); N]) -> Json {
+    Json::Object(fields.into_iter().map(|(key, value)| (key.to_string(), value)).collect())
+}
+
+impl Json {
+    pub fn parse(text: &str) -> Result<Json, String> {
+        let mut parser = Parser { bytes: text.as_bytes(), pos: 0 };
+        let value = parser.value(0)?;
+        parser.skip_space();
+        if parser.pos < parser.bytes.len() {
+            return Err(parser.error("trailing characters"));
+        }
+        Ok(value)
+    }
+
+    // Adds a field to an object, does nothing to anything else.
+    pub fn push(&mut self, key: &str, value: impl Into<Json>) {
+        if let Json::Object(fields) = self {
+            fields.push((key.to_string(), value.into()));
+        }
+    }
+
+    // Null when it isn't an object or doesn't have the key, so lookups can
+    // be chained.
+    pub fn get(&self, key: &str) -> &Json {
+        match self {
+            Json::Object(fields) => fields.iter()
+                .find(|(name, _)| name == key)
+                .map_or(&NULL, |(_, value)| value),
+            _ => &NULL,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Json::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    // Only whole numbers.
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Json::Number(n) if n.fract() == 0.0 && n.abs() < 9e15 => Some(*n as i64),
+            _ => None,
+        }
+    }
+
+    // Empty when it isn't an array.
+    pub fn as_array(&self) -> &[Json] {
+        match self {
+            Json::Array(items) => items,
+            _ => &[],
+        }
+    }
+}
+
+impl From<bool> for Json {
+    fn from(b: bool) -> Json {
+        Json::Bool(b)
+    }
+}
+
+impl From<i64> for Json {
+    fn from(n: i64) -> Json {
+        Json::Number(n as f64)
+    }
+}
+
+impl From<usize> for Json {
+    fn from(n: usize) -> Json {
+        Json::Number(n as f64)
+    }
+}
+
+impl From<&str> for Json {
+    fn from(s: &str) -> Json {
+        Json::String(s.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(s: String) -> Json {
+        Json::String(s)
+    }
+}
+
+impl From<Vec<Json>> for Json {
+    fn from(items: Vec<Json>) -> Json {
+        Json::Array(items)
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) if !n.is_finite() => write!(f, "null"),
+            Json::Number(n) if n.fract() == 0.0 && n.abs() < 9e15 => write!(f, "{}", *n as i64),
+            Json::Number(n) => write!(f, "{}", n),
+            Json::String(s) => write_string(f, s),
+            Json::Array(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            },
+            Json::Object(fields) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            },
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+const MAX_DEPTH: usize = 128;
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, message: &str) -> String {
+        format!("{} at byte {}", message, self.pos)
+    }
+
+    fn skip_space(&mut self) {
+        while self.bytes.get(self.pos).is_some_and(|b| b.is_ascii_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    fn eat(&mut self, byte: u8) -> bool {
+        self.skip_space();
+        if self.bytes.get(self.pos) == Some(&byte) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn literal(&mut self, word: &str, value: Json) -> Result<Json, String> {
+        if self.bytes[self.pos..].starts_with(word.as_bytes()) {
+            self.pos += word.len();
+            Ok(value)
+        } else {
+            Err(self.error("unexpected character"))
+        }
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Json, String> {
+        if depth > MAX_DEPTH {
+            return Err(self.error("nested too deeply"));
+        }
+        self.skip_space();
+        match self.bytes.get(self.pos) {
+            None => Err(self.error("unexpected end")),
+            Some(b'n') => self.literal("null", Json::Null),
+            Some(b't') => self.literal("true", Json::Bool(true)),
+            Some(b'f') => self.literal("false", Json::Bool(false)),
+            Some(b'"') => self.string().map(Json::String),
+            Some(b'[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                if self.eat(b']') {
+                    return Ok(Json::Array(items));
+                }
+                loop {
+                    items.push(self.value(depth + 1)?);
+                    if self.eat(b']') {
+                        return Ok(Json::Array(items));
+                    }
+                    if !self.eat(b',') {
+                        return Err(self.error("expected , or ]"));
+                    }
+                }
+            },
+            Some(b'{') => {
+                self.pos += 1;
+                let mut fields = Vec::new();
+                if self.eat(b'}') {
+                    return Ok(Json::Object(fields));
+                }
+                loop {
+                    self.skip_space();
+                    if self.bytes.get(self.pos) != Some(&b'"') {
+                        return Err(self.error("expected a key"));
+                    }
+                    let key = self.string()?;
+                    if !self.eat(b':') {
+                        return Err(self.error("expected :"));
+                    }
+                    fields.push((key, self.value(depth + 1)?));
+                    if self.eat(b'}') {
+                        return Ok(Json::Object(fields));
+                    }
+                    if !self.eat(b',') {
+                        return Err(self.error("expected , or }"));
+                    }
+                }
+            },
+            Some(_) => {
+                let start = self.pos;
+                while self.bytes.get(self.pos).is_some_and(|b| b"+-.eE0123456789".contains(b)) {
+                    self.pos += 1;
+                }
+                let text = std::str::from_utf8(&self.bytes[start..self.pos]).unwrap_or("");
+                text.parse().map(Json::Number).map_err(|_| {
+                    self.pos = start;
+                    self.error("unexpected character")
+                })
+            },
+        }
+    }
+
+    // From the opening quote.
+    fn string(&mut self) -> Result<String, String> {
+        self.pos += 1;
+        let mut out = Vec::new();
+        loop {
+            let byte = *self.bytes.get(self.pos).ok_or_else(|| self.error("unterminated string"))?;
+            self.pos += 1;
+            match byte {
+                b'"' => break,
+                b'\\' => {
+                    let escape = *self.bytes.get(self.pos).ok_or_else(|| self.error("unterminated string"))?;
+                    self.pos += 1;
+                    let c = match escape {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            let high = self.hex4()?;
+                            // Anything outside the BMP comes as a surrogate pair.
+                            // Half of one becomes U+FFFD, leaving whatever
+                            // followed it alone.
+                            let mut code = high;
+                            if (0xD800..0xDC00).contains(&high) && self.bytes[self.pos..].starts_with(b"\\u") {
+                                let after_high = self.pos;
+                                self.pos += 2;
+                                let low = self.hex4()?;
+                                if (0xDC00..0xE000).contains(&low) {
+                                    code = 0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00);
+                                } else {
+                                    self.pos = after_high;
+                                }
+                            }
+                            char::from_u32(code).unwrap_or('\u{FFFD}')
+                        },
+                        _ => return Err(self.error("bad escape")),
+                    };
+                    let mut buffer = [0; 4];
+                    out.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+                },
+                byte => out.push(byte),
+            }
+        }
+        // The input was a &str, so this only fails on a malformed escape.
+        String::from_utf8(out).map_err(|_| self.error("invalid UTF-8"))
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let digits = self.bytes.get(self.pos..self.pos + 4)
+            .and_then(|digits| std::str::from_utf8(digits).ok())
+            .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+            .ok_or_else(|| self.error("bad \\u escape"))?;
+        self.pos += 4;
+        Ok(digits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(text: &str) -> String {
+        Json::parse(text).unwrap().to_string()
+    }
+
+    #[test]
+    fn escapes() {
+        let json = Json::parse(r#""a\"b\\c\/d\b\f\n\r\t\u0041\u00e9""#).unwrap();
+        assert_eq!(json, Json::String("a\"b\\c/d\u{8}\u{c}\n\r\tAé".to_string()));
+        assert_eq!(json.to_string(), r#""a\"b\\c/d\u0008\u000c\n\r\tAé""#);
+        assert_eq!(Json::from("\u{1}é😀").to_string(), "\"\\u0001é😀\"");
+        assert!(Json::parse(r#""\x""#).is_err());
+        assert!(Json::parse(r#""\u12""#).is_err());
+        assert!(Json::parse(r#""abc"#).is_err());
+    }
+
+    #[test]
+    fn surrogates() {
+        assert_eq!(Json::parse(r#""\ud83d\ude00""#).unwrap(), Json::from("😀"));
+        assert_eq!(Json::parse(r#""\uD83D\uDE00!""#).unwrap(), Json::from("😀!"));
+        // Half a pair doesn't take anything else with it.
+        assert_eq!(Json::parse(r#""\ud83d""#).unwrap(), Json::from("\u{FFFD}"));
+        assert_eq!(Json::parse(r#""\ud83dA""#).unwrap(), Json::from("\u{FFFD}A"));
+        assert_eq!(Json::parse(r#""\ud83d\u0041""#).unwrap(), Json::from("\u{FFFD}A"));
+        assert_eq!(Json::parse(r#""\ude00""#).unwrap(), Json::from("\u{FFFD}"));
+    }
+
+    #[test]
+    fn nesting() {
+        let text = r#"{"seq":1,"arguments":{"lines":[1,[2,{}],[]],"ok":true,"no":false,"none":null}}"#;
+        let json = Json::parse(text).unwrap();
+        assert_eq!(json.get("arguments").get("lines").as_array().len(), 3);
+        assert_eq!(json.get("arguments").get("ok").as_bool(), Some(true));
+        assert_eq!(json.get("missing").get("deeper"), &Json::Null);
+        assert_eq!(json.to_string(), text);
+        assert_eq!(round_trip(" { \"a\" : [ 1 , 2 ] } "), r#"{"a":[1,2]}"#);
+
+        assert_eq!(Json::parse(&"[".repeat(MAX_DEPTH)).unwrap_err(), "unexpected end at byte 128");
+        assert!(Json::parse(&"[".repeat(MAX_DEPTH + 1)).unwrap_err().starts_with("nested too deeply"));
+        for bad in ["[1,]", "[1 2]", "{\"a\" 1}", "{1:2}", "{\"a\":1,}", "[] []", "nul"] {
+            assert!(Json::parse(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(Json::parse("-12").unwrap().as_i64(), Some(-12));
+        assert_eq!(Json::parse("1e3").unwrap().as_i64(), Some(1000));
+        assert_eq!(Json::parse("1.5").unwrap().as_i64(), None);
+        assert_eq!(Json::parse("1e300").unwrap().as_i64(), None);
+        assert_eq!(round_trip("1.5"), "1.5");
+        assert_eq!(round_trip("-0.25e1"), "-2.5");
+        assert_eq!(round_trip("4294967295"), "4294967295");
+        assert_eq!(Json::Number(f64::NAN).to_string(), "null");
+        assert!(Json::parse("-").is_err());
+        assert!(Json::parse("0x10").is_err());
+    }
+}
@@ -0,0 +1,334 @@
+use std::io::{self, BufRead, Write};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+use chip8_core::debug::{Access, Condition, Debugger, Stop, Watch, Watchpoint};
+use chip8_core::disasm::Line;
+use chip8_core::{Chip8Error, Env, Host, Instruction, Keypad};
+
+const HELP: &str = "\
+s, step [n]        run n instructions (default 1)
+c, continue        run until a breakpoint
+u, until <addr>    run until the PC gets to addr
+p, pause           stop wherever the program is
+b, break [addr]    set a breakpoint, or list them
+d, delete <addr>   remove a breakpoint
+w, watch <expr>    stop when an expression like V3 == 0x10 && I > 0x300
+                   becomes true (V0-VF, I, PC, SP, DT, ST, [addr])
+rw <addr> [len]    stop when memory is read
+ww <addr> [len]    stop when memory is written
+aw <addr> [len]    stop when memory is read or written
+watches            list the watches
+unwatch <n>        remove a watch by its number in the list
+r, regs            show the registers and timers
+x <addr> [len]     hexdump memory (default 64 bytes)
+bt                 show the call stack
+l, list [addr]     disassemble around addr (default PC)
+q, quit            close the emulator
+Addresses are hex, an empty line repeats the last command.";
+
+// Something that takes over running the Env to debug it, either the REPL
+// below or a remote debugger. The main loop calls it once per frame.
+pub trait Session {
+    // Handles whatever has come in since the last frame.
+    fn poll(&mut self, env: &mut Env<Keypad>);
+    // Takes the place of Env::run_frame.
+    fn run_frame(&mut self, env: &mut Env<Keypad>) -> Result<(), Chip8Error>;
+    fn wants_quit(&self) -> bool;
+}
+
+// A debugger driven from the terminal while the window shows the game.
+// Commands are read on their own thread so typing never holds up a frame,
+// and are run between frames.
+pub struct Repl {
+    debugger: Debugger,
+    commands: Receiver<String>,
+    last: String,
+    quit: bool,
+}
+
+impl Repl {
+    // Starts paused at the first instruction, so breakpoints can be set first.
+    pub fn start<H: Host>(env: &Env<H>) -> Repl {
+        let (send, commands) = mpsc::channel();
+        thread::spawn(move || {
+            for line in io::stdin().lock().lines() {
+                match line {
+                    Ok(line) => if send.send(line).is_err() {
+                        break;
+                    },
+                    Err(_) => break,
+                }
+            }
+        });
+
+        let mut debugger = Debugger::new();
+        debugger.pause();
+        debugger.take_stop();
+        println!("chip8 debugger, type help for the commands");
+        println!("{}", current(env));
+        prompt();
+        Repl { debugger, commands, last: String::new(), quit: false }
+    }
+
+    fn command<H: Host>(&mut self, line: &str, env: &mut Env<H>) -> Result<(), String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (command, args) = match words.split_first() {
+            Some((command, args)) => (*command, args),
+            None => return Ok(()),
+        };
+        match (command, args) {
+            ("s" | "step", []) => self.step(env, 1),
+            ("s" | "step", [n]) => self.step(env, n.parse().map_err(|_| format!("{} isn't a number", n))?),
+            ("c" | "continue", []) => {
+                self.debugger.resume();
+                Ok(())
+            },
+            ("u" | "until", [address]) => {
+                self.debugger.run_to(parse_address(address)?);
+                Ok(())
+            },
+            ("p" | "pause", []) => {
+                self.debugger.pause();
+                self.debugger.take_stop();
+                println!("{}", current(env));
+                Ok(())
+            },
+            ("b" | "break", []) => {
+                for address in self.debugger.breakpoints() {
+                    println!("{}", line_at(env, address));
+                }
+                Ok(())
+            },
+            ("b" | "break", [address]) => {
+                let address = parse_address(address)?;
+                if !self.debugger.add_breakpoint(address) {
+                    return Err(format!("there's already a breakpoint at 0x{:03X}", address));
+                }
+                Ok(())
+            },
+            ("d" | "delete", [address]) => {
+                let address = parse_address(address)?;
+                if !self.debugger.remove_breakpoint(address) {
+                    return Err(format!("there's no breakpoint at 0x{:03X}", address));
+                }
+                Ok(())
+            },
+            ("w" | "watch", [_, ..]) => {
+                let text = line.trim_start()[command.len()..].trim();
+                let condition = Condition::parse(text).map_err(|err| err.to_string())?;
+                let i = self.debugger.add_watch(Watch::Condition(condition));
+                println!("{}: {}", i, self.debugger.watches()[i]);
+                Ok(())
+            },
+            ("rw", _) => self.watch_memory(args, true, false),
+            ("ww", _) => self.watch_memory(args, false, true),
+            ("aw", _) => self.watch_memory(args, true, true),
+            ("watches", []) => {
+                for (i, watch) in self.debugger.watches().iter().enumerate() {
+                    println!("{}: {}", i, watch);
+                }
+                Ok(())
+            },
+            ("unwatch", [i]) => {
+                let removed = i.parse().ok().and_then(|i| self.debugger.remove_watch(i));
+                match removed {
+                    Some(_) => Ok(()),
+                    None => Err(format!("there's no watch {}", i)),
+                }
+            },
+            ("r" | "regs", []) => {
+                registers(env);
+                Ok(())
+            },
+            ("x", [address]) => {
+                hexdump(env, parse_address(address)?, 64);
+                Ok(())
+            },
+            ("x", [address, len]) => {
+                let len = len.parse().map_err(|_| format!("{} isn't a number", len))?;
+                hexdump(env, parse_address(address)?, len);
+                Ok(())
+            },
+            ("bt", []) => {
+                println!("#0 {}", current(env));
+                for (i, &call) in env.stack().iter().rev().enumerate() {
+                    println!("#{} {}", i + 1, line_at(env, call));
+                }
+                Ok(())
+            },
+            ("l" | "list", []) => {
+                self.list(env, env.program_counter());
+                Ok(())
+            },
+            ("l" | "list", [address]) => {
+                self.list(env, parse_address(address)?);
+                Ok(())
+            },
+            ("h" | "help", []) => {
+                println!("{}", HELP);
+                Ok(())
+            },
+            ("q" | "quit", []) => {
+                self.quit = true;
+                Ok(())
+            },
+            _ => Err(format!("don't know how to {}, try help", line.trim())),
+        }
+    }
+
+    fn step<H: Host>(&mut self, env: &mut Env<H>, n: usize) -> Result<(), String> {
+        self.debugger.pause();
+        self.debugger.take_stop();
+        for _ in 0..n {
+            if let Err(err) = self.debugger.step(env) {
+                println!("{}", current(env));
+                return Err(err.to_string());
+            }
+            // Watches cut a long step short.
+            if let Some(stop) = self.debugger.take_stop() {
+                self.report(stop, env);
+                return Ok(());
+            }
+        }
+        println!("{}", current(env));
+        Ok(())
+    }
+
+    fn report<H: Host>(&self, stop: Stop, env: &Env<H>) {
+        match stop {
+            Stop::Breakpoint(pc) => println!("breakpoint at 0x{:03X}", pc),
+            Stop::Watchpoint { pc, address, access } => {
+                let access = match access {
+                    Access::Read => "read",
+                    Access::Write => "wrote",
+                };
+                println!("0x{:03X} {} 0x{:03X}", pc, access, address);
+            },
+            Stop::Condition(i) => println!("{}", self.debugger.watches()[i]),
+            Stop::RunTo(_) | Stop::Paused => {},
+        }
+        println!("{}", current(env));
+    }
+
+    fn watch_memory(&mut self, args: &[&str], read: bool, write: bool) -> Result<(), String> {
+        let (address, len) = match args {
+            [address] => (parse_address(address)?, 1),
+            [address, len] => (parse_address(address)?, len.parse().map_err(|_| format!("{} isn't a number", len))?),
+            _ => return Err("which address?".to_string()),
+        };
+        // No further than the end of memory.
+        let len = usize::clamp(len, 1, 0x10000 - address as usize).min(u16::MAX as usize) as u16;
+        let i = self.debugger.add_watch(Watch::Memory(Watchpoint { address, len, read, write }));
+        println!("{}: {}", i, self.debugger.watches()[i]);
+        Ok(())
+    }
+
+    // A few instructions either side, `>` marking the PC and `*` breakpoints.
+    fn list<H: Host>(&self, env: &Env<H>, around: u16) {
+        let mut address = around.saturating_sub(10);
+        for _ in 0..11 {
+            let line = line_at(env, address);
+            let text = line.to_string();
+            let breakpoint = if self.debugger.has_breakpoint(address) { '*' } else { ' ' };
+            let pc = if address == env.program_counter() { '>' } else { ' ' };
+            println!("{}{}{}", breakpoint, pc, &text[2..]);
+            address = address.wrapping_add(line.bytes.len() as u16);
+        }
+    }
+}
+
+impl Session for Repl {
+    fn poll(&mut self, env: &mut Env<Keypad>) {
+        loop {
+            let line = match self.commands.try_recv() {
+                Ok(line) => line,
+                Err(TryRecvError::Empty) => return,
+                // stdin closed, so nobody can resume us
+                Err(TryRecvError::Disconnected) => {
+                    self.quit = self.debugger.is_paused();
+                    return;
+                },
+            };
+            let line = if line.trim().is_empty() { self.last.clone() } else { line };
+            if let Err(message) = self.command(&line, env) {
+                println!("{}", message);
+            }
+            self.last = line;
+            if self.quit {
+                return;
+            }
+            prompt();
+        }
+    }
+
+    fn run_frame(&mut self, env: &mut Env<Keypad>) -> Result<(), Chip8Error> {
+        let result = self.debugger.run_frame(env);
+        match &result {
+            Err(err) => println!("\nstopped by an error: {}\n{}", err, current(env)),
+            Ok(()) => match self.debugger.take_stop() {
+                Some(stop) => {
+                    println!();
+                    self.report(stop, env);
+                },
+                None => return result,
+            },
+        }
+        prompt();
+        result
+    }
+
+    fn wants_quit(&self) -> bool {
+        self.quit
+    }
+}
+
+fn prompt() {
+    print!("(chip8) ");
+    let _ = io::stdout().flush();
+}
+
+fn parse_address(text: &str) -> Result<u16, String> {
+    let digits = text.trim_start_matches("0x").trim_start_matches("0X");
+    u16::from_str_radix(digits, 16).map_err(|_| format!("{} isn't a hex address", text))
+}
+
+// The instruction at an address, or its two bytes as data if it isn't one.
+pub fn line_at<H: Host>(env: &Env<H>, address: u16) -> Line {
+    let memory = env.memory();
+    let byte = |offset: u16| memory[address.wrapping_add(offset) as usize];
+    let instruction = Instruction::decode((byte(0) as u16) << 8 | byte(1) as u16);
+    let size = instruction.map_or(2, |instr| instr.size());
+    Line {
+        address,
+        bytes: (0..size).map(byte).collect(),
+        instruction,
+    }
+}
+
+fn current<H: Host>(env: &Env<H>) -> Line {
+    line_at(env, env.program_counter())
+}
+
+pub fn registers<H: Host>(env: &Env<H>) {
+    for (i, values) in env.registers().chunks(8).enumerate() {
+        let values: Vec<String> = values.iter()
+            .enumerate()
+            .map(|(j, v)| format!("V{:X} {:02X}", i * 8 + j, v))
+            .collect();
+        println!("{}", values.join("  "));
+    }
+    println!("PC {:03X}  I {:04X}  SP {}  DT {:02X}  ST {:02X}  cycle {}  frame {}",
+        env.program_counter(), env.index_register(), env.stack().len(),
+        env.delay_timer(), env.sound_timer(), env.cycle_count(), env.frame_count());
+}
+
+fn hexdump<H: Host>(env: &Env<H>, address: u16, len: usize) {
+    let memory = env.memory();
+    let start = address as usize;
+    let end = start.saturating_add(len).min(memory.len());
+    for (i, row) in memory[start..end].chunks(16).enumerate() {
+        let bytes: Vec<String> = row.iter().map(|b| format!("{:02X}", b)).collect();
+        println!("{:04X}: {}", start + i * 16, bytes.join(" "));
+    }
+}
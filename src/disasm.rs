@@ -0,0 +1,28 @@
+use std::fs;
+use std::process;
+
+use chip8_core::disasm;
+
+// chip8 disasm <rom>
+// Prints the ROM as assembler source, with addresses and raw bytes in comments.
+pub fn run(args: &[String]) {
+    let path = match args {
+        [path] => path,
+        _ => {
+            eprintln!("usage: chip8 disasm <rom>");
+            process::exit(2);
+        },
+    };
+    let rom = match fs::read(path) {
+        Ok(rom) => rom,
+        Err(err) => {
+            eprintln!("chip8: couldn't read {}: {}", path, err);
+            process::exit(1);
+        },
+    };
+
+    println!("; {}", path);
+    for line in disasm::disassemble(&rom) {
+        println!("{}", line);
+    }
+}
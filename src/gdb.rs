@@ -0,0 +1,570 @@
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use chip8_core::debug::{Access, Debugger, Stop, Watch, Watchpoint};
+use chip8_core::{Chip8Error, Env, Keypad};
+
+use crate::debugger::Session;
+
+// A stub for GDB's remote serial protocol, so gdb (or anything else that
+// speaks it) can attach with `target remote localhost:<port>`.
+//
+// The registers, in order, are V0-VF, I, PC, SP, DT and ST. I and PC are
+// 16 bits and sent big-endian like everything else on the CHIP-8, the rest
+// are a byte. SP can't be written. Breakpoints (Z0/Z1) and watchpoints
+// (Z2-Z4) go to the same Debugger the terminal one uses.
+//
+// The game stays paused until the first client continues it, and carries
+// on by itself after the client detaches.
+pub struct GdbStub {
+    listener: TcpListener,
+    client: Option<TcpStream>,
+    input: Vec<u8>,
+    debugger: Debugger,
+    no_ack: bool,
+    // The client is waiting on a stop reply to a continue.
+    running: bool,
+    exit_reported: bool,
+}
+
+const REGISTER_COUNT: usize = 21;
+const PACKET_SIZE: usize = 0x4000;
+
+impl GdbStub {
+    pub fn listen(port: u16) -> io::Result<GdbStub> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        listener.set_nonblocking(true)?;
+        eprintln!("chip8: waiting for gdb on localhost:{}", port);
+        let mut debugger = Debugger::new();
+        debugger.pause();
+        debugger.take_stop();
+        Ok(GdbStub {
+            listener,
+            client: None,
+            input: Vec::new(),
+            debugger,
+            no_ack: false,
+            running: false,
+            exit_reported: false,
+        })
+    }
+
+    fn disconnect(&mut self) {
+        self.client = None;
+        self.input.clear();
+        self.running = false;
+        self.debugger.resume();
+        eprintln!("chip8: gdb detached");
+    }
+
+    fn send(&mut self, data: &str) {
+        let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        let packet = format!("${}#{:02x}", data, checksum);
+        let sent = match self.client.as_mut() {
+            Some(client) => client.write_all(packet.as_bytes()),
+            None => return,
+        };
+        if sent.is_err() {
+            self.disconnect();
+        }
+    }
+
+    // Pulls the next whole packet (or interrupt) out of the input.
+    fn next_packet(&mut self) -> Option<Packet> {
+        loop {
+            match *self.input.first()? {
+                b'$' => {},
+                0x03 => {
+                    self.input.remove(0);
+                    return Some(Packet::Interrupt);
+                },
+                // Acks, and any noise between packets.
+                _ => {
+                    self.input.remove(0);
+                    continue;
+                },
+            }
+            let end = self.input.iter().position(|&b| b == b'#')?;
+            if self.input.len() < end + 3 {
+                return None;
+            }
+            let packet: Vec<u8> = self.input.drain(..end + 3).collect();
+            let data = &packet[1..end];
+            let sum = data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+            let checksum = std::str::from_utf8(&packet[end + 1..]).ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+            // Without acks nobody would resend a bad packet, so take it as is.
+            if !self.no_ack {
+                let valid = checksum == Some(sum);
+                if let Some(client) = self.client.as_mut() {
+                    let _ = client.write_all(if valid { b"+" } else { b"-" });
+                }
+                if !valid {
+                    continue;
+                }
+            }
+            return Some(Packet::Command(String::from_utf8_lossy(data).into_owned()));
+        }
+    }
+
+    fn stop_reply(&self, stop: Stop) -> String {
+        match stop {
+            Stop::Watchpoint { address, access, .. } => {
+                // An access watchpoint says so, whichever way it went off.
+                let both = self.debugger.watches().iter().any(|watch| matches!(watch,
+                    Watch::Memory(wp) if wp.read && wp.write
+                        && (wp.address as usize..wp.address as usize + wp.len as usize).contains(&(address as usize))));
+                let kind = match (both, access) {
+                    (true, _) => "awatch",
+                    (false, Access::Read) => "rwatch",
+                    (false, Access::Write) => "watch",
+                };
+                format!("T05{}:{:x};", kind, address)
+            },
+            Stop::Paused => "S02".to_string(),
+            _ => "S05".to_string(),
+        }
+    }
+
+    fn handle(&mut self, command: &str, env: &mut Env<Keypad>) {
+        let reply = match command.as_bytes().first() {
+            Some(b'?') => "S05".to_string(),
+            Some(b'g') => {
+                (0..REGISTER_COUNT).map(|n| read_register(env, n)).collect()
+            },
+            Some(b'G') => {
+                let mut hex = &command[1..];
+                for n in 0..REGISTER_COUNT {
+                    let width = register_size(n) * 2;
+                    if hex.len() < width {
+                        break;
+                    }
+                    write_register(env, n, &hex[..width]);
+                    hex = &hex[width..];
+                }
+                "OK".to_string()
+            },
+            Some(b'p') => match usize::from_str_radix(&command[1..], 16) {
+                Ok(n) if n < REGISTER_COUNT => read_register(env, n),
+                _ => "E00".to_string(),
+            },
+            Some(b'P') => {
+                let parsed = command[1..].split_once('=')
+                    .and_then(|(n, value)| Some((usize::from_str_radix(n, 16).ok()?, value)));
+                match parsed {
+                    Some((n, value)) if n < REGISTER_COUNT && write_register(env, n, value) => "OK".to_string(),
+                    _ => "E00".to_string(),
+                }
+            },
+            Some(b'm') => match parse_range(&command[1..]) {
+                Some((address, len)) => match address.checked_add(len) {
+                    Some(end) if address < env.memory().len() => {
+                        let memory = env.memory();
+                        memory[address..end.min(memory.len())].iter().map(|b| format!("{:02x}", b)).collect()
+                    },
+                    _ => "E01".to_string(),
+                },
+                None => "E00".to_string(),
+            },
+            Some(b'M') => {
+                let written = command[1..].split_once(':').and_then(|(range, hex)| {
+                    let (address, len) = parse_range(range)?;
+                    let bytes = decode_hex(hex)?;
+                    if bytes.len() != len || address.checked_add(len)? > env.memory().len() {
+                        return None;
+                    }
+                    env.memory_mut()[address..address + len].copy_from_slice(&bytes);
+                    Some(())
+                });
+                match written {
+                    Some(()) => "OK".to_string(),
+                    None => "E00".to_string(),
+                }
+            },
+            Some(b'c') => {
+                if let Ok(address) = u16::from_str_radix(&command[1..], 16) {
+                    env.set_program_counter(address);
+                }
+                self.debugger.resume();
+                self.running = true;
+                return;
+            },
+            Some(b's') => {
+                if let Ok(address) = u16::from_str_radix(&command[1..], 16) {
+                    env.set_program_counter(address);
+                }
+                self.debugger.pause();
+                self.debugger.take_stop();
+                match self.debugger.step(env) {
+                    Err(_) => "S0b".to_string(), // SIGSEGV, the closest thing to a fault
+                    Ok(()) if env.has_exited() => "W00".to_string(),
+                    Ok(()) => match self.debugger.take_stop() {
+                        Some(stop) => self.stop_reply(stop),
+                        None => "S05".to_string(),
+                    },
+                }
+            },
+            Some(b'Z') | Some(b'z') => self.breakpoint(command),
+            Some(b'D') => {
+                self.send("OK");
+                self.disconnect();
+                return;
+            },
+            Some(b'k') => {
+                self.disconnect();
+                return;
+            },
+            Some(b'H') => "OK".to_string(),
+            _ => self.query(command),
+        };
+        self.send(&reply);
+    }
+
+    fn breakpoint(&mut self, command: &str) -> String {
+        let insert = command.starts_with('Z');
+        let mut fields = command[1..].split(',');
+        let (kind, address, len) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(kind), Some(address), Some(len)) => (kind, address, len),
+            _ => return "E00".to_string(),
+        };
+        let (address, len) = match (u16::from_str_radix(address, 16), u16::from_str_radix(len, 16)) {
+            (Ok(address), Ok(len)) => (address, len),
+            _ => return "E00".to_string(),
+        };
+        let (read, write) = match kind {
+            "0" | "1" => {
+                if insert {
+                    self.debugger.add_breakpoint(address);
+                } else {
+                    self.debugger.remove_breakpoint(address);
+                }
+                return "OK".to_string();
+            },
+            "2" => (false, true),
+            "3" => (true, false),
+            "4" => (true, true),
+            _ => return String::new(),
+        };
+        let watchpoint = Watchpoint { address, len: len.max(1), read, write };
+        if insert {
+            self.debugger.add_watch(Watch::Memory(watchpoint));
+        } else {
+            let index = self.debugger.watches().iter()
+                .position(|watch| matches!(watch, Watch::Memory(wp) if *wp == watchpoint));
+            if let Some(index) = index {
+                self.debugger.remove_watch(index);
+            }
+        }
+        "OK".to_string()
+    }
+
+    // The q and v packets. Empty means unsupported, which makes gdb fall
+    // back on something simpler.
+    fn query(&mut self, command: &str) -> String {
+        if command.starts_with("qSupported") {
+            return format!("PacketSize={:x};qXfer:features:read+;QStartNoAckMode+", PACKET_SIZE);
+        }
+        if command == "QStartNoAckMode" {
+            self.no_ack = true;
+            return "OK".to_string();
+        }
+        if let Some(range) = command.strip_prefix("qXfer:features:read:target.xml:") {
+            let xml = target_xml();
+            return match parse_range(range) {
+                Some((offset, len)) if offset <= xml.len() => match offset.checked_add(len) {
+                    Some(end) => {
+                        let end = end.min(xml.len());
+                        let more = if end < xml.len() { 'm' } else { 'l' };
+                        format!("{}{}", more, &xml[offset..end])
+                    },
+                    None => "E01".to_string(),
+                },
+                _ => "E00".to_string(),
+            };
+        }
+        match command {
+            "qAttached" => "1".to_string(),
+            "qC" => "QC1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Packet {
+    Command(String),
+    Interrupt, // ^C
+}
+
+impl Session for GdbStub {
+    fn poll(&mut self, env: &mut Env<Keypad>) {
+        if self.client.is_none() {
+            match self.listener.accept() {
+                Ok((client, address)) => {
+                    if client.set_nonblocking(true).is_err() {
+                        return;
+                    }
+                    let _ = client.set_nodelay(true);
+                    eprintln!("chip8: gdb attached from {}", address);
+                    self.client = Some(client);
+                    self.no_ack = false;
+                    // Whatever was running stops for the new client.
+                    self.debugger.pause();
+                    self.debugger.take_stop();
+                },
+                Err(_) => return,
+            }
+        }
+
+        let mut buffer = [0; 4096];
+        loop {
+            let read = match self.client.as_mut() {
+                Some(client) => client.read(&mut buffer),
+                None => return,
+            };
+            match read {
+                Ok(0) => {
+                    self.disconnect();
+                    return;
+                },
+                Ok(n) => self.input.extend_from_slice(&buffer[..n]),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(_) => {
+                    self.disconnect();
+                    return;
+                },
+            }
+        }
+
+        while let Some(packet) = self.next_packet() {
+            match packet {
+                Packet::Interrupt => {
+                    self.debugger.pause();
+                    self.debugger.take_stop();
+                    if self.running {
+                        self.running = false;
+                        self.send("S02");
+                    }
+                },
+                Packet::Command(command) => self.handle(&command, env),
+            }
+        }
+    }
+
+    fn run_frame(&mut self, env: &mut Env<Keypad>) -> Result<(), Chip8Error> {
+        let result = self.debugger.run_frame(env);
+        if !self.running {
+            // Nobody to tell but the terminal.
+            if let Err(err) = &result {
+                eprintln!("chip8: {}", err);
+            }
+            return result;
+        }
+        if result.is_err() {
+            self.running = false;
+            self.send("S0b");
+        } else if env.has_exited() {
+            if !self.exit_reported {
+                self.running = false;
+                self.exit_reported = true;
+                self.send("W00");
+            }
+        } else if let Some(stop) = self.debugger.take_stop() {
+            self.running = false;
+            let reply = self.stop_reply(stop);
+            self.send(&reply);
+        }
+        result
+    }
+
+    fn wants_quit(&self) -> bool {
+        false
+    }
+}
+
+fn register_size(n: usize) -> usize {
+    match n {
+        16 | 17 => 2, // I, PC
+        _ => 1,
+    }
+}
+
+fn read_register(env: &Env<Keypad>, n: usize) -> String {
+    match n {
+        0..=15 => format!("{:02x}", env.registers()[n]),
+        16 => format!("{:04x}", env.index_register()),
+        17 => format!("{:04x}", env.program_counter()),
+        18 => format!("{:02x}", env.stack().len()),
+        19 => format!("{:02x}", env.delay_timer()),
+        _ => format!("{:02x}", env.sound_timer()),
+    }
+}
+
+// False if the value doesn't parse.
+fn write_register(env: &mut Env<Keypad>, n: usize, hex: &str) -> bool {
+    let value = match u16::from_str_radix(hex, 16) {
+        Ok(value) => value,
+        Err(_) => return false,
+    };
+    match n {
+        0..=15 => env.registers_mut()[n] = value as u8,
+        16 => env.set_index_register(value),
+        17 => env.set_program_counter(value),
+        18 => {}, // the stack can't be conjured up
+        19 => env.set_delay_timer(value as u8),
+        _ => env.set_sound_timer(value as u8),
+    }
+    true
+}
+
+// "addr,len" in hex
+fn parse_range(text: &str) -> Option<(usize, usize)> {
+    let (address, len) = text.split_once(',')?;
+    Some((usize::from_str_radix(address, 16).ok()?, usize::from_str_radix(len, 16).ok()?))
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
+
+fn target_xml() -> String {
+    let mut regs = String::new();
+    for n in 0..16 {
+        regs += &format!("<reg name=\"v{:x}\" bitsize=\"8\" type=\"uint8\"/>", n);
+    }
+    regs += "<reg name=\"i\" bitsize=\"16\" type=\"data_ptr\"/>";
+    regs += "<reg name=\"pc\" bitsize=\"16\" type=\"code_ptr\"/>";
+    regs += "<reg name=\"sp\" bitsize=\"8\" type=\"uint8\"/>";
+    regs += "<reg name=\"dt\" bitsize=\"8\" type=\"uint8\"/>";
+    regs += "<reg name=\"st\" bitsize=\"8\" type=\"uint8\"/>";
+    format!("<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+        <target version=\"1.0\"><feature name=\"org.chip8.core\">{}</feature></target>", regs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chip8_core::Quirks;
+
+    // A stub on a free port with a client attached.
+    fn attach() -> (GdbStub, TcpStream, Env<Keypad>) {
+        let mut stub = GdbStub::listen(0).unwrap();
+        let client = TcpStream::connect(stub.listener.local_addr().unwrap()).unwrap();
+        client.set_read_timeout(Some(std::time::Duration::from_secs(5))).unwrap();
+        let mut env = Env::new(Keypad::new(), Quirks::default());
+        env.load_into_memory(&[0x60, 0x12, 0xA3, 0x00]).unwrap();
+        stub.poll(&mut env);
+        assert!(stub.client.is_some());
+        (stub, client, env)
+    }
+
+    fn packet(data: &str) -> String {
+        format!("${}#{:02x}", data, data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b)))
+    }
+
+    // Reads what the stub wrote back: its ack, then the reply's data.
+    fn exchange(stub: &mut GdbStub, client: &mut TcpStream, env: &mut Env<Keypad>, command: &str) -> String {
+        client.write_all(packet(command).as_bytes()).unwrap();
+        stub.poll(env);
+        let mut received = Vec::new();
+        let mut byte = [0];
+        while received.len() < 3 || received[received.len() - 3] != b'#' {
+            client.read_exact(&mut byte).unwrap();
+            received.push(byte[0]);
+        }
+        let reply = String::from_utf8(received).unwrap();
+        let data = reply.strip_prefix("+$").expect(&reply);
+        assert_eq!(reply, format!("+{}", packet(&data[..data.len() - 3])));
+        data[..data.len() - 3].to_string()
+    }
+
+    fn unattached() -> GdbStub {
+        GdbStub::listen(0).unwrap()
+    }
+
+    #[test]
+    fn packets_are_framed() {
+        let mut stub = unattached();
+        stub.input = format!("+{}\x03-{}$m0,", packet("g"), packet("qC")).into_bytes();
+        assert_eq!(stub.next_packet(), Some(Packet::Command("g".to_string())));
+        assert_eq!(stub.next_packet(), Some(Packet::Interrupt));
+        assert_eq!(stub.next_packet(), Some(Packet::Command("qC".to_string())));
+        assert_eq!(stub.next_packet(), None);
+        stub.input.extend_from_slice(b"2#");
+        assert_eq!(stub.next_packet(), None);
+        stub.input.extend_from_slice(b"F");
+        assert_eq!(stub.next_packet(), None);
+        stub.input.extend_from_slice(b"B");
+        assert_eq!(stub.next_packet(), Some(Packet::Command("m0,2".to_string())));
+    }
+
+    #[test]
+    fn bad_checksums_are_dropped() {
+        let mut stub = unattached();
+        stub.input = format!("$g#00$g#zz{}", packet("?")).into_bytes();
+        assert_eq!(stub.next_packet(), Some(Packet::Command("?".to_string())));
+        assert_eq!(stub.next_packet(), None);
+
+        // Unless acks are off, when there's nobody to resend them.
+        stub.no_ack = true;
+        stub.input = b"$g#00".to_vec();
+        assert_eq!(stub.next_packet(), Some(Packet::Command("g".to_string())));
+    }
+
+    #[test]
+    fn bad_checksums_are_nacked() {
+        let (mut stub, mut client, mut env) = attach();
+        client.write_all(b"$g#00").unwrap();
+        stub.poll(&mut env);
+        let mut nack = [0];
+        client.read_exact(&mut nack).unwrap();
+        assert_eq!(&nack, b"-");
+        assert_eq!(exchange(&mut stub, &mut client, &mut env, "m200,2"), "6012");
+    }
+
+    #[test]
+    fn reading_memory() {
+        let (mut stub, mut client, mut env) = attach();
+        let mut m = |command: &str| exchange(&mut stub, &mut client, &mut env, command);
+        assert_eq!(m("m200,4"), "6012a300");
+        assert_eq!(m("m202,0"), "");
+        // Cut short at the end of memory, and refused past it.
+        assert_eq!(m("mfffe,10"), "0000");
+        assert_eq!(m("m10000,1"), "E01");
+        assert_eq!(m("m1,ffffffffffffffff"), "E01");
+        assert_eq!(m("m200"), "E00");
+        assert_eq!(m("mxyz,1"), "E00");
+    }
+
+    #[test]
+    fn writing_memory() {
+        let (mut stub, mut client, mut env) = attach();
+        assert_eq!(exchange(&mut stub, &mut client, &mut env, "M300,2:abcd"), "OK");
+        assert_eq!(env.memory()[0x300..0x302], [0xAB, 0xCD]);
+        assert_eq!(exchange(&mut stub, &mut client, &mut env, "Mffff,1:01"), "OK");
+        assert_eq!(env.memory()[0xFFFF], 1);
+        for bad in ["Mffff,2:0102", "M300,2:ab", "M300,1:xy", "M1,ffffffffffffffff:00"] {
+            assert_eq!(exchange(&mut stub, &mut client, &mut env, bad), "E00", "{}", bad);
+        }
+        assert_eq!(env.memory()[0x300..0x302], [0xAB, 0xCD]);
+    }
+
+    #[test]
+    fn reading_the_target_description() {
+        let (mut stub, mut client, mut env) = attach();
+        let xml = target_xml();
+        let mut read = |range: &str| {
+            exchange(&mut stub, &mut client, &mut env, &format!("qXfer:features:read:target.xml:{}", range))
+        };
+        assert_eq!(read("0,10"), format!("m{}", &xml[..0x10]));
+        assert_eq!(read(&format!("10,{:x}", xml.len())), format!("l{}", &xml[0x10..]));
+        assert_eq!(read(&format!("{:x},10", xml.len())), "l");
+        assert_eq!(read(&format!("{:x},10", xml.len() + 1)), "E00");
+        assert_eq!(read("1,ffffffffffffffff"), "E01");
+    }
+}
@@ -0,0 +1,145 @@
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+
+use chip8_core::movie::Movie;
+use chip8_core::screenshot::Screenshot;
+use chip8_core::script::KeyScript;
+use chip8_core::{fnv1a, Env, Keypad};
+
+use crate::debugger;
+use crate::record::{Recorder, SoundRecorder};
+use crate::trace::Trace;
+use crate::PALETTE;
+
+// How far a headless run goes.
+pub enum Limit {
+    Cycles(u64),
+    Frames(u64),
+}
+
+// Each pixel of the display dump, by its value in the two XO-CHIP planes.
+const PIXELS: [char; 4] = ['.', '#', '+', '*'];
+
+pub fn load_keys(path: &str) -> KeyScript {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) => {
+            eprintln!("chip8: couldn't read {}: {}", path, err);
+            process::exit(1);
+        },
+    };
+    match KeyScript::parse(&text) {
+        Ok(keys) => keys,
+        Err(err) => {
+            eprintln!("chip8: {}: {}", path, err);
+            process::exit(1);
+        },
+    }
+}
+
+// Movies are loaded the same way, for --play-movie with or without a window.
+pub fn load_movie(path: &str) -> Movie {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) => {
+            eprintln!("chip8: couldn't read {}: {}", path, err);
+            process::exit(1);
+        },
+    };
+    match Movie::parse(&text) {
+        Ok(movie) => movie,
+        Err(err) => {
+            eprintln!("chip8: {}: {}", path, err);
+            process::exit(1);
+        },
+    }
+}
+
+// Everything a headless run can do besides running.
+#[derive(Default)]
+pub struct Extras {
+    pub keys: KeyScript,
+    pub trace: Option<Trace>,
+    pub screenshot: Option<(PathBuf, usize)>, // taken at the end, at that scale
+    pub recorder: Option<Recorder>, // gets a frame each time a frame finishes
+    pub sound: Option<SoundRecorder>, // recorded after every instruction
+    pub movie: Option<(String, Movie)>, // the keys from the script, saved at the end
+}
+
+// Runs the program with no window or audio until it gets to the limit,
+// exits or faults, then prints the state it ended up in: the registers, a
+// hash of memory and the display. Returns the exit code for the process,
+// which is 1 if the program faulted or anything couldn't be written.
+pub fn run(env: &mut Env<Keypad>, limit: Limit, extras: Extras) -> i32 {
+    let Extras { mut keys, mut trace, screenshot, mut recorder, mut sound, mut movie } = extras;
+    let done = |env: &Env<Keypad>| match limit {
+        Limit::Cycles(cycles) => env.cycle_count() >= cycles,
+        Limit::Frames(frames) => env.frame_count() >= frames,
+    };
+    let mut result = Ok(());
+    while !done(env) && !env.has_exited() {
+        let frame = env.frame_count();
+        let applied = keys.apply(env);
+        if let Some((_, movie)) = movie.as_mut() {
+            for event in applied {
+                movie.record(env, event.key, event.pressed);
+            }
+        }
+        result = match trace.as_mut() {
+            Some(trace) => trace.step(env),
+            None => env.step(),
+        };
+        if result.is_err() {
+            break;
+        }
+        if let Some(sound) = sound.as_mut() {
+            sound.update(env);
+        }
+        if let Some(recorder) = recorder.as_mut().filter(|_| env.frame_count() != frame) {
+            recorder.frame(env);
+        }
+    }
+    // Finish the trace before anything is printed.
+    drop(trace);
+
+    match &result {
+        Err(err) => println!("halted: {}", err),
+        Ok(()) if env.has_exited() => println!("exited"),
+        Ok(()) => println!("stopped"),
+    }
+    debugger::registers(env);
+    println!("memory {:016x}", fnv1a(env.memory()));
+    let (width, height) = env.resolution();
+    println!("display {}x{}", width, height);
+    for y in 0..height {
+        let row: String = (0..width).map(|x| PIXELS[env.pixel(x, y) as usize]).collect();
+        println!("{}", row);
+    }
+    if let Some((path, mut movie)) = movie {
+        movie.finish(env);
+        if let Err(err) = fs::write(&path, movie.to_string()) {
+            eprintln!("chip8: couldn't save the movie to {}: {}", path, err);
+            return 1;
+        }
+    }
+    if let Some(recorder) = recorder {
+        if let Err(err) = recorder.finish() {
+            eprintln!("chip8: couldn't finish recording: {}", err);
+            return 1;
+        }
+    }
+    if let Some(sound) = sound {
+        if let Err(err) = sound.finish() {
+            eprintln!("chip8: couldn't finish recording sound: {}", err);
+            return 1;
+        }
+    }
+    if let Some((path, scale)) = screenshot {
+        if let Err(err) = Screenshot::capture(env).save(&path, &PALETTE, scale) {
+            eprintln!("chip8: couldn't save a screenshot to {}: {}", path.display(), err);
+            return 1;
+        }
+    }
+    if result.is_err() { 1 } else { 0 }
+}
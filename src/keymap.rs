@@ -0,0 +1,226 @@
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use sdl2::keyboard::Scancode;
+
+// Which keys on the keyboard are which keys on the keypad. Keys are by
+// where they are on the keyboard rather than what's printed on them, and
+// named for what's there on a US keyboard, so the default block is the same
+// on any layout. A keymap file has a line for each keypad key that's moved,
+// with any number of keyboard keys for it, and can start from a preset:
+//
+//   # pong.keys
+//   preset numpad
+//   1 = W, Up
+//   4 = S, Down
+//
+// The names are SDL's, so the number pad keys are `Keypad 8` and so on.
+pub struct Keymap {
+    keys: Vec<(Scancode, u8)>,
+    held: HashSet<Scancode>,
+}
+
+/*
+    1 2 3 4 is the mapping of 1 2 3 C
+    Q W E R                   4 5 6 D
+    A S D F                   7 8 9 E
+    Z X C V                   A 0 B F
+*/
+const QWERTY: [Scancode; 16] = [
+    Scancode::X,    Scancode::Num1, Scancode::Num2, Scancode::Num3,
+    Scancode::Q,    Scancode::W,    Scancode::E,    Scancode::A,
+    Scancode::S,    Scancode::D,    Scancode::Z,    Scancode::C,
+    Scancode::Num4, Scancode::R,    Scancode::F,    Scancode::V,
+];
+
+/*
+    7 8 9 /   is the mapping of 1 2 3 C
+    4 5 6 *                     4 5 6 D
+    1 2 3 -                     7 8 9 E
+    0 . Enter +                 A 0 B F
+    The digits keep their place on the keypad rather than their value.
+*/
+const NUMPAD: [Scancode; 16] = [
+    Scancode::KpPeriod, Scancode::Kp7, Scancode::Kp8, Scancode::Kp9,
+    Scancode::Kp4,      Scancode::Kp5, Scancode::Kp6, Scancode::Kp1,
+    Scancode::Kp2,      Scancode::Kp3, Scancode::Kp0, Scancode::KpEnter,
+    Scancode::KpDivide, Scancode::KpMultiply, Scancode::KpMinus, Scancode::KpPlus,
+];
+
+impl Keymap {
+    // By name. AZERTY keyboards have the same keys in the same places as
+    // QWERTY ones, just printed differently, so it's the same map.
+    pub fn preset(name: &str) -> Option<Keymap> {
+        let keys = match name {
+            "qwerty" | "azerty" => QWERTY,
+            "numpad" => NUMPAD,
+            _ => return None,
+        };
+        Some(Keymap::from_table(&keys))
+    }
+
+    fn from_table(table: &[Scancode; 16]) -> Keymap {
+        let keys = table.iter().enumerate().map(|(key, &scancode)| (scancode, key as u8)).collect();
+        Keymap { keys, held: HashSet::new() }
+    }
+
+    // For --keymap, a preset or a file, and otherwise the ROM's own keymap
+    // next to it (pong.ch8 -> pong.keys), the one in the config directory or
+    // the default, in that order.
+    pub fn find(keymap: Option<&str>, rom_path: &str) -> Result<Keymap, String> {
+        if let Some(keymap) = keymap {
+            return match Keymap::preset(keymap) {
+                Some(preset) => Ok(preset),
+                None => Keymap::load(Path::new(keymap)),
+            };
+        }
+        let rom_keymap = Path::new(rom_path).with_extension("keys");
+        match [Some(rom_keymap), config_path()].into_iter().flatten().find(|path| path.exists()) {
+            Some(path) => Keymap::load(&path),
+            None => Ok(Keymap::from_table(&QWERTY)),
+        }
+    }
+
+    fn load(path: &Path) -> Result<Keymap, String> {
+        let text = fs::read_to_string(path).map_err(|err| format!("couldn't read {}: {}", path.display(), err))?;
+        Keymap::parse(&text).map_err(|err| format!("{}: {}", path.display(), err))
+    }
+
+    pub fn parse(text: &str) -> Result<Keymap, String> {
+        let mut keymap = Keymap::from_table(&QWERTY);
+        for (i, line) in text.lines().enumerate() {
+            let error = |message: String| format!("line {}: {}", i + 1, message);
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix("preset ") {
+                let name = name.trim();
+                keymap = Keymap::preset(name).ok_or_else(|| error(format!("there's no {} preset", name)))?;
+                continue;
+            }
+            keymap.set(line).map_err(error)?;
+        }
+        Ok(keymap)
+    }
+
+    // Moves a keypad key to other keyboard keys, from `5 = W, Up`, for a
+    // line of a keymap file or --key.
+    pub fn set(&mut self, binding: &str) -> Result<(), String> {
+        let (key, names) = binding.split_once('=').ok_or("expected a keypad key = keyboard keys")?;
+        let key = match u8::from_str_radix(key.trim(), 16) {
+            Ok(key) if key < 16 => key,
+            _ => return Err(format!("{} isn't a keypad key, they go from 0 to F", key.trim())),
+        };
+        let scancodes = names.split(',')
+            .map(|name| Scancode::from_name(name.trim()).ok_or_else(|| format!("there's no {} key", name.trim())))
+            .collect::<Result<Vec<_>, _>>()?;
+        // A keyboard key only does one thing.
+        self.keys.retain(|&(scancode, bound)| bound != key && !scancodes.contains(&scancode));
+        self.keys.extend(scancodes.into_iter().map(|scancode| (scancode, key)));
+        Ok(())
+    }
+
+    // The keypad key to press when a keyboard key goes down.
+    pub fn press(&mut self, scancode: Scancode) -> Option<u8> {
+        let key = self.key(scancode)?;
+        self.held.insert(scancode);
+        Some(key)
+    }
+
+    // The keypad key to release when a keyboard key comes up, unless
+    // another key for it is still held.
+    pub fn release(&mut self, scancode: Scancode) -> Option<u8> {
+        let key = self.key(scancode)?;
+        self.held.remove(&scancode);
+        if self.held.iter().any(|&other| self.key(other) == Some(key)) {
+            return None;
+        }
+        Some(key)
+    }
+
+    fn key(&self, scancode: Scancode) -> Option<u8> {
+        self.keys.iter().find(|&&(bound, _)| bound == scancode).map(|&(_, key)| key)
+    }
+}
+
+// $XDG_CONFIG_HOME/chip8/keys, or ~/.config/chip8/keys.
+fn config_path() -> Option<PathBuf> {
+    let config = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+    Some(config.join("chip8").join("keys"))
+}
+
+// Key names go through SDL, so these need libSDL2 to run.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn presets() {
+        let mut qwerty = Keymap::preset("qwerty").unwrap();
+        assert_eq!(qwerty.press(Scancode::X), Some(0));
+        assert_eq!(qwerty.press(Scancode::V), Some(0xF));
+        assert_eq!(qwerty.press(Scancode::Kp7), None);
+        let mut numpad = Keymap::preset("numpad").unwrap();
+        assert_eq!(numpad.press(Scancode::Kp7), Some(1));
+        assert_eq!(numpad.press(Scancode::KpPeriod), Some(0));
+        assert_eq!(numpad.press(Scancode::X), None);
+        assert!(Keymap::preset("dvorak").is_none());
+    }
+
+    #[test]
+    fn parse_moves_keys() {
+        let text = "# pong.keys\n\npreset numpad\n1 = W, Up  # both\n  4 = S,Down\n";
+        let mut keymap = Keymap::parse(text).unwrap();
+        assert_eq!(keymap.press(Scancode::W), Some(1));
+        assert_eq!(keymap.press(Scancode::Up), Some(1));
+        assert_eq!(keymap.press(Scancode::S), Some(4));
+        assert_eq!(keymap.press(Scancode::Down), Some(4));
+        // Where they were in the preset does nothing now.
+        assert_eq!(keymap.press(Scancode::Kp7), None);
+        assert_eq!(keymap.press(Scancode::Kp4), None);
+        assert_eq!(keymap.press(Scancode::Kp8), Some(2));
+    }
+
+    #[test]
+    fn set_steals_keyboard_keys() {
+        let mut keymap = Keymap::preset("qwerty").unwrap();
+        // Q was 4's.
+        keymap.set("5 = Q").unwrap();
+        assert_eq!(keymap.press(Scancode::Q), Some(5));
+        assert_eq!(keymap.press(Scancode::W), None);
+        keymap.set("a = Keypad 8, 1").unwrap();
+        assert_eq!(keymap.press(Scancode::Kp8), Some(0xA));
+        assert_eq!(keymap.press(Scancode::Num1), Some(0xA));
+        assert_eq!(keymap.press(Scancode::Z), None);
+    }
+
+    #[test]
+    fn errors_say_which_line() {
+        let error = |text: &str| Keymap::parse(text).err().unwrap();
+        assert_eq!(error("preset numpad\nG = W"), "line 2: G isn't a keypad key, they go from 0 to F");
+        assert_eq!(error("10 = W"), "line 1: 10 isn't a keypad key, they go from 0 to F");
+        assert_eq!(error("\n\n1 = W, Nope"), "line 3: there's no Nope key");
+        assert_eq!(error("1 W"), "line 1: expected a keypad key = keyboard keys");
+        assert_eq!(error("preset dvorak"), "line 1: there's no dvorak preset");
+    }
+
+    #[test]
+    fn a_key_stays_down_while_any_of_its_keys_are() {
+        let mut keymap = Keymap::preset("qwerty").unwrap();
+        keymap.set("1 = W, Up").unwrap();
+        assert_eq!(keymap.press(Scancode::W), Some(1));
+        assert_eq!(keymap.press(Scancode::Up), Some(1));
+        assert_eq!(keymap.press(Scancode::X), Some(0));
+        assert_eq!(keymap.release(Scancode::W), None);
+        assert_eq!(keymap.release(Scancode::X), Some(0));
+        assert_eq!(keymap.release(Scancode::Up), Some(1));
+        assert_eq!(keymap.release(Scancode::Kp7), None);
+        // With nothing else held for it, even a missed press lets go.
+        assert_eq!(keymap.release(Scancode::W), Some(1));
+    }
+}
@@ -1,55 +1,414 @@
-mod vm;
+mod asm;
+mod dap;
+mod debugger;
+mod disasm;
+mod gdb;
+mod headless;
+mod keymap;
+mod record;
+mod trace;
 
 extern crate sdl2;
 use sdl2::pixels::Color;
 use sdl2::rect::Rect;
 use sdl2::event::Event;
 use sdl2::audio::{AudioCallback, AudioSpecDesired};
+use sdl2::keyboard::{Mod, Scancode};
 
-use std::fs::File;
-use std::io::Read;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
 use std::thread;
-use std::time::{Instant, Duration};
-use vm::Env;
+use std::time::{Instant, Duration, SystemTime, UNIX_EPOCH};
+use chip8_core::screenshot::{Format, Palette, Screenshot};
+use chip8_core::movie::Movie;
+use chip8_core::script::KeyScript;
+use chip8_core::{octo, Env, Keypad, Quirks, RandomMode, Rewind};
+use dap::Dap;
+use debugger::{Repl, Session};
+use gdb::GdbStub;
+use headless::{Extras, Limit};
+use keymap::Keymap;
+use record::{Recorder, SoundRecorder};
+use trace::Trace;
 use std::env;
 
-// The audio code below is stolen from the SDL rust example repo for square waves.
+// The audio code below started out as the SDL rust example for square waves.
 //https://github.com/Rust-SDL2/rust-sdl2/blob/master/examples/audio-squarewave.rs
-struct SquareWave {
-    phase_inc: f32,
-    phase: f32,
+// Now it loops the 128 1-bit samples of the XO-CHIP audio pattern, which are
+// copied over from the Env along with their playback rate.
+struct PatternWave {
+    pattern: [u8; 16],
+    rate: f32, // pattern samples per second
+    sample_rate: f32,
+    position: f32, // in pattern samples
     volume: f32,
 }
 
-impl AudioCallback for SquareWave {
+impl AudioCallback for PatternWave {
     type Channel = f32;
 
     fn callback(&mut self, out: &mut [f32]) {
-        // Generate a square wave
         for x in out.iter_mut() {
-            *x = if self.phase <= 0.5 {
+            let i = self.position as usize;
+            let bit = (self.pattern[i / 8] >> (7 - i % 8)) & 1;
+            *x = if bit == 1 {
                 self.volume
             } else {
                 -self.volume
             };
-            self.phase = (self.phase + self.phase_inc) % 1.0;
+            self.position = (self.position + self.rate / self.sample_rate) % 128.0;
         }
     }
 }
 
+// For the audio device and --wav.
+const SAMPLE_RATE: u32 = 44_100;
+
+// Indexed by the pixel's color: neither plane, plane 0, plane 1, both
+const PALETTE: Palette = [
+    [0, 0, 0],
+    [0, 255, 0],
+    [255, 170, 0],
+    [255, 255, 255],
+];
+
+// F1-F9 load the numbered save state slots, and with shift held save to them.
+fn save_slot(scancode: Scancode) -> Option<u8> {
+    match scancode {
+        Scancode::F1 => Some(1),
+        Scancode::F2 => Some(2),
+        Scancode::F3 => Some(3),
+        Scancode::F4 => Some(4),
+        Scancode::F5 => Some(5),
+        Scancode::F6 => Some(6),
+        Scancode::F7 => Some(7),
+        Scancode::F8 => Some(8),
+        Scancode::F9 => Some(9),
+        _ => None,
+    }
+}
+
+// Save states are written next to the ROM, e.g. pong.ch8 -> pong.state1
+fn state_path(rom_path: &str, slot: u8) -> PathBuf {
+    Path::new(rom_path).with_extension(format!("state{}", slot))
+}
+
+// F12 screenshots and F11 recordings go next to the ROM too, numbered so
+// none are overwritten: pong.ch8 -> pong-1.png, pong-2.png...
+fn numbered_path(rom_path: &str, extension: &str) -> PathBuf {
+    let rom_path = Path::new(rom_path);
+    let stem = rom_path.file_stem().map_or("chip8".into(), |stem| stem.to_string_lossy());
+    (1..)
+        .map(|n| rom_path.with_file_name(format!("{}-{}.{}", stem, n, extension)))
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+// After a rewind or a save state is loaded. A movie being played has
+// nothing for the new timeline so it stops, one being recorded goes on from
+// the new point.
+fn went_back(env: &Env<Keypad>, playing: &mut Option<(KeyScript, u64)>, recording: &mut Option<(String, Movie)>) {
+    if playing.take().is_some() {
+        eprintln!("chip8: stopped playing the movie");
+    }
+    if let Some((_, movie)) = recording.as_mut() {
+        movie.rewind(env);
+    }
+}
+
 fn main() {
     const WIDTH: u32 = 1024; // nearest multiple of 2
     const HEIGHT: u32 = WIDTH / 2;
 
-    let args: Vec<String> = env::args().collect();
-    if args.len() <= 1 {
-        panic!("No file provided to run");
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("asm") => return asm::run(&args[1..]),
+        Some("disasm") => return disasm::run(&args[1..]),
+        Some("trace") => return trace::run(&args[1..]),
+        // Optional, but reads better with --headless.
+        Some("run") => {
+            args.remove(0);
+        },
+        _ => {},
+    }
+
+    // usage: chip8 [--quirks vip|chip48|schip|xochip] [--seed N] [--random xorshift|vip]
+    //              [--cycles-per-frame N] [--rewind-frames N] [--rewind-memory MB]
+    //              [--debug | --gdb PORT] <rom or .8o>
+    //              [--trace FILE [--trace-format text|binary] [--trace-range START-END]...]
+    //              [--screenshot-scale N] [--record FILE] [--record-raw FILE] [--record-scale N]
+    //              [--wav FILE] [--record-movie FILE | --play-movie FILE]
+    //              [--keymap qwerty|azerty|numpad|FILE] [--key K=KEY[,KEY...]]...
+    //        chip8 [run] [options] --headless --cycles N|--frames N [--keys SCRIPT]
+    //              [--screenshot FILE] <rom or .8o>
+    //        chip8 [options] --dap stdio|PORT
+    let mut rom_path = None;
+    let mut quirks = Quirks::default();
+    let mut seed = None;
+    let mut random_mode = RandomMode::default();
+    let mut cycles_per_frame = 9; // 540hz
+    let mut rewind_frames = 60 * 60; // a minute
+    let mut rewind_memory = 64; // MB
+    let mut debug = false;
+    let mut gdb_port = None;
+    let mut dap_transport = None;
+    let mut trace_path = None;
+    let mut trace_binary = false;
+    let mut trace_ranges = Vec::new();
+    let mut headless = false;
+    let mut cycles = None;
+    let mut frames = None;
+    let mut keys_path = None;
+    let mut screenshot = None;
+    let mut screenshot_scale = None;
+    let mut record_path = None;
+    let mut record_raw_path = None;
+    let mut record_scale = 4;
+    let mut wav_path = None;
+    let mut record_movie_path = None;
+    let mut play_movie_path = None;
+    let mut keymap_name = None;
+    let mut key_bindings = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--quirks" => {
+                let name = args.next().expect("--quirks needs a profile name");
+                quirks = match Quirks::from_name(&name) {
+                    Some(quirks) => quirks,
+                    None => panic!("Unknown quirks profile {} (try vip, chip48, schip or xochip)", name),
+                };
+            },
+            "--seed" => {
+                seed = Some(args.next()
+                    .and_then(|n| n.parse().ok())
+                    .expect("--seed needs a number"));
+            },
+            "--random" => {
+                let name = args.next().expect("--random needs a generator name");
+                random_mode = match RandomMode::from_name(&name) {
+                    Some(mode) => mode,
+                    None => panic!("Unknown random generator {} (try xorshift or vip)", name),
+                };
+            },
+            "--cycles-per-frame" => {
+                cycles_per_frame = args.next()
+                    .and_then(|n| n.parse().ok())
+                    .expect("--cycles-per-frame needs a number of instructions");
+            },
+            "--rewind-frames" => {
+                rewind_frames = args.next()
+                    .and_then(|n| n.parse().ok())
+                    .expect("--rewind-frames needs a number of frames");
+            },
+            "--rewind-memory" => {
+                rewind_memory = args.next()
+                    .and_then(|n| n.parse().ok())
+                    .expect("--rewind-memory needs a size in MB");
+            },
+            "--debug" => debug = true,
+            "--gdb" => {
+                gdb_port = Some(args.next()
+                    .and_then(|n| n.parse::<u16>().ok())
+                    .expect("--gdb needs a port number"));
+            },
+            "--trace" => trace_path = Some(args.next().expect("--trace needs a file to write to")),
+            "--trace-format" => {
+                trace_binary = match args.next().as_deref() {
+                    Some("text") => false,
+                    Some("binary") => true,
+                    _ => panic!("--trace-format needs text or binary"),
+                };
+            },
+            "--trace-range" => {
+                trace_ranges.push(args.next()
+                    .and_then(|range| trace::parse_range(&range))
+                    .expect("--trace-range needs a range of hex addresses like 200-2FF"));
+            },
+            "--headless" => headless = true,
+            "--cycles" => {
+                cycles = Some(args.next()
+                    .and_then(|n| n.parse().ok())
+                    .expect("--cycles needs a number of instructions"));
+            },
+            "--frames" => {
+                frames = Some(args.next()
+                    .and_then(|n| n.parse().ok())
+                    .expect("--frames needs a number of frames"));
+            },
+            "--keys" => keys_path = Some(args.next().expect("--keys needs a key script")),
+            "--screenshot" => {
+                screenshot = Some(args.next()
+                    .map(PathBuf::from)
+                    .filter(|path| Format::from_path(path).is_some())
+                    .expect("--screenshot needs a .png, .pbm or .ppm file to write to"));
+            },
+            "--screenshot-scale" => {
+                screenshot_scale = Some(args.next()
+                    .and_then(|n| n.parse().ok())
+                    .filter(|&n| (1..=64).contains(&n))
+                    .expect("--screenshot-scale needs a number from 1 to 64"));
+            },
+            "--record" => record_path = Some(args.next().expect("--record needs a GIF to write to")),
+            "--record-raw" => record_raw_path = Some(args.next().expect("--record-raw needs a file to write to")),
+            "--wav" => wav_path = Some(args.next().expect("--wav needs a file to write to")),
+            "--record-scale" => {
+                record_scale = args.next()
+                    .and_then(|n| n.parse().ok())
+                    .filter(|&n| (1..=64).contains(&n))
+                    .expect("--record-scale needs a number from 1 to 64");
+            },
+            "--record-movie" => record_movie_path = Some(args.next().expect("--record-movie needs a file to write to")),
+            "--play-movie" => play_movie_path = Some(args.next().expect("--play-movie needs a movie")),
+            "--keymap" => keymap_name = Some(args.next().expect("--keymap needs a preset or a keymap file")),
+            "--key" => key_bindings.push(args.next().expect("--key needs a keypad key and keyboard keys like 5=W,Up")),
+            "--dap" => dap_transport = Some(args.next().expect("--dap needs stdio or a port number")),
+            _ => rom_path = Some(arg),
+        }
+    }
+    if [debug, gdb_port.is_some(), dap_transport.is_some()].iter().filter(|&&on| on).count() > 1 {
+        panic!("only one of --debug, --gdb and --dap can be used at a time");
+    }
+    // The debuggers do their own stepping.
+    if trace_path.is_some() && (debug || gdb_port.is_some() || dap_transport.is_some()) {
+        panic!("--trace can't be used with a debugger");
+    }
+    if headless && (debug || gdb_port.is_some() || dap_transport.is_some()) {
+        panic!("--headless can't be used with a debugger");
+    }
+    if record_movie_path.is_some() && play_movie_path.is_some() {
+        panic!("--record-movie and --play-movie can't be used together");
+    }
+    if keys_path.is_some() && play_movie_path.is_some() {
+        panic!("--keys can't be used with --play-movie, the keys come from the movie");
+    }
+    if !headless && (cycles.is_some() || frames.is_some() || keys_path.is_some() || screenshot.is_some()) {
+        panic!("--cycles, --frames, --keys and --screenshot only go with --headless");
+    }
+
+    // With --dap the editor says what to run once it's connected.
+    let mut dap = dap_transport.map(|transport| match Dap::connect(&transport) {
+        Ok(dap) => dap,
+        Err(err) => panic!("couldn't start the debug adapter because {}", err),
+    });
+    let (rom_path, rom) = if let Some(dap) = dap.as_mut() {
+        match dap.launch() {
+            Some(launched) => launched,
+            None => return,
+        }
+    } else {
+        let rom_path = rom_path.expect("No file provided to run");
+        // Octo source is compiled on the way in, anything else is taken to be a ROM.
+        let rom = if Path::new(&rom_path).extension().is_some_and(|ext| ext == "8o") {
+            match octo::compile_file(Path::new(&rom_path)) {
+                Ok(rom) => rom,
+                Err(err) => {
+                    eprintln!("chip8: {}", err);
+                    process::exit(1);
+                },
+            }
+        } else {
+            match fs::read(&rom_path) {
+                Ok(rom) => rom,
+                Err(err) => panic!("couldn't open file because {}", err),
+            }
+        };
+        (rom_path, rom)
+    };
+
+    // A movie being played back says how the machine was set up, so it runs
+    // the same way again.
+    let playing = play_movie_path.map(|path| {
+        let movie = headless::load_movie(&path);
+        if !movie.matches(&rom) {
+            eprintln!("chip8: {} was recorded on a different ROM", path);
+            process::exit(1);
+        }
+        movie
+    });
+    if let Some(movie) = &playing {
+        quirks = movie.quirks;
+        random_mode = movie.random_mode;
+        seed = Some(movie.seed);
+        cycles_per_frame = movie.cycles_per_frame;
+    }
+
+    let mut env = Env::new(Keypad::new(), quirks);
+    // Print the seed when it's picked for us, so the run can be repeated with --seed.
+    let seed = seed.unwrap_or_else(|| {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_nanos() as u64)
+            .unwrap_or(0);
+        eprintln!("chip8: random seed {}", seed);
+        seed
+    });
+    env.seed_random(random_mode, seed);
+    env.set_cycles_per_frame(cycles_per_frame);
+    let mut recording = record_movie_path.map(|path| {
+        (path, Movie::new(&rom, quirks, random_mode, seed, cycles_per_frame))
+    });
+
+    if let Err(err) = env.load_into_memory(&rom) {
+        panic!("couldn't load the ROM because {}", err);
+    }
+
+    let mut trace = trace_path.map(|path| match Trace::create(&path, trace_binary, trace_ranges) {
+        Ok(trace) => trace,
+        Err(err) => panic!("couldn't write the trace to {} because {}", path, err),
+    });
+
+    let mut recorder = if record_path.is_some() || record_raw_path.is_some() {
+        let gif = record_path.as_deref().map(Path::new);
+        let raw = record_raw_path.as_deref().map(Path::new);
+        match Recorder::create(gif, raw, record_scale) {
+            Ok(recorder) => Some(recorder),
+            Err(err) => panic!("couldn't start recording because {}", err),
+        }
+    } else {
+        None
+    };
+
+    let mut sound = wav_path.map(|path| match SoundRecorder::create(Path::new(&path), SAMPLE_RATE) {
+        Ok(sound) => sound,
+        Err(err) => panic!("couldn't write the sound to {} because {}", path, err),
+    });
+
+    // With --headless nothing is opened at all, the program runs to the
+    // limit and the state it's left in is printed.
+    if headless {
+        let limit = match (cycles, frames, &playing) {
+            (Some(cycles), None, _) => Limit::Cycles(cycles),
+            (None, Some(frames), _) => Limit::Frames(frames),
+            // A movie runs to its end.
+            (None, None, Some(movie)) => Limit::Frames(movie.frames),
+            _ => panic!("--headless needs either --cycles or --frames"),
+        };
+        let keys = match (&playing, keys_path) {
+            (Some(movie), None) => movie.script(),
+            (None, Some(path)) => headless::load_keys(&path),
+            (None, None) => KeyScript::default(),
+            (Some(_), Some(_)) => unreachable!("--keys with --play-movie is refused above"),
+        };
+        // Headless screenshots are at the display's own size unless asked otherwise.
+        let screenshot = screenshot.map(|path| (path, screenshot_scale.unwrap_or(1)));
+        let extras = Extras { keys, trace, screenshot, recorder, sound, movie: recording };
+        process::exit(headless::run(&mut env, limit, extras));
     }
 
-    let mut file = match File::open(&args[1]) {
-        Ok(file) => file,
-        Err(err) => panic!("couldn't open file because {}", err),
+    let mut keymap = match Keymap::find(keymap_name.as_deref(), &rom_path) {
+        Ok(keymap) => keymap,
+        Err(err) => {
+            eprintln!("chip8: {}", err);
+            process::exit(1);
+        },
     };
+    for binding in &key_bindings {
+        if let Err(err) = keymap.set(binding) {
+            panic!("--key {}: {}", binding, err);
+        }
+    }
 
     let sdl_context = sdl2::init()
         .expect("Couldn't initialize SDL2");
@@ -64,19 +423,21 @@ fn main() {
 
 
     let desired_spec = AudioSpecDesired {
-        freq: Some(44_100),
+        freq: Some(SAMPLE_RATE as i32),
         channels: Some(1), // mono
         samples: None,     // default sample size
     };
 
-    let device = audio_subsystem.open_playback(None, &desired_spec, |spec| {
+    let mut device = audio_subsystem.open_playback(None, &desired_spec, |spec| {
         // Show obtained AudioSpec
         //println!("{:?}", spec);
 
         // initialize the audio callback
-        SquareWave {
-            phase_inc: 440.0 / spec.freq as f32,
-            phase: 0.0,
+        PatternWave {
+            pattern: [0; 16],
+            rate: 0.0,
+            sample_rate: spec.freq as f32,
+            position: 0.0,
             volume: 0.25,
         }
     }).unwrap();
@@ -96,33 +457,169 @@ fn main() {
         .build()
         .expect("Couldn't initialize the canvas");
 
-    let mut env = Env::new();
-
-    let mut buf: Vec<u8> = Vec::new();
-    file.read_to_end(&mut buf).unwrap();
-    env.load_into_memory(&buf);
-
-    let hz = 540;
-
-    let mut iterations_this_nap = 0;
-    const NUM_NAPS: u32 = 30;
-    let mut nap_start = Instant::now();
+    let mut rewind = Rewind::new(rewind_frames, rewind_memory * 1024 * 1024);
+    // With --debug the program is run from a REPL in the terminal, with
+    // --gdb from whatever connects to the port and with --dap from an editor.
+    let mut session: Option<Box<dyn Session>> = if debug {
+        Some(Box::new(Repl::start(&env)))
+    } else if let Some(port) = gdb_port {
+        match GdbStub::listen(port) {
+            Ok(stub) => Some(Box::new(stub)),
+            Err(err) => panic!("couldn't listen on port {} because {}", port, err),
+        }
+    } else if let Some(dap) = dap {
+        Some(Box::new(dap))
+    } else {
+        None
+    };
+    // Set once the program faults. The window stays open showing the last frame.
+    let mut halted = false;
+    let mut playing = playing.map(|movie| (movie.script(), movie.frames));
+    // The emulator keeps its own time in frames, all we do is make sure
+    // there are 60 of them every real second.
+    const FRAME: Duration = Duration::from_nanos(1_000_000_000 / 60);
+    let mut next_frame = Instant::now();
 
     'main: loop {
+        let mut redraw = false;
         for event in event_pump.poll_iter() {
             match event {
                 Event::Quit { .. } => break 'main,
+                Event::KeyDown { scancode: Some(scancode), keymod, repeat: false, .. } => {
+                    if scancode == Scancode::F11 {
+                        // Stops a recording, or starts a GIF at --record-scale.
+                        if let Some(recorder) = recorder.take() {
+                            match recorder.finish() {
+                                Ok(()) => eprintln!("chip8: stopped recording"),
+                                Err(err) => eprintln!("chip8: couldn't finish recording: {}", err),
+                            }
+                        } else {
+                            let path = numbered_path(&rom_path, "gif");
+                            match Recorder::create(Some(&path), None, record_scale) {
+                                Ok(started) => {
+                                    eprintln!("chip8: recording to {}", path.display());
+                                    recorder = Some(started);
+                                },
+                                Err(err) => eprintln!("chip8: couldn't record to {}: {}", path.display(), err),
+                            }
+                        }
+                    } else if scancode == Scancode::F12 {
+                        // The same size as the window unless --screenshot-scale says otherwise.
+                        let scale = screenshot_scale.unwrap_or(WIDTH as usize / env.resolution().0);
+                        let path = numbered_path(&rom_path, "png");
+                        match Screenshot::capture(&env).save(&path, &PALETTE, scale) {
+                            Ok(()) => eprintln!("chip8: screenshot saved to {}", path.display()),
+                            Err(err) => eprintln!("chip8: couldn't save a screenshot to {}: {}", path.display(), err),
+                        }
+                    } else if let Some(slot) = save_slot(scancode) {
+                        let path = state_path(&rom_path, slot);
+                        if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+                            match fs::write(&path, env.save_state()) {
+                                Ok(()) => eprintln!("chip8: saved to {}", path.display()),
+                                Err(err) => eprintln!("chip8: couldn't save to {}: {}", path.display(), err),
+                            }
+                        } else {
+                            let loaded = fs::read(&path)
+                                .map_err(|err| err.to_string())
+                                .and_then(|data| env.load_state(&data).map_err(|err| err.to_string()));
+                            match loaded {
+                                Ok(()) => {
+                                    eprintln!("chip8: loaded {}", path.display());
+                                    canvas.window_mut().set_title("chip8").unwrap();
+                                    halted = env.has_exited();
+                                    // Rewinding past a load would jump back into the old timeline.
+                                    rewind.clear();
+                                    went_back(&env, &mut playing, &mut recording);
+                                    redraw = true;
+                                },
+                                Err(err) => eprintln!("chip8: couldn't load {}: {}", path.display(), err),
+                            }
+                        }
+                    } else if let Some(key) = keymap.press(scancode).filter(|_| playing.is_none()) {
+                        env.press_key(key);
+                        if let Some((_, movie)) = recording.as_mut() {
+                            movie.record(&env, key, true);
+                        }
+                    }
+                },
+                Event::KeyUp { scancode: Some(scancode), .. } => {
+                    if let Some(key) = keymap.release(scancode).filter(|_| playing.is_none()) {
+                        env.release_key(key);
+                        if let Some((_, movie)) = recording.as_mut() {
+                            movie.record(&env, key, false);
+                        }
+                    }
+                },
                 _ => {},
             }
         }
         
-        env.read_instr(&mut event_pump);
-        if env.display_changed {
-            for y in 0..32 {
-                for x in 0..64 {
-                    let bit = (env.display[y] >> (64 - (x + 1))) as u8 & 1;
-                    canvas.set_draw_color(Color::RGB(0, 255 * bit, 0));
-                    let w = WIDTH / 64;
+        if let Some(session) = session.as_mut() {
+            session.poll(&mut env);
+            if session.wants_quit() {
+                break 'main;
+            }
+            // Stepping doesn't keep track of what changed, so always redraw.
+            redraw = true;
+        }
+
+        // Holding backspace steps backwards a frame at a time instead of running.
+        let rewinding = event_pump.keyboard_state().is_scancode_pressed(Scancode::Backspace);
+
+        if rewinding {
+            if rewind.rewind(&mut env) {
+                canvas.window_mut().set_title("chip8").unwrap();
+                halted = env.has_exited();
+                went_back(&env, &mut playing, &mut recording);
+                redraw = true;
+            }
+        } else if !halted {
+            // While a movie plays the keys come from it, and then from the
+            // keyboard again once it's over.
+            if let Some((keys, end)) = playing.as_mut() {
+                if env.frame_count() < *end {
+                    keys.apply(&mut env);
+                } else {
+                    eprintln!("chip8: the movie's over");
+                    for key in 0..16 {
+                        env.release_key(key);
+                    }
+                    playing = None;
+                }
+            }
+            let result = match (session.as_mut(), trace.as_mut()) {
+                (Some(session), _) => session.run_frame(&mut env),
+                (None, Some(trace)) => {
+                    // Like the debuggers this steps by itself, so can't tell what changed.
+                    redraw = true;
+                    trace.run_frame(&mut env)
+                },
+                (None, None) => env.run_frame(),
+            };
+            if let Err(err) = result {
+                // The debugger has already said so.
+                if session.is_none() {
+                    eprintln!("chip8: {}", err);
+                }
+                canvas.window_mut()
+                    .set_title(&format!("chip8 - halted: {}", err))
+                    .unwrap();
+                halted = true;
+            } else if env.has_exited() {
+                canvas.window_mut().set_title("chip8 - exited").unwrap();
+                halted = true;
+            }
+            redraw |= env.display_changed();
+            rewind.record(&env);
+        }
+        if redraw {
+            let (width, height) = env.resolution();
+            // 16px per pixel in lores, 8px in hires
+            let w = WIDTH / width as u32;
+            for y in 0..height {
+                for x in 0..width {
+                    let [r, g, b] = PALETTE[env.pixel(x, y) as usize];
+                    canvas.set_draw_color(Color::RGB(r, g, b));
                     canvas.fill_rect(Rect::new(
                         x as i32 * w as i32, 
                         y as i32 * w as i32, 
@@ -131,27 +628,55 @@ fn main() {
             }
             canvas.present();
         }
+        // Only as far as the program's got, so nothing while it's halted.
+        if let Some(sound) = sound.as_mut() {
+            sound.update(&env);
+        }
+        // Every time round is a 60th of a second, whether or not anything ran.
+        if let Some(recorder) = recorder.as_mut() {
+            recorder.frame(&env);
+        }
         
         // Vf is changed if any pixels were set from 1 to 0.
         //if env.variable_registers[0xf] != 0 {  
         //}
         
-        iterations_this_nap += 1;
-        if iterations_this_nap >= hz / NUM_NAPS {
-            iterations_this_nap = 0;
-
-            if env.is_beeping() {   // it's practical to check it here.
-                device.resume();
-            } else {
-                device.pause();
-            }
-            
-            let dur = (1_000_000_000 / NUM_NAPS) as i32 - nap_start.elapsed().as_nanos() as i32;
-            if dur > 0 {
-                thread::sleep(Duration::new(0, dur as u32));
+        if env.is_beeping() && !halted && !rewinding {
+            {
+                let mut wave = device.lock();
+                wave.pattern = *env.audio_pattern();
+                wave.rate = env.playback_rate();
             }
-            
-            nap_start = Instant::now();
+            device.resume();
+        } else {
+            device.pause();
+        }
+
+        next_frame += FRAME;
+        let now = Instant::now();
+        if next_frame > now {
+            thread::sleep(next_frame - now);
+        } else {
+            // Running behind (or the window was dragged), don't try to catch up.
+            next_frame = now;
+        }
+    }
+
+    if let Some(recorder) = recorder {
+        if let Err(err) = recorder.finish() {
+            eprintln!("chip8: couldn't finish recording: {}", err);
+        }
+    }
+    if let Some(sound) = sound {
+        if let Err(err) = sound.finish() {
+            eprintln!("chip8: couldn't finish recording sound: {}", err);
+        }
+    }
+    if let Some((path, mut movie)) = recording {
+        movie.finish(&env);
+        match fs::write(&path, movie.to_string()) {
+            Ok(()) => eprintln!("chip8: saved the movie to {}", path),
+            Err(err) => eprintln!("chip8: couldn't save the movie to {}: {}", path, err),
         }
     }
 }
@@ -0,0 +1,101 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use chip8_core::audio::{Beeper, WavWriter};
+use chip8_core::gif::GifWriter;
+use chip8_core::screenshot::Screenshot;
+use chip8_core::{Env, Keypad};
+
+use crate::PALETTE;
+
+// Records the display a frame at a time, for --record, --record-raw and F11.
+// The raw frames are 24-bit RGB one after the other with nothing in between,
+// the same size as the GIF, for handing to a video encoder.
+pub struct Recorder {
+    gif: Option<GifWriter<BufWriter<File>>>,
+    raw: Option<BufWriter<File>>,
+    scale: usize, // of a high resolution pixel
+}
+
+impl Recorder {
+    pub fn create(gif: Option<&Path>, raw: Option<&Path>, scale: usize) -> io::Result<Recorder> {
+        let gif = match gif {
+            Some(path) => Some(GifWriter::new(BufWriter::new(File::create(path)?), &PALETTE, scale)?),
+            None => None,
+        };
+        let raw = match raw {
+            Some(path) => Some(BufWriter::new(File::create(path)?)),
+            None => None,
+        };
+        Ok(Recorder { gif, raw, scale })
+    }
+
+    // Adds a 60th of a second of the display as it is now. Whichever output
+    // can't be written to any more is dropped, and the game goes on.
+    pub fn frame(&mut self, env: &Env<Keypad>) {
+        let screenshot = Screenshot::capture(env);
+        if let Some(gif) = self.gif.as_mut() {
+            if let Err(err) = gif.frame(&screenshot) {
+                eprintln!("chip8: stopped recording the GIF: {}", err);
+                self.gif = None;
+            }
+        }
+        if let Some(raw) = self.raw.as_mut() {
+            let rgb: Vec<u8> = screenshot.scaled(self.scale * 128 / screenshot.width())
+                .iter()
+                .flat_map(|&value| PALETTE[value as usize])
+                .collect();
+            if let Err(err) = raw.write_all(&rgb) {
+                eprintln!("chip8: stopped recording raw frames: {}", err);
+                self.raw = None;
+            }
+        }
+    }
+
+    // Writes out the end of the GIF.
+    pub fn finish(self) -> io::Result<()> {
+        if let Some(gif) = self.gif {
+            gif.finish()?;
+        }
+        if let Some(mut raw) = self.raw {
+            raw.flush()?;
+        }
+        Ok(())
+    }
+}
+
+// Records the sound to a WAV file, for --wav.
+pub struct SoundRecorder {
+    beeper: Beeper,
+    wav: Option<WavWriter<BufWriter<File>>>, // None once writing has failed
+    samples: Vec<i16>,
+}
+
+impl SoundRecorder {
+    pub fn create(path: &Path, sample_rate: u32) -> io::Result<SoundRecorder> {
+        let wav = WavWriter::new(BufWriter::new(File::create(path)?), sample_rate)?;
+        Ok(SoundRecorder { beeper: Beeper::new(sample_rate), wav: Some(wav), samples: Vec::new() })
+    }
+
+    // Adds the sound up to now, see Beeper::render.
+    pub fn update(&mut self, env: &Env<Keypad>) {
+        let Some(wav) = self.wav.as_mut() else {
+            return;
+        };
+        self.samples.clear();
+        self.beeper.render(env, &mut self.samples);
+        if let Err(err) = wav.write(&self.samples) {
+            eprintln!("chip8: stopped recording sound: {}", err);
+            self.wav = None;
+        }
+    }
+
+    // Fills in the WAV's header.
+    pub fn finish(self) -> io::Result<()> {
+        if let Some(wav) = self.wav {
+            wav.finish()?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,120 @@
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::process;
+
+use chip8_core::trace::{BinaryReader, BinaryWriter, Step};
+use chip8_core::{Chip8Error, Env, Keypad};
+
+enum Output {
+    Text(BufWriter<File>),
+    Binary(BinaryWriter<BufWriter<File>>),
+}
+
+// Writes a trace of the instructions as they run, for --trace.
+pub struct Trace {
+    out: Option<Output>, // None once writing has failed
+    ranges: Vec<(u16, u16)>, // inclusive, and everything when empty
+}
+
+impl Trace {
+    pub fn create(path: &str, binary: bool, ranges: Vec<(u16, u16)>) -> io::Result<Trace> {
+        let file = BufWriter::new(File::create(path)?);
+        let out = if binary {
+            Output::Binary(BinaryWriter::new(file)?)
+        } else {
+            Output::Text(file)
+        };
+        Ok(Trace { out: Some(out), ranges })
+    }
+
+    // Takes the place of Env::run_frame, stepping through the frame one
+    // instruction at a time.
+    pub fn run_frame(&mut self, env: &mut Env<Keypad>) -> Result<(), Chip8Error> {
+        let frame = env.frame_count();
+        while env.frame_count() == frame && !env.has_exited() {
+            self.step(env)?;
+        }
+        Ok(())
+    }
+
+    // Takes the place of Env::step.
+    pub fn step(&mut self, env: &mut Env<Keypad>) -> Result<(), Chip8Error> {
+        let step = Step::run(env)?;
+        if !self.ranges.is_empty() && !self.ranges.iter().any(|&(start, end)| (start..=end).contains(&step.pc)) {
+            return Ok(());
+        }
+        let written = match self.out.as_mut() {
+            Some(Output::Text(out)) => writeln!(out, "{}", step),
+            Some(Output::Binary(out)) => out.write(&step),
+            None => return Ok(()),
+        };
+        // The game goes on without it.
+        if let Err(err) = written {
+            eprintln!("chip8: stopped tracing: {}", err);
+            self.out = None;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Trace {
+    fn drop(&mut self) {
+        let flushed = match self.out.as_mut() {
+            Some(Output::Text(out)) => out.flush(),
+            Some(Output::Binary(out)) => out.flush(),
+            None => Ok(()),
+        };
+        if let Err(err) = flushed {
+            eprintln!("chip8: couldn't finish the trace: {}", err);
+        }
+    }
+}
+
+// "200-2FF" in hex, or a single address.
+pub fn parse_range(text: &str) -> Option<(u16, u16)> {
+    let hex = |text: &str| {
+        let text = text.trim_start_matches("0x").trim_start_matches("0X");
+        u16::from_str_radix(text, 16).ok()
+    };
+    match text.split_once('-') {
+        Some((start, end)) => Some((hex(start)?, hex(end)?)).filter(|(start, end)| start <= end),
+        None => hex(text).map(|address| (address, address)),
+    }
+}
+
+// chip8 trace <binary trace>
+// Prints a binary trace the way a text one would have been written.
+pub fn run(args: &[String]) {
+    let path = match args {
+        [path] => path,
+        _ => {
+            eprintln!("usage: chip8 trace <binary trace>");
+            process::exit(2);
+        },
+    };
+    let steps = File::open(path).and_then(|file| BinaryReader::new(BufReader::new(file)));
+    let steps = match steps {
+        Ok(steps) => steps,
+        Err(err) => {
+            eprintln!("chip8: couldn't read {}: {}", path, err);
+            process::exit(1);
+        },
+    };
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    for step in steps {
+        let written = match step {
+            Ok(step) => writeln!(out, "{}", step),
+            Err(err) => {
+                let _ = out.flush();
+                eprintln!("chip8: couldn't read {}: {}", path, err);
+                process::exit(1);
+            },
+        };
+        // Most likely piped into something that's stopped reading.
+        if written.is_err() {
+            return;
+        }
+    }
+    let _ = out.flush();
+}